@@ -1,4 +1,6 @@
 use errors::*;
+use hash::{self, Hash, Hasher};
+use snapshot::Snapshot;
 use std::fs;
 use std::io::{self, Read};
 use std::marker::PhantomData;
@@ -10,6 +12,7 @@ pub struct Block {
     file_id: usize,
     block_id: usize,
     data: Box<[u8]>,
+    hash: Hash,
 }
 
 impl Block {
@@ -24,12 +27,18 @@ impl Block {
     pub fn data(&self) -> &[u8] {
         &self.data
     }
+
+    // The hash of the data, worked out as the block is read.
+    pub fn hash(&self) -> &[u8] {
+        &self.hash
+    }
 }
 
 #[derive(Debug)]
 pub struct File {
     id: usize,
     path: PathBuf,
+    snapshot: Option<Snapshot>,
 }
 
 impl File {
@@ -37,8 +46,15 @@ impl File {
         Self {
             id,
             path: path.into(),
+            snapshot: None,
         }
     }
+
+    // Expects the file to match the snapshot once it is read.
+    pub fn snapshot(mut self, snapshot: Option<Snapshot>) -> Self {
+        self.snapshot = snapshot;
+        self
+    }
 }
 
 pub struct BlockIter<'a, I>
@@ -50,6 +66,13 @@ where
     block_id: usize,
     path: Option<PathBuf>,
     file: Option<fs::File>,
+    snapshot: Option<Snapshot>,
+    file_len: u64,
+    tolerate_changes: bool,
+    changed: Vec<usize>,
+    hash_algorithm: hash::Algorithm,
+    hasher: Hasher,
+    hashes: Vec<(usize, Hash)>,
     file_iter: I,
     phantom: PhantomData<&'a I>,
 }
@@ -68,6 +91,13 @@ where
             block_id: 0,
             path: None,
             file: None,
+            snapshot: None,
+            file_len: 0,
+            tolerate_changes: false,
+            changed: vec![],
+            hash_algorithm: hash::Algorithm::default(),
+            hasher: hash::Algorithm::default().hasher(),
+            hashes: vec![],
             file_iter,
             phantom: PhantomData,
         };
@@ -75,12 +105,57 @@ where
         Ok(iter)
     }
 
+    // Hashes the files and the blocks with the algorithm, rather than
+    // with SHA-1.
+    pub fn hash_algorithm(mut self, hash_algorithm: hash::Algorithm) -> Self {
+        self.hash_algorithm = hash_algorithm;
+        self.hasher = hash_algorithm.hasher();
+        self
+    }
+
+    // Carries on past files that changed since their snapshots,
+    // instead of failing.
+    pub fn tolerate_changes(mut self, tolerate_changes: bool) -> Self {
+        self.tolerate_changes = tolerate_changes;
+        self
+    }
+
+    // Returns the ids of the files that changed since their snapshots.
+    pub fn changed(&self) -> &[usize] {
+        &self.changed
+    }
+
+    // Returns the ids of the files read in full with the hashes of
+    // their contents.
+    pub fn hashes(&self) -> &[(usize, Hash)] {
+        &self.hashes
+    }
+
+    // Checks the file just read in full against its snapshot.
+    fn finish_file(&mut self) -> Result<()> {
+        self.hashes.push((self.file_id, self.hasher.digest()));
+        if let Some(snapshot) = self.snapshot.take() {
+            let path = self.path.as_ref().expect("BlockIter::path");
+            if self.file_len != snapshot.len || Snapshot::take(path)? != snapshot {
+                if self.tolerate_changes {
+                    self.changed.push(self.file_id);
+                } else {
+                    bail!(ErrorKind::FileChanged(path.clone()));
+                }
+            }
+        }
+        Ok(())
+    }
+
     fn open_next(&mut self) -> Result<bool> {
         if let Some(file) = self.file_iter.next() {
             self.file_id = file.id;
             self.file = Some(fs::File::open(&file.path)
-                .chain_err(|| format!("error opening {:?}", file.path))?);
+                .chain_err(|| ErrorKind::UnreadableFile(file.path.clone()))?);
             self.path = Some(file.path);
+            self.snapshot = file.snapshot;
+            self.file_len = 0;
+            self.hasher.reset();
             Ok(true)
         } else {
             self.file = None;
@@ -107,16 +182,20 @@ where
                     Ok(byte_count) => bytes_read += byte_count,
                     Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
                     err => {
-                        err.chain_err(|| format!("error reading from {:?}", self.path))?;
+                        let path = self.path.clone().expect("BlockIter::path");
+                        err.chain_err(|| ErrorKind::UnreadableFile(path))?;
                     }
                 }
             }
 
+            self.file_len += bytes_read as u64;
             if bytes_read > 0 {
                 let _ = block.split_off(bytes_read);
+                self.hasher.update(&block);
                 let retval = Ok(Some(Block {
                     file_id: self.file_id,
                     block_id: self.block_id,
+                    hash: self.hash_algorithm.digest(&block),
                     data: block.into_boxed_slice(),
                 }));
 
@@ -125,10 +204,12 @@ where
                     self.block_id += 1;
                 } else {
                     // end of file
+                    self.finish_file()?;
                 }
 
                 retval
             } else {
+                self.finish_file()?;
                 self.next_block()
             }
         }
@@ -141,8 +222,10 @@ mod test {
     use index::FileTable;
     use medium::Medium;
     use path::Path;
+    use snapshot::Snapshot;
     use std::fs;
     use std::io::Write;
+    use tempdir::TempDir;
     use unit;
 
     #[test]
@@ -185,6 +268,31 @@ mod test {
         teardown_files();
     }
 
+    #[test]
+    fn test_changed_file() {
+        let temp_dir = TempDir::new("test_changed_file").expect("TempDir::new");
+        let path = temp_dir.path().join("file");
+        fs::File::create(&path)
+            .and_then(|mut file| file.write_all(b"1234"))
+            .expect("write");
+        let snapshot = Snapshot::take(&path).expect("Snapshot::take");
+        fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .and_then(|mut file| file.write_all(b"5678"))
+            .expect("append");
+
+        let files = || vec![block::File::new(7, &path).snapshot(Some(snapshot))].into_iter();
+        let mut iter = BlockIter::new(16, files()).expect("BlockIter::new");
+        assert!(iter.next_block().is_err());
+
+        let mut iter = BlockIter::new(16, files())
+            .expect("BlockIter::new")
+            .tolerate_changes(true);
+        while let Some(_) = iter.next_block().expect("next_block") {}
+        assert_eq!(iter.changed(), &[7]);
+    }
+
     fn setup_files() -> Vec<unit::File> {
         let mut test1 = fs::OpenOptions::new()
             .write(true)
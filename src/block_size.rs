@@ -1,10 +1,12 @@
 use autofill::AutoFill;
 use consts::*;
 use itertools::Itertools;
+use profile::MediumProfile;
 use slog::Logger;
 use stats::Stats;
 
-const BLOCK_SIZES: &[u64] = &[
+// The block sizes considered, from the smallest to the largest.
+pub const BLOCK_SIZES: &[u64] = &[
     0x200,
     0x400,
     0x800,
@@ -28,6 +30,8 @@ const BLOCK_SIZES: &[u64] = &[
 
 pub struct BlockSize {
     stats: Stats,
+    sector_size: u64,
+    recommended: Option<u64>,
     log: Logger,
 }
 
@@ -35,11 +39,30 @@ impl BlockSize {
     pub fn new(stats: Stats, log: &Logger) -> Self {
         BlockSize {
             stats,
+            sector_size: 0,
+            recommended: None,
             log: log.new(o!("function" => "block_size")),
         }
     }
 
+    // Blocks smaller than a sector of the medium are not considered,
+    // and a block size recommended by the profile is used as is.
+    pub fn profile(mut self, profile: &MediumProfile) -> Self {
+        self.sector_size = profile.sector_size;
+        self.recommended = profile.block_size;
+        self
+    }
+
     pub fn block_size(&self) -> u64 {
+        if let Some(recommended) = self.recommended {
+            slog_info!(
+                &self.log,
+                "use the block size recommended for the medium: {}",
+                recommended
+            );
+            return recommended;
+        }
+
         let mut loss_table = vec![];
 
         AutoFill::new(concat!(
@@ -48,7 +71,10 @@ impl BlockSize {
             " bytes in the actual backup media."
         )).foreach(|line| slog_info!(&self.log, "{}", line));
 
-        for block_size in BLOCK_SIZES {
+        for block_size in BLOCK_SIZES
+            .iter()
+            .filter(|&&block_size| block_size >= self.sector_size)
+        {
             let loss = self.stats
                 .file_sizes
                 .iter()
@@ -0,0 +1,269 @@
+use catalog::Catalog;
+use consts::*;
+use errors::*;
+use hash;
+use index::{self, Block, FileTable, MediaTable, RedundancyIndex, RedundancyTable};
+use naming::MAX_NAME_LEN;
+use path::Path;
+use profile::MediumProfile;
+use redundancy::{self, EncKey, Nonce};
+use serde::Serialize;
+use std::cmp;
+use std::fs;
+use std::io::{self, Write};
+use std::mem;
+use std::path::Path as StdPath;
+use unit::File;
+use unitset::UnitSet;
+
+// Room left for the checksum verifile keeps with each file it writes.
+const VERIFILE_OVERHEAD: u64 = 4096;
+
+// Works out how much space a group of media takes once everything is
+// placed on them: the data media hold their files, the index tables
+// of the group and the encryption key, and the redundancy medium
+// holds the tables and a nonce-prefixed, padded block for every pair
+// of data blocks.  Every medium holds the catalog of the backup as
+// well.  Every file takes whole sectors.
+#[derive(Debug)]
+pub struct CapacityModel {
+    capacity: u64,
+    sector_size: u64,
+    block_size: u64,
+    hash_algorithm: hash::Algorithm,
+    redundancy_file_size: u64,
+    catalog: u64,
+}
+
+impl CapacityModel {
+    pub fn new(profile: &MediumProfile, block_size: u64) -> Self {
+        CapacityModel {
+            capacity: profile.capacity,
+            sector_size: cmp::max(profile.sector_size, 1),
+            block_size,
+            hash_algorithm: hash::Algorithm::default(),
+            redundancy_file_size: REDUNDANCY_FILE_SIZE,
+            catalog: 0,
+        }
+    }
+
+    // Sizes the hashes in the tables for the algorithm.
+    pub fn hash_algorithm(mut self, hash_algorithm: hash::Algorithm) -> Self {
+        self.hash_algorithm = hash_algorithm;
+        self
+    }
+
+    pub fn redundancy_file_size(mut self, redundancy_file_size: u64) -> Self {
+        self.redundancy_file_size = redundancy_file_size;
+        self
+    }
+
+    // Makes room on every medium for the catalog of the backup.
+    pub fn catalog(mut self, catalog: &Catalog) -> Result<Self> {
+        self.catalog = self.on_medium(serialised_len(catalog)? + VERIFILE_OVERHEAD);
+        Ok(self)
+    }
+
+    pub fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    fn on_medium(&self, len: u64) -> u64 {
+        (len + self.sector_size - 1) / self.sector_size * self.sector_size
+    }
+
+    fn blocks(&self, files: &[&File]) -> u64 {
+        files
+            .iter()
+            .map(|file| (file.len + self.block_size - 1) / self.block_size)
+            .sum()
+    }
+
+    // Returns the space taken on the left, right and redundancy media
+    // of a group.
+    pub fn group(&self, left: &[&File], right: &[&File]) -> Result<[u64; 3]> {
+        let blocks = cmp::max(self.blocks(left), self.blocks(right));
+        let record = self.block_size + mem::size_of::<Nonce>() as u64;
+        let max_blocks = redundancy::blocks_per_file(self.block_size, self.redundancy_file_size);
+
+        let mut redun_files = vec![];
+        let mut redun_len = 0;
+        let mut remaining = blocks;
+        while remaining > 0 {
+            let len = cmp::min(remaining, max_blocks) * record;
+            redun_files.push(File::new(
+                Path::with_prefix("").path(format!("{:010}", redun_files.len())),
+                len,
+            ));
+            redun_len += self.on_medium(len + VERIFILE_OVERHEAD);
+            remaining -= cmp::min(remaining, max_blocks);
+        }
+
+        let tables = self.tables(left, right, &redun_files, blocks)?;
+        let key = self.on_medium(mem::size_of::<EncKey>() as u64 + VERIFILE_OVERHEAD);
+        let data = |files: &[&File]| -> u64 {
+            files.iter().map(|file| self.on_medium(file.len)).sum()
+        };
+
+        Ok([
+            data(left) + tables + key + self.catalog,
+            data(right) + tables + key + self.catalog,
+            redun_len + tables + self.catalog,
+        ])
+    }
+
+    // Serialises the tables of a group on trial.  The redundancy table
+    // has one entry per pair of blocks; its entries, and the hashes of
+    // the files, are sized with the largest values they could take.
+    fn tables(
+        &self,
+        left: &[&File],
+        right: &[&File],
+        redun_files: &[File],
+        blocks: u64,
+    ) -> Result<u64> {
+        let mut media_table = MediaTable::new();
+        for _ in 0..3 {
+            let _ = media_table.add_name(&"M".repeat(MAX_NAME_LEN));
+        }
+
+        let worst_hash = vec![0xffu8; self.hash_algorithm.len()];
+        let mut file_table = FileTable::default().with_hash_algorithm(self.hash_algorithm);
+        for (medium_id, files) in [left, right].iter().enumerate() {
+            for file in files.iter() {
+                let id = file_table.add_entry(medium_id, file)?;
+                file_table.set_hash(id, &worst_hash);
+            }
+        }
+        for file in redun_files {
+            let _ = file_table.add_entry(2, file)?;
+        }
+
+        let file_id = file_table.entries().len();
+        let worst = Block::new(
+            file_id,
+            blocks as usize,
+            self.block_size as u32,
+            &worst_hash,
+        );
+        let entry = || RedundancyIndex::Redundancy {
+            left: worst.clone(),
+            right: worst.clone(),
+            redundancy: worst.clone(),
+        };
+        let mut redun_table = RedundancyTable::new().with_hash_algorithm(self.hash_algorithm);
+        redun_table.add(entry());
+        let one = serialised_len(&redun_table)?;
+        redun_table.add(entry());
+        let per_entry = serialised_len(&redun_table)? - one;
+        let redun_table_len = one + blocks.saturating_sub(1) * per_entry;
+
+        Ok(self.on_medium(serialised_len(&media_table)? + VERIFILE_OVERHEAD)
+            + self.on_medium(serialised_len(&file_table)? + VERIFILE_OVERHEAD)
+            + self.on_medium(redun_table_len + VERIFILE_OVERHEAD))
+    }
+
+    // Returns by how many bytes the fullest medium in the plan, parity
+    // included, overflows.  Zero means everything fits.
+    pub fn overflow(&self, sets: &[UnitSet]) -> Result<u64> {
+        let mut overflow = 0;
+
+        for pair in sets.chunks(2) {
+            let left = files_of(&pair[0]);
+            let right = pair.get(1).map(files_of).unwrap_or_default();
+            for len in &self.group(&left, &right)? {
+                overflow = cmp::max(overflow, len.saturating_sub(self.capacity));
+            }
+        }
+
+        Ok(overflow)
+    }
+
+    // Adds up the space the files under a directory take.  Symlinks
+    // the layout makes in symlink mode are followed, as what they link
+    // to is what goes on the medium.
+    pub fn measure(&self, dir: &StdPath) -> Result<u64> {
+        let mut len = 0;
+        for entry in dir.read_dir()
+            .chain_err(|| format!("error reading directory {:?}", dir))?
+        {
+            let entry = entry.chain_err(|| format!("error reading directory {:?}", dir))?;
+            let metadata = fs::metadata(entry.path())
+                .chain_err(|| format!("error getting metadata of {:?}", entry.path()))?;
+            if metadata.is_dir() {
+                len += self.measure(&entry.path())?;
+            } else {
+                len += self.on_medium(metadata.len());
+            }
+        }
+        Ok(len)
+    }
+}
+
+fn files_of(set: &UnitSet) -> Vec<&File> {
+    set.0.iter().flat_map(|unit| unit.files.0.iter()).collect()
+}
+
+struct Counter(u64);
+
+impl Write for Counter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn serialised_len<T: Serialize>(table: &T) -> Result<u64> {
+    let mut counter = Counter(0);
+    index::serialise(&mut counter, table)?;
+    Ok(counter.0)
+}
+
+#[cfg(test)]
+mod test {
+    use capacity::CapacityModel;
+    use path::Path;
+    use profile::MediumProfile;
+    use std::fs;
+    use tempdir::TempDir;
+    use unit::File;
+
+    #[test]
+    fn test_group() {
+        let model = CapacityModel::new(&MediumProfile::file(1024 * 1024), 0x1000);
+        let left = vec![
+            File::new(Path::with_prefix("/").path("/a"), 0x1800),
+            File::new(Path::with_prefix("/").path("/b"), 0x100),
+        ];
+        let right = vec![File::new(Path::with_prefix("/").path("/c"), 0x1000)];
+        let left: Vec<&File> = left.iter().collect();
+        let right: Vec<&File> = right.iter().collect();
+
+        let [left_len, right_len, redun_len] = model.group(&left, &right).expect("group");
+
+        // Three blocks on the left against one on the right make three
+        // nonce-prefixed blocks of parity, which outgrow the data.
+        assert!(left_len > 0x1800 + 0x200);
+        assert!(right_len > 0x1000);
+        assert!(redun_len > 3 * (0x1000 + 16));
+        assert_eq!(left_len - right_len, 0x1800 + 0x200 - 0x1000);
+    }
+
+    #[cfg(target_family = "unix")]
+    #[test]
+    fn test_measure_symlinks() {
+        let temp_dir = TempDir::new("test_capacity").expect("TempDir::new");
+        let target = temp_dir.path().join("target");
+        fs::write(&target, vec![0u8; 0x1800]).expect("write");
+        let medium = temp_dir.path().join("medium");
+        fs::create_dir(&medium).expect("create_dir");
+        ::std::os::unix::fs::symlink(&target, medium.join("link")).expect("symlink");
+
+        let model = CapacityModel::new(&MediumProfile::file(1024 * 1024), 0x1000);
+        assert_eq!(model.measure(&medium).expect("measure"), 0x1800);
+    }
+}
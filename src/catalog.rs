@@ -0,0 +1,435 @@
+use config::Settings;
+use consts::*;
+use errors::*;
+use hash::{self, Hash};
+use index::{self, FileTable, MediaTable};
+use naming::MAX_NAME_LEN;
+use slog::Logger;
+use std::collections::{BTreeMap, HashSet};
+use std::path::Path as StdPath;
+use unit::File;
+use unitset::UnitSet;
+use verifile::Verifile;
+
+const CATALOG_IDENTIFIER: &str = "Backup Catalog";
+
+// The third medium of each group holds its redundancy.
+const REDUNDANCY_MEDIUM_ID: usize = 2;
+
+// A file in a backup, and the generation and the medium its contents
+// are stored on.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct CatalogEntry {
+    pub path: String,
+    pub size: u64,
+    #[serde(default)]
+    pub modified: Option<(u64, u32)>,
+    #[serde(default)]
+    pub hash: Option<Hash>,
+    // Entries carried over from earlier generations keep the hashes
+    // those were made with.
+    #[serde(default)]
+    pub hash_algorithm: hash::Algorithm,
+    pub generation: u64,
+    pub medium: String,
+}
+
+// Every file in a backup as of one generation.  Files that did not
+// change since an earlier generation refer to the media of that
+// generation, so the files of a single catalog recreate the tree as it
+// was when the catalog was made.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Catalog {
+    identifier: String,
+    generation: u64,
+    entries: Vec<CatalogEntry>,
+    // files of the previous generation that are gone from this one
+    #[serde(default)]
+    deleted: Vec<String>,
+    // the settings the backup was made with
+    #[serde(default)]
+    config: Option<Settings>,
+}
+
+// Catalogs came in after binary tables were versioned, so every one
+// has a header.
+#[cfg(feature = "binary-tables")]
+impl index::Versioned for Catalog {
+    type Unversioned = Catalog;
+}
+
+// How the files of two catalogs differ.
+#[derive(Debug, Default)]
+pub struct Diff<'a> {
+    pub added: Vec<&'a CatalogEntry>,
+    pub deleted: Vec<&'a CatalogEntry>,
+    // the entries of the older catalog next to those of the newer
+    pub changed: Vec<(&'a CatalogEntry, &'a CatalogEntry)>,
+}
+
+// What changed since a catalog of an earlier backup.
+#[derive(Debug, Default)]
+pub struct Changes {
+    pub unchanged: Vec<CatalogEntry>,
+    pub deleted: Vec<String>,
+    pub bytes: u64,
+}
+
+impl Catalog {
+    pub fn new(generation: u64) -> Self {
+        Catalog {
+            identifier: CATALOG_IDENTIFIER.into(),
+            generation,
+            entries: vec![],
+            deleted: vec![],
+            config: None,
+        }
+    }
+
+    pub fn with_config(mut self, config: &Settings) -> Self {
+        self.config = Some(config.clone());
+        self
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    pub fn entries(&self) -> &[CatalogEntry] {
+        &self.entries
+    }
+
+    pub fn deleted(&self) -> &[String] {
+        &self.deleted
+    }
+
+    // Adds the files of a group of media of this generation.
+    pub fn add_tables(&mut self, media_table: &MediaTable, file_table: &FileTable) -> Result<()> {
+        for entry in file_table
+            .entries()
+            .iter()
+            .filter(|entry| entry.medium_id() != REDUNDANCY_MEDIUM_ID)
+        {
+            let medium = media_table
+                .name(entry.medium_id())
+                .ok_or_else(|| format!("medium {} is not in the media table", entry.medium_id()))?;
+            self.entries.push(CatalogEntry {
+                path: entry.path().into(),
+                size: entry.size(),
+                modified: entry.modified(),
+                hash: entry.hash().map(|hash| hash.to_vec()),
+                hash_algorithm: file_table.hash_algorithm(),
+                generation: self.generation,
+                medium: medium.into(),
+            });
+        }
+        Ok(())
+    }
+
+    // Adds the files that did not change since an earlier generation,
+    // and those that are gone.
+    pub fn add_changes(&mut self, unchanged: &[CatalogEntry], deleted: &[String]) {
+        self.entries.extend_from_slice(unchanged);
+        self.deleted.extend_from_slice(deleted);
+    }
+
+    // Makes up the catalog of a planned backup, with the largest
+    // values its entries could take, to see how much room it takes.
+    pub fn planned(
+        generation: u64,
+        hash_algorithm: hash::Algorithm,
+        unit_set: &UnitSet,
+        changes: &Changes,
+    ) -> Result<Self> {
+        let mut catalog = Self::new(generation);
+        let files = unit_set.0.iter().flat_map(|unit| unit.files.0.iter());
+        for file in files {
+            let path = index::logical_path(&file.path)?;
+            catalog
+                .entries
+                .push(worst_entry(file, &path, hash_algorithm));
+            for duplicate in &file.duplicates {
+                let path = index::logical_path(&duplicate.path)?;
+                catalog
+                    .entries
+                    .push(worst_entry(file, &path, hash_algorithm));
+            }
+        }
+        catalog.add_changes(&changes.unchanged, &changes.deleted);
+        Ok(catalog)
+    }
+
+    pub fn save(&mut self, path: &StdPath) -> Result<()> {
+        self.entries.sort_by(|left, right| left.path.cmp(&right.path));
+        let mut file = Verifile::new(path.to_str().ok_or("utf8 error")?)?;
+        let mut write = file.write()?;
+        index::serialise(&mut write, self)?;
+        write.close()?;
+        Ok(())
+    }
+
+    // Reads the catalog on a medium of an earlier backup, given the
+    // root of the medium.  Backups made before there were catalogs only
+    // keep the tables of their group on each medium, which make up a
+    // part of the catalog of generation 0.
+    pub fn load(dir: &StdPath) -> Result<Self> {
+        let path = dir.join(CATALOG_FILE);
+        if path.is_file() {
+            let catalog: Catalog = index::read(&path)?;
+            if catalog.identifier != CATALOG_IDENTIFIER {
+                bail!("{:?} is not a backup catalog", path);
+            }
+            Ok(catalog)
+        } else if dir.join(FILE_TABLE_FILE).is_file() {
+            let media_table: MediaTable = index::read(&dir.join(MEDIA_TABLE_FILE))?;
+            let file_table: FileTable = index::read(&dir.join(FILE_TABLE_FILE))?;
+            let mut catalog = Self::new(0);
+            catalog.add_tables(&media_table, &file_table)?;
+            Ok(catalog)
+        } else {
+            bail!("{:?} holds neither a catalog nor the index tables of a backup", dir)
+        }
+    }
+
+    // Combines the catalogs of earlier backups.  Those of the latest
+    // generation among them are taken together; the others are older
+    // states of the same tree.
+    pub fn latest(catalogs: Vec<Catalog>) -> Option<Self> {
+        let generation = match catalogs.iter().map(|catalog| catalog.generation).max() {
+            Some(generation) => generation,
+            None => return None,
+        };
+        let mut entries = BTreeMap::new();
+        for catalog in catalogs
+            .into_iter()
+            .filter(|catalog| catalog.generation == generation)
+        {
+            for entry in catalog.entries {
+                let _ = entries.insert(entry.path.clone(), entry);
+            }
+        }
+
+        let mut catalog = Self::new(generation);
+        catalog.entries = entries.into_iter().map(|(_, entry)| entry).collect();
+        Some(catalog)
+    }
+
+    // Takes the files that did not change since this catalog out of the
+    // units, so only new and changed files go on the new media.  A file
+    // is unchanged if it has the same size, and either the same
+    // modification time or the same contents.
+    pub fn compare(&self, unit_set: &mut UnitSet, log: &Logger) -> Result<Changes> {
+        let previous: BTreeMap<_, _> = self.entries
+            .iter()
+            .map(|entry| (entry.path.as_str(), entry))
+            .collect();
+        let mut seen = HashSet::new();
+        let mut changes = Changes::default();
+
+        for unit in &mut unit_set.0 {
+            let mut files = vec![];
+            for file in unit.files.0.drain(..) {
+                let path = index::logical_path(&file.path)?;
+                let unchanged = match previous.get(path.as_str()) {
+                    Some(entry) if is_unchanged(entry, &file) => {
+                        slog_debug!(log, "unchanged"; "path" => &path);
+                        let mut entry = (*entry).clone();
+                        entry.modified = file.snapshot.map(|snapshot| snapshot.modified);
+                        Some(entry)
+                    }
+                    _ => None,
+                };
+                let _ = seen.insert(path);
+                match unchanged {
+                    Some(entry) => {
+                        changes.bytes += entry.size;
+                        changes.unchanged.push(entry);
+                    }
+                    None => files.push(file),
+                }
+            }
+            unit.files.0 = files;
+        }
+        unit_set.recount();
+
+        changes.deleted = self.entries
+            .iter()
+            .filter(|entry| !seen.contains(&entry.path))
+            .map(|entry| entry.path.clone())
+            .collect();
+        Ok(changes)
+    }
+
+    // Lists the files added, deleted and changed in a newer catalog.
+    pub fn diff<'a>(&'a self, newer: &'a Catalog) -> Diff<'a> {
+        let older: BTreeMap<_, _> = self.entries
+            .iter()
+            .map(|entry| (entry.path.as_str(), entry))
+            .collect();
+        let newer: BTreeMap<_, _> = newer
+            .entries
+            .iter()
+            .map(|entry| (entry.path.as_str(), entry))
+            .collect();
+        let mut diff = Diff::default();
+        for (path, &entry) in &newer {
+            match older.get(path) {
+                Some(&old) if !is_same(old, entry) => diff.changed.push((old, entry)),
+                Some(_) => {}
+                None => diff.added.push(entry),
+            }
+        }
+        diff.deleted = older
+            .iter()
+            .filter(|&(path, _)| !newer.contains_key(path))
+            .map(|(_, &entry)| entry)
+            .collect();
+        diff
+    }
+}
+
+// Compares two entries of the same path.  Hashes made with different
+// algorithms cannot be compared, so then the modification times are.
+fn is_same(older: &CatalogEntry, newer: &CatalogEntry) -> bool {
+    if older.size != newer.size {
+        return false;
+    }
+    match (&older.hash, &newer.hash) {
+        (&Some(ref left), &Some(ref right)) if older.hash_algorithm == newer.hash_algorithm => {
+            left == right
+        }
+        _ => older.modified == newer.modified,
+    }
+}
+
+fn is_unchanged(entry: &CatalogEntry, file: &File) -> bool {
+    if entry.size != file.len {
+        return false;
+    }
+    let modified = file.snapshot.map(|snapshot| snapshot.modified);
+    if modified.is_some() && modified == entry.modified {
+        return true;
+    }
+    // A file that cannot be read counts as changed; reading it again
+    // during the build reports the error.
+    match entry.hash {
+        Some(ref hash) => entry.hash_algorithm.file(&file.path).ok().as_ref() == Some(hash),
+        None => false,
+    }
+}
+
+fn worst_entry(file: &File, path: &str, hash_algorithm: hash::Algorithm) -> CatalogEntry {
+    CatalogEntry {
+        path: path.into(),
+        size: file.len,
+        modified: Some((u64::max_value(), u32::max_value())),
+        hash: Some(vec![0xff; hash_algorithm.len()]),
+        hash_algorithm,
+        generation: u64::max_value(),
+        medium: "M".repeat(MAX_NAME_LEN),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use catalog::{Catalog, CatalogEntry};
+    use filter::Filter;
+    use hash;
+    use path::Path;
+    use slog::{Discard, Logger};
+    use std::fs;
+    use std::io::Write;
+    use tempdir::TempDir;
+    use unitset::UnitSet;
+
+    #[test]
+    fn test_compare() {
+        let temp_dir = TempDir::new("test_catalog").expect("TempDir::new");
+        let root = temp_dir.path();
+        let write = |path: &str, contents: &[u8]| {
+            fs::File::create(root.join(path))
+                .and_then(|mut file| file.write_all(contents))
+                .expect("write");
+        };
+        write("kept", b"kept");
+        write("touched", b"touched");
+        write("edited", b"edited");
+
+        let log = Logger::root(Discard, o!());
+        let filter = Filter::new(root);
+        let scan = || {
+            UnitSet::from_path(Path::with_prefix(root).path(root), &filter, &log)
+                .expect("UnitSet::from_path")
+        };
+        let mut unit_set = scan();
+        let mut previous = Catalog::new(0);
+        let changes = previous.compare(&mut unit_set, &log).expect("compare");
+        assert_eq!(changes.unchanged.len(), 0);
+        previous.entries = Catalog::planned(0, hash::Algorithm::Sha256, &unit_set, &changes)
+            .expect("planned")
+            .entries;
+        for entry in &mut previous.entries {
+            let path = root.join(&entry.path);
+            entry.medium = "apple".into();
+            entry.generation = 0;
+            entry.hash = Some(entry.hash_algorithm.file(&path).expect("hash"));
+            if entry.path != "kept" {
+                entry.modified = Some((0, 0));
+            }
+        }
+        write("edited", b"EDITED");
+        write("added", b"added");
+        previous.entries.push(previous.entries[0].clone());
+        previous.entries.last_mut().unwrap().path = "gone".into();
+
+        let mut unit_set = scan();
+        let changes = previous.compare(&mut unit_set, &log).expect("compare");
+        let mut unchanged: Vec<_> = changes
+            .unchanged
+            .iter()
+            .map(|entry| entry.path.as_str())
+            .collect();
+        unchanged.sort();
+        assert_eq!(unchanged, vec!["kept", "touched"]);
+        assert_eq!(changes.deleted, vec!["gone".to_string()]);
+        assert_eq!(unit_set.len(), 11);
+    }
+
+    #[test]
+    fn test_diff() {
+        let entry = |path: &str, size: u64, hash: &[u8]| CatalogEntry {
+            path: path.into(),
+            size,
+            modified: Some((0, 0)),
+            hash: Some(hash.to_vec()),
+            hash_algorithm: hash::Algorithm::Sha256,
+            generation: 0,
+            medium: "apple".into(),
+        };
+        let mut older = Catalog::new(0);
+        older.entries = vec![
+            entry("kept", 4, b"kept"),
+            entry("edited", 6, b"edited"),
+            entry("grown", 5, b"grown"),
+            entry("gone", 4, b"gone"),
+        ];
+        let mut newer = Catalog::new(1);
+        newer.entries = vec![
+            entry("added", 5, b"added"),
+            entry("edited", 6, b"EDITED"),
+            entry("grown", 6, b"grown"),
+            entry("kept", 4, b"kept"),
+        ];
+        // Hashes of other algorithms are not compared.
+        newer.entries[3].hash_algorithm = hash::Algorithm::Blake3;
+
+        let diff = older.diff(&newer);
+        let paths = |entries: &[&CatalogEntry]| -> Vec<String> {
+            entries.iter().map(|entry| entry.path.clone()).collect()
+        };
+        assert_eq!(paths(&diff.added), vec!["added"]);
+        assert_eq!(paths(&diff.deleted), vec!["gone"]);
+        let changed: Vec<_> = diff.changed.iter().map(|&(_, entry)| entry).collect();
+        assert_eq!(paths(&changed), vec!["edited", "grown"]);
+    }
+}
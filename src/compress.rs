@@ -0,0 +1,332 @@
+use consts::*;
+use dedup::Savings;
+use errors::*;
+use hash::{self, Hash, Hasher};
+use report::{self, Skipped};
+use slog::Logger;
+use snapshot::{ChangePolicy, Snapshot};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::Path as StdPath;
+use std::path::PathBuf;
+use unit::File;
+use unitset::UnitSet;
+use zstd;
+
+// Types of files that are compressed already, and gain nothing from
+// being compressed again.
+const COMPRESSED_EXTENSIONS: &[&str] = &[
+    "7z", "avi", "bz2", "flac", "gif", "gz", "heic", "jpeg", "jpg", "lz4", "lzma", "m4a", "m4v",
+    "mkv", "mov", "mp3", "mp4", "ogg", "opus", "png", "rar", "tgz", "webm", "webp", "xz", "zip",
+    "zst",
+];
+
+// How much of a file is compressed to see whether the whole file is
+// worth compressing.
+const SAMPLE_SIZE: usize = 128 * 1024;
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Algorithm {
+    Zstd,
+}
+
+impl Algorithm {
+    pub fn extension(&self) -> &'static str {
+        match *self {
+            Algorithm::Zstd => "zst",
+        }
+    }
+}
+
+// How a file is stored on its medium when it is compressed.  The
+// length of the file is its compressed length, so the planners and the
+// block size see what actually goes on the media.  The compressed copy
+// and the hash of the original contents are known once the file is
+// compressed for the build.
+#[derive(Clone, Debug)]
+pub struct Compression {
+    pub algorithm: Algorithm,
+    pub original_len: u64,
+    pub stored: Option<PathBuf>,
+    pub hash: Option<Hash>,
+    // set when the file changed while it was compressed
+    pub changed: bool,
+}
+
+impl Compression {
+    pub fn new(algorithm: Algorithm, original_len: u64) -> Self {
+        Compression {
+            algorithm,
+            original_len,
+            stored: None,
+            hash: None,
+            changed: false,
+        }
+    }
+}
+
+// Decides which files to store compressed, and works out their
+// compressed lengths.  Files of types that are compressed already are
+// left alone, as are those whose first part or whole contents barely
+// compress.
+pub fn plan(unit_set: &mut UnitSet, skipped: Option<&Skipped>, log: &Logger) -> Result<Savings> {
+    let mut savings = Savings::default();
+    for unit in &mut unit_set.0 {
+        for file in &mut unit.files.0 {
+            if file.len == 0 || is_compressed_type(&file.path) {
+                continue;
+            }
+            let len = match report::tolerate(skipped, &file.path, compressed_len(&file.path))? {
+                Some(Some(len)) => len,
+                Some(None) | None => continue,
+            };
+            if !worth_it(len, file.len) {
+                continue;
+            }
+            slog_debug!(log, "compress";
+                        "path" => format!("{:?}", file.path),
+                        "len" => file.len,
+                        "compressed" => len);
+            savings.files += 1;
+            savings.bytes += file.len - len;
+            file.compression = Some(Compression::new(Algorithm::Zstd, file.len));
+            file.len = len;
+        }
+    }
+    unit_set.recount();
+    Ok(savings)
+}
+
+// Compresses a file into the directory for the build, unless it is
+// compressed already, hashing the original contents with the
+// algorithm.  A file that changes while it is compressed is compressed
+// again, fails the build or is marked, as the policy says.
+pub fn store(
+    file: &mut File,
+    dir: &StdPath,
+    hash_algorithm: hash::Algorithm,
+    policy: ChangePolicy,
+) -> Result<()> {
+    let algorithm = match file.compression {
+        Some(ref compression) if compression.stored.is_none() => compression.algorithm,
+        _ => return Ok(()),
+    };
+    let mut name = file.path.logical()?.into_os_string();
+    name.push(".");
+    name.push(algorithm.extension());
+    let target = dir.join(name);
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent).chain_err(|| format!("error creating {:?}", parent))?;
+    }
+
+    let mut attempt = 0;
+    loop {
+        let (original_len, len, hash) = compress(&file.path, &target, hash_algorithm)?;
+        let changed = match file.snapshot {
+            Some(snapshot) => {
+                original_len != snapshot.len || Snapshot::take(&file.path)? != snapshot
+            }
+            None => false,
+        };
+        if changed {
+            match policy {
+                ChangePolicy::Retry if attempt < CHANGE_RETRIES => {
+                    attempt += 1;
+                    warn!("{:?} changed while it was compressed, compressing it again", file.path);
+                    file.refresh()?;
+                    continue;
+                }
+                ChangePolicy::Mark => {
+                    warn!(
+                        "{:?} changed while it was compressed, marking it inconsistent",
+                        file.path
+                    );
+                }
+                _ => bail!(ErrorKind::FileChanged(file.path.to_path_buf())),
+            }
+        }
+
+        file.len = len;
+        let compression = file.compression.as_mut().expect("File::compression");
+        compression.original_len = original_len;
+        compression.stored = Some(target);
+        compression.hash = Some(hash);
+        compression.changed = changed;
+        return Ok(());
+    }
+}
+
+// Writes out the original contents of a file stored compressed.
+pub fn decompress<R, W>(algorithm: Algorithm, read: R, write: &mut W) -> Result<()>
+where
+    R: Read,
+    W: Write,
+{
+    match algorithm {
+        Algorithm::Zstd => {
+            zstd::stream::copy_decode(read, write).chain_err(|| "error decompressing a file")
+        }
+    }
+}
+
+fn is_compressed_type(path: &StdPath) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| {
+            COMPRESSED_EXTENSIONS
+                .iter()
+                .any(|compressed| extension.eq_ignore_ascii_case(compressed))
+        })
+        .unwrap_or(false)
+}
+
+// Compression has to save a twentieth of a file to be worth it.
+fn worth_it(compressed_len: u64, len: u64) -> bool {
+    compressed_len * 20 <= len * 19
+}
+
+// Returns the compressed length of a file, or None if a sample from
+// the start of the file shows it is not worth compressing.
+fn compressed_len(path: &StdPath) -> Result<Option<u64>> {
+    let open = || fs::File::open(path).chain_err(|| ErrorKind::UnreadableFile(path.into()));
+
+    let mut sample = vec![];
+    let _ = open()?
+        .take(SAMPLE_SIZE as u64)
+        .read_to_end(&mut sample)
+        .chain_err(|| ErrorKind::UnreadableFile(path.into()))?;
+    let mut counter = Counter(0);
+    zstd::stream::copy_encode(&sample[..], &mut counter, COMPRESSION_LEVEL)
+        .chain_err(|| format!("error compressing {:?}", path))?;
+    if !worth_it(counter.0, sample.len() as u64) {
+        return Ok(None);
+    }
+    if sample.len() < SAMPLE_SIZE {
+        return Ok(Some(counter.0));
+    }
+
+    let mut counter = Counter(0);
+    zstd::stream::copy_encode(open()?, &mut counter, COMPRESSION_LEVEL)
+        .chain_err(|| ErrorKind::UnreadableFile(path.into()))?;
+    Ok(Some(counter.0))
+}
+
+// Compresses a file, returning its length, its compressed length and
+// the hash of its contents.
+fn compress(
+    path: &StdPath,
+    target: &StdPath,
+    hash_algorithm: hash::Algorithm,
+) -> Result<(u64, u64, Hash)> {
+    let file = fs::File::open(path).chain_err(|| ErrorKind::UnreadableFile(path.into()))?;
+    let mut read = HashRead {
+        read: file,
+        hasher: hash_algorithm.hasher(),
+        len: 0,
+    };
+    let mut write = fs::File::create(target).chain_err(|| format!("error creating {:?}", target))?;
+    zstd::stream::copy_encode(&mut read, &mut write, COMPRESSION_LEVEL)
+        .chain_err(|| ErrorKind::UnreadableFile(path.into()))?;
+    let len = write
+        .metadata()
+        .chain_err(|| format!("error getting metadata of {:?}", target))?
+        .len();
+    Ok((read.len, len, read.hasher.digest()))
+}
+
+// Hashes what passes through it.
+struct HashRead<R> {
+    read: R,
+    hasher: Hasher,
+    len: u64,
+}
+
+impl<R: Read> Read for HashRead<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.read.read(buf)?;
+        self.hasher.update(&buf[..len]);
+        self.len += len as u64;
+        Ok(len)
+    }
+}
+
+struct Counter(u64);
+
+impl Write for Counter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use compress::{self, decompress, Algorithm};
+    use filter::Filter;
+    use hash;
+    use path::Path;
+    use slog::{Discard, Logger};
+    use snapshot::ChangePolicy;
+    use std::fs;
+    use std::io::Write;
+    use tempdir::TempDir;
+    use unitset::UnitSet;
+
+    #[test]
+    fn test_compress() {
+        let temp_dir = TempDir::new("test_compress").expect("TempDir::new");
+        let root = temp_dir.path().join("root");
+        let work = temp_dir.path().join("work");
+        fs::create_dir(&root).expect("create_dir");
+        let contents = "a line of a log file that repeats\n".repeat(10000);
+        let write = |path: &str, contents: &[u8]| {
+            fs::File::create(root.join(path))
+                .and_then(|mut file| file.write_all(contents))
+                .expect("write");
+        };
+        write("log.txt", contents.as_bytes());
+        write("log.gz", contents.as_bytes());
+
+        let log = Logger::root(Discard, o!());
+        let filter = Filter::new(&root);
+        let mut unit_set = UnitSet::from_path(Path::with_prefix(&root).path(&root), &filter, &log)
+            .expect("UnitSet::from_path");
+        let savings = compress::plan(&mut unit_set, None, &log).expect("plan");
+        assert_eq!(savings.files, 1);
+
+        let files = &mut unit_set.0[0].files.0;
+        let planned_len = files
+            .iter()
+            .find(|file| file.path.ends_with("log.gz"))
+            .expect("log.gz")
+            .len;
+        assert_eq!(planned_len, contents.len() as u64);
+        let file = files
+            .iter_mut()
+            .find(|file| file.path.ends_with("log.txt"))
+            .expect("log.txt");
+        let planned_len = file.len;
+        assert!(planned_len < contents.len() as u64 / 5);
+
+        compress::store(file, &work, hash::Algorithm::Blake3, ChangePolicy::Fail).expect("store");
+        assert_eq!(file.len, planned_len);
+        let compression = file.compression.as_ref().expect("compression");
+        assert_eq!(
+            compression.hash,
+            Some(hash::Algorithm::Blake3.digest(contents.as_bytes()))
+        );
+        let stored = compression.stored.as_ref().expect("stored");
+        let mut restored = vec![];
+        decompress(
+            Algorithm::Zstd,
+            fs::File::open(stored).expect("open"),
+            &mut restored,
+        ).expect("decompress");
+        assert_eq!(restored, contents.as_bytes());
+    }
+}
@@ -0,0 +1,292 @@
+use consts::*;
+use errors::*;
+use filter;
+use serde::de::Error as DeError;
+use serde::{Deserialize, Deserializer};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path as StdPath;
+use std::path::PathBuf;
+use std::result::Result as StdResult;
+use toml;
+
+// The data media of a group.  The redundancy of a group is the XOR of
+// exactly two of them.
+pub const GROUP_WIDTH: usize = 2;
+
+const DEFAULT_NAMING: &str = "fruit";
+const DEFAULT_LINK_MODE: &str = "auto";
+
+// A configuration file of recurring backup jobs, each a profile in a
+// table of its own:
+//
+//     [profile.photos]
+//     sources = ["/home/me/photos", "/home/me/videos"]
+//     exclude = ["*.tmp"]
+//     medium = "bd-r-25"
+//     block-size = "1M"
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    #[serde(default)]
+    profile: BTreeMap<String, Settings>,
+}
+
+// How a backup is made.  Each setting comes from a profile of a
+// configuration file or from the command line, which takes precedence:
+// a value on the command line replaces that of the profile, and
+// patterns on the command line are added to those of the profile.  The
+// settings in effect are recorded in the plan and the catalog, so the
+// backup can be made again the same way.  The output mode, how the
+// files are put in place on the media, is the link mode.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Settings {
+    pub sources: Vec<PathBuf>,
+    pub exclude: Vec<String>,
+    pub include: Vec<String>,
+    pub exclude_from: Vec<PathBuf>,
+    pub medium: Option<String>,
+    pub medium_profiles: Option<PathBuf>,
+    pub group_width: Option<usize>,
+    pub encryption_recipients: Vec<String>,
+    pub naming: Option<String>,
+    #[serde(alias = "output-mode")]
+    pub link_mode: Option<String>,
+    #[serde(deserialize_with = "size")]
+    pub block_size: Option<u64>,
+    #[serde(deserialize_with = "size")]
+    pub small_file_threshold: Option<u64>,
+    #[serde(deserialize_with = "size")]
+    pub redundancy_file_size: Option<u64>,
+}
+
+impl Settings {
+    // Reads the named profile from a configuration file.  Relative
+    // paths in it are taken from the directory of the file.
+    pub fn load<P: AsRef<StdPath>>(path: P, profile: &str) -> Result<Self> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path).chain_err(|| format!("error reading {:?}", path))?;
+        let mut config: ConfigFile = toml::from_str(&text)
+            .chain_err(|| format!("error reading the configuration in {:?}", path))?;
+        let mut settings = match config.profile.remove(profile) {
+            Some(settings) => settings,
+            None => bail!("there is no profile {} in {:?}", profile, path),
+        };
+
+        let dir = path.parent().unwrap_or_else(|| StdPath::new(""));
+        for source in &mut settings.sources {
+            *source = dir.join(&source);
+        }
+        for patterns in &mut settings.exclude_from {
+            *patterns = dir.join(&patterns);
+        }
+        if let Some(ref mut medium_profiles) = settings.medium_profiles {
+            *medium_profiles = dir.join(&medium_profiles);
+        }
+        Ok(settings)
+    }
+
+    // Lays the settings from the command line over these.
+    pub fn merge(mut self, cli: Settings) -> Self {
+        if !cli.sources.is_empty() {
+            self.sources = cli.sources;
+        }
+        self.exclude.extend(cli.exclude);
+        self.include.extend(cli.include);
+        self.exclude_from.extend(cli.exclude_from);
+        self.encryption_recipients.extend(cli.encryption_recipients);
+        Settings {
+            medium: cli.medium.or(self.medium),
+            medium_profiles: cli.medium_profiles.or(self.medium_profiles),
+            group_width: cli.group_width.or(self.group_width),
+            naming: cli.naming.or(self.naming),
+            link_mode: cli.link_mode.or(self.link_mode),
+            block_size: cli.block_size.or(self.block_size),
+            small_file_threshold: cli.small_file_threshold.or(self.small_file_threshold),
+            redundancy_file_size: cli.redundancy_file_size.or(self.redundancy_file_size),
+            ..self
+        }
+    }
+
+    // Fills in the defaults of what is not set, and rejects what
+    // backups cannot do.
+    pub fn effective(self) -> Result<Self> {
+        if self.sources.is_empty() {
+            bail!("no source directory to back up");
+        }
+        if self.medium.is_none() {
+            bail!("no medium profile or size for the backup");
+        }
+        match self.group_width {
+            Some(width) if width != GROUP_WIDTH => bail!(
+                "groups of {} data media are not supported, only of {}",
+                width,
+                GROUP_WIDTH
+            ),
+            _ => {}
+        }
+        if !self.encryption_recipients.is_empty() {
+            bail!(concat!(
+                "encrypting for recipients is not supported; the redundancy of every ",
+                "group is encrypted with a key of its own, kept on its data media"
+            ));
+        }
+        if self.block_size == Some(0) || self.redundancy_file_size == Some(0) {
+            bail!("the block size and the redundancy file size cannot be 0");
+        }
+        // The length of a block is kept in 32 bits.
+        match self.block_size {
+            Some(size) if size > u64::from(u32::MAX) => bail!(
+                "the block size cannot be larger than {} bytes: {}",
+                u32::MAX,
+                size
+            ),
+            _ => {}
+        }
+
+        Ok(Settings {
+            group_width: Some(GROUP_WIDTH),
+            naming: self.naming.or_else(|| Some(DEFAULT_NAMING.into())),
+            link_mode: self.link_mode.or_else(|| Some(DEFAULT_LINK_MODE.into())),
+            small_file_threshold: self.small_file_threshold.or(Some(SMALL_FILE_UPPER_BOUND)),
+            redundancy_file_size: self.redundancy_file_size.or(Some(REDUNDANCY_FILE_SIZE)),
+            ..self
+        })
+    }
+
+    pub fn naming(&self) -> &str {
+        self.naming.as_ref().map_or(DEFAULT_NAMING, |naming| naming.as_str())
+    }
+
+    pub fn link_mode(&self) -> &str {
+        self.link_mode
+            .as_ref()
+            .map_or(DEFAULT_LINK_MODE, |link_mode| link_mode.as_str())
+    }
+
+    pub fn small_file_threshold(&self) -> u64 {
+        self.small_file_threshold.unwrap_or(SMALL_FILE_UPPER_BOUND)
+    }
+
+    pub fn redundancy_file_size(&self) -> u64 {
+        self.redundancy_file_size.unwrap_or(REDUNDANCY_FILE_SIZE)
+    }
+}
+
+// Reads a size given in bytes, or as text such as "4M".
+fn size<'de, D: Deserializer<'de>>(deserializer: D) -> StdResult<Option<u64>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Size {
+        Bytes(u64),
+        Text(String),
+    }
+
+    // Binary tables cannot tell the two apart, and hold the sizes as
+    // they were written, in bytes.
+    if !deserializer.is_human_readable() {
+        return Option::<u64>::deserialize(deserializer);
+    }
+    match Option::<Size>::deserialize(deserializer)? {
+        None => Ok(None),
+        Some(Size::Bytes(len)) => Ok(Some(len)),
+        Some(Size::Text(text)) => filter::parse_size(&text)
+            .map(Some)
+            .map_err(|err| D::Error::custom(err.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use config::Settings;
+    use consts::*;
+    use std::fs;
+    use std::path::PathBuf;
+    use tempdir::TempDir;
+
+    // The settings recorded in a binary catalog are read back.
+    #[cfg(feature = "binary-tables")]
+    #[test]
+    fn test_binary_settings() {
+        use bincode;
+
+        let settings = Settings {
+            medium: Some("lto-7".into()),
+            block_size: Some(1024 * 1024),
+            ..Settings::default()
+        };
+        let bytes = bincode::serialize(&settings, bincode::Infinite).expect("serialize");
+        let read: Settings =
+            bincode::deserialize_from(&mut &bytes[..], bincode::Infinite).expect("deserialize");
+        assert_eq!(read, settings);
+    }
+
+    #[test]
+    fn test_settings() {
+        let temp_dir = TempDir::new("test_config").expect("TempDir::new");
+        let path = temp_dir.path().join("backup.toml");
+        fs::write(
+            &path,
+            r#"
+[profile.photos]
+sources = ["photos", "/videos"]
+exclude = ["*.tmp"]
+medium = "bd-r-25"
+block-size = "1M"
+redundancy-file-size = 1048576
+
+[profile.wide]
+medium = "lto-7"
+group-width = 3
+
+[profile.copies]
+sources = ["/data"]
+medium = "lto-7"
+output-mode = "copy"
+"#,
+        ).expect("write");
+
+        let settings = Settings::load(&path, "photos").expect("load");
+        assert_eq!(
+            settings.sources,
+            vec![temp_dir.path().join("photos"), PathBuf::from("/videos")]
+        );
+        assert_eq!(settings.block_size, Some(1024 * 1024));
+        assert_eq!(settings.redundancy_file_size, Some(1024 * 1024));
+
+        let cli = Settings {
+            exclude: vec!["*.bak".into()],
+            medium: Some("bd-r-50".into()),
+            naming: Some("seq:photos".into()),
+            ..Settings::default()
+        };
+        let settings = settings.merge(cli).effective().expect("effective");
+        assert_eq!(settings.sources.len(), 2);
+        assert_eq!(settings.exclude, vec!["*.tmp", "*.bak"]);
+        assert_eq!(settings.medium, Some("bd-r-50".to_string()));
+        assert_eq!(settings.naming(), "seq:photos");
+        assert_eq!(settings.link_mode(), "auto");
+        assert_eq!(settings.block_size, Some(1024 * 1024));
+        assert_eq!(settings.small_file_threshold(), SMALL_FILE_UPPER_BOUND);
+
+        let wide = Settings::load(&path, "wide").expect("load");
+        let cli = Settings {
+            sources: vec!["/data".into()],
+            ..Settings::default()
+        };
+        assert!(wide.merge(cli).effective().is_err());
+        let copies = Settings::load(&path, "copies").expect("load");
+        assert_eq!(copies.clone().effective().expect("effective").link_mode(), "copy");
+        let cli = Settings {
+            block_size: Some(u64::from(u32::MAX) + 1),
+            ..Settings::default()
+        };
+        assert!(copies.merge(cli).effective().is_err());
+        assert!(Settings::load(&path, "missing").is_err());
+
+        // Misspelt settings are not left unnoticed.
+        fs::write(&path, "[profile.typo]\nmediums = \"lto-7\"\n").expect("write");
+        assert!(Settings::load(&path, "typo").is_err());
+    }
+}
@@ -1,8 +1,13 @@
 pub const LOG_PATH: &str = "ideas.log";
 pub const LOG_PATH_JSON: &str = "ideas-log.json";
+pub const SKIPPED_REPORT_FILE: &str = "ideas-skipped.json";
 pub const SMALL_FILE_UPPER_BOUND: u64 = 10 * 1024 * 1024;
 pub const RECORD_SIZE: u64 = 64;
-pub const MAX_REDUNDANCY_BLOCKS: usize = 1000;
+pub const REDUNDANCY_FILE_SIZE: u64 = 1024 * 1024 * 1024;
+pub const MEMORY_LIMIT: u64 = 1024 * 1024 * 1024;
+pub const READ_AHEAD_BLOCKS: usize = 16;
+pub const CHANGE_RETRIES: usize = 3;
+pub const COMPRESSION_LEVEL: i32 = 3;
 
 pub const WORK_DIR: &str = "backup";
 pub const LAYOUT_SUBDIR: &str = "layout";
@@ -10,3 +15,17 @@ pub const FILES_SUBDIR: &str = "files";
 pub const INDEX_SUBDIR: &str = "index";
 pub const REDUNDANCY_SUBDIR: &str = "redundancy";
 pub const ENCRYPTION_KEY_SUBDIR: &str = "encryption-key";
+pub const CATALOG_SUBDIR: &str = "catalog";
+pub const COMPRESSED_SUBDIR: &str = "compressed";
+
+pub const MEDIA_TABLE_FILE: &str = "media-table";
+pub const FILE_TABLE_FILE: &str = "file-table";
+pub const REDUN_TABLE_FILE: &str = "redun-table";
+pub const CATALOG_FILE: &str = "catalog";
+pub const ENCRYPTION_KEY_FILE: &str = "encryption-key";
+pub const JOURNAL_FILE: &str = "journal";
+pub const PLAN_FILE: &str = "plan";
+
+pub const IGNORE_FILE: &str = ".redbackupignore";
+pub const CACHEDIR_TAG: &str = "CACHEDIR.TAG";
+pub const CACHEDIR_TAG_SIGNATURE: &[u8] = b"Signature: 8a477f597d28d172789f06886806bc55";
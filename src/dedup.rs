@@ -0,0 +1,202 @@
+use errors::*;
+use hash::{self, Hash};
+use report::{self, Skipped};
+use slog::Logger;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path as StdPath;
+use unit::Duplicate;
+use unitset::UnitSet;
+
+const BUFFER_SIZE: usize = 64 * 1024;
+
+// The hashes only pick out candidates and are not kept, so the fastest
+// algorithm will do.
+const HASH_ALGORITHM: hash::Algorithm = hash::Algorithm::Blake3;
+
+#[derive(Debug, Default)]
+pub struct Savings {
+    pub files: usize,
+    pub bytes: u64,
+}
+
+// Finds files with identical contents and keeps only the first of each
+// in path order, recording the others as its duplicates.  The units
+// shrink accordingly, so the planners see the deduplicated sizes.
+//
+// Only files of the same size are hashed, and files with the same hash
+// are compared byte for byte before they are taken for duplicates.
+pub fn deduplicate(
+    unit_set: &mut UnitSet,
+    skipped: Option<&Skipped>,
+    log: &Logger,
+) -> Result<Savings> {
+    let mut by_len: BTreeMap<u64, Vec<(usize, usize)>> = BTreeMap::new();
+    for (unit_index, unit) in unit_set.0.iter().enumerate() {
+        for (file_index, file) in unit.files.0.iter().enumerate() {
+            if file.len > 0 {
+                by_len
+                    .entry(file.len)
+                    .or_insert_with(Vec::new)
+                    .push((unit_index, file_index));
+            }
+        }
+    }
+
+    // pairs of a duplicate and the file it duplicates
+    let mut duplicates = vec![];
+    for (_, candidates) in by_len.into_iter().filter(|&(_, ref files)| files.len() > 1) {
+        let mut by_hash: HashMap<Hash, Vec<(usize, usize)>> = HashMap::new();
+        for (unit_index, file_index) in candidates {
+            let path = &unit_set.0[unit_index].files.0[file_index].path;
+            if let Some(hash) = report::tolerate(skipped, path, HASH_ALGORITHM.file(path))? {
+                by_hash
+                    .entry(hash)
+                    .or_insert_with(Vec::new)
+                    .push((unit_index, file_index));
+            }
+        }
+
+        for (_, mut same) in by_hash.into_iter().filter(|&(_, ref files)| files.len() > 1) {
+            same.sort_by(|left, right| {
+                let path = |&(unit_index, file_index): &(usize, usize)| {
+                    &unit_set.0[unit_index].files.0[file_index].path
+                };
+                path(left).cmp(path(right))
+            });
+            let original = same[0];
+            for duplicate in same.into_iter().skip(1) {
+                let original_path = &unit_set.0[original.0].files.0[original.1].path;
+                let duplicate_path = &unit_set.0[duplicate.0].files.0[duplicate.1].path;
+                // Files that cannot be compared are kept apart, and left
+                // for the build to read, or skip, on their own.
+                match same_contents(original_path, duplicate_path) {
+                    Ok(true) => {
+                        slog_debug!(log, "duplicate";
+                                    "path" => format!("{:?}", duplicate_path),
+                                    "original" => format!("{:?}", original_path));
+                        duplicates.push((duplicate, original));
+                    }
+                    Ok(false) => {}
+                    Err(err) => slog_warn!(log, "cannot compare, taken for different";
+                                           "path" => format!("{:?}", duplicate_path),
+                                           "original" => format!("{:?}", original_path),
+                                           "error" => format!("{}", err)),
+                }
+            }
+        }
+    }
+
+    let mut savings = Savings::default();
+    let mut removed = vec![vec![]; unit_set.0.len()];
+    for &((unit_index, file_index), (original_unit, original_file)) in &duplicates {
+        let duplicate = unit_set.0[unit_index].files.0[file_index].clone();
+        savings.files += 1;
+        savings.bytes += duplicate.len;
+        unit_set.0[original_unit].files.0[original_file]
+            .duplicates
+            .push(Duplicate {
+                path: duplicate.path,
+                snapshot: duplicate.snapshot,
+            });
+        removed[unit_index].push(file_index);
+    }
+    for (unit, mut removed) in unit_set.0.iter_mut().zip(removed) {
+        removed.sort();
+        for file_index in removed.into_iter().rev() {
+            let _ = unit.files.0.remove(file_index);
+        }
+    }
+    unit_set.recount();
+
+    Ok(savings)
+}
+
+pub fn same_contents(left: &StdPath, right: &StdPath) -> Result<bool> {
+    let open = |path: &StdPath| {
+        fs::File::open(path).chain_err(|| ErrorKind::UnreadableFile(path.into()))
+    };
+    let mut left_file = open(left)?;
+    let mut right_file = open(right)?;
+    let mut left_buf = vec![0u8; BUFFER_SIZE];
+    let mut right_buf = vec![0u8; BUFFER_SIZE];
+
+    loop {
+        let len = read_full(&mut left_file, &mut left_buf)
+            .chain_err(|| ErrorKind::UnreadableFile(left.into()))?;
+        let right_len = read_full(&mut right_file, &mut right_buf)
+            .chain_err(|| ErrorKind::UnreadableFile(right.into()))?;
+        if len != right_len || left_buf[..len] != right_buf[..len] {
+            return Ok(false);
+        } else if len == 0 {
+            return Ok(true);
+        }
+    }
+}
+
+fn read_full(file: &mut fs::File, buf: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match file.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(count) => len += count,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(len)
+}
+
+#[cfg(test)]
+mod test {
+    use dedup::deduplicate;
+    use filter::Filter;
+    use path::Path;
+    use slog::{Discard, Logger};
+    use std::fs;
+    use std::io::Write;
+    use tempdir::TempDir;
+    use unit::Duplicate;
+use unitset::UnitSet;
+
+    #[test]
+    fn test_deduplicate() {
+        let temp_dir = TempDir::new("test_dedup").expect("TempDir::new");
+        let root = temp_dir.path();
+        fs::create_dir(root.join("a")).expect("create_dir");
+        fs::create_dir(root.join("b")).expect("create_dir");
+        let write = |path: &str, contents: &[u8]| {
+            fs::File::create(root.join(path))
+                .and_then(|mut file| file.write_all(contents))
+                .expect("write");
+        };
+        write("a/photo", b"the same photo");
+        write("b/copy", b"the same photo");
+        write("b/other", b"a second photo");
+        write("b/empty", b"");
+
+        let log = Logger::root(Discard, o!());
+        let filter = Filter::new(root);
+        let mut unit_set = UnitSet::from_path(Path::with_prefix(root).path(root), &filter, &log)
+            .expect("UnitSet::from_path");
+        let savings = deduplicate(&mut unit_set, None, &log).expect("deduplicate");
+
+        assert_eq!(savings.files, 1);
+        assert_eq!(savings.bytes, 14);
+        assert_eq!(unit_set.len(), 28);
+        let files: Vec<_> = unit_set
+            .0
+            .iter()
+            .flat_map(|unit| unit.files.0.iter())
+            .collect();
+        assert_eq!(files.len(), 3);
+        let photo = files
+            .iter()
+            .find(|file| file.path.ends_with("a/photo"))
+            .expect("a/photo");
+        assert_eq!(photo.duplicates.len(), 1);
+        assert!(photo.duplicates[0].path.ends_with("b/copy"));
+        assert!(photo.duplicates[0].snapshot.is_some());
+    }
+}
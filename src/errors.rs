@@ -2,6 +2,30 @@
 error_chain!{
     errors {
         EmptyUnitSet
+        UnknownMediumProfile(name: String) {
+            description("unknown medium profile")
+            display("unknown medium profile: {}", name)
+        }
+        FileChanged(path: ::std::path::PathBuf) {
+            description("file changed during the backup")
+            display("{:?} changed during the backup", path)
+        }
+        UnreadableFile(path: ::std::path::PathBuf) {
+            description("unreadable file")
+            display("error reading {:?}", path)
+        }
+        FilesSkipped(count: usize) {
+            description("files skipped")
+            display("{} files or directories could not be read and were skipped", count)
+        }
+        VerificationFailed(count: usize) {
+            description("verification failed")
+            display("{} files do not match the index tables", count)
+        }
+        MediumOverflow(name: String, len: u64, capacity: u64) {
+            description("medium overflow")
+            display("Medium {} takes {} bytes, more than its capacity of {}", name, len, capacity)
+        }
     }
 
     foreign_links {
@@ -0,0 +1,135 @@
+use errors::*;
+use source::Source;
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::{self, Read};
+use std::path::PathBuf;
+
+// A list of files to back up, decided by some other tool, taking the
+// place of the scan of the source directories.
+#[derive(Debug)]
+pub struct FileList(Vec<PathBuf>);
+
+impl FileList {
+    // Reads the list from a file, or from standard input if the path is
+    // "-".  Entries are separated by newlines, or by NULs as find
+    // -print0 writes them.
+    pub fn read(path: &str, null: bool) -> Result<Self> {
+        let mut contents = vec![];
+        if path == "-" {
+            let stdin = io::stdin();
+            let mut lock = stdin.lock();
+            lock.read_to_end(&mut contents)
+                .chain_err(|| "error reading the list of files from standard input")?;
+        } else {
+            fs::File::open(path)
+                .and_then(|mut file| file.read_to_end(&mut contents))
+                .chain_err(|| format!("error reading the list of files from {:?}", path))?;
+        }
+        Self::parse(&contents, null)
+    }
+
+    fn parse(contents: &[u8], null: bool) -> Result<Self> {
+        let separator = if null { b'\0' } else { b'\n' };
+        let mut paths = vec![];
+        for entry in contents.split(|&byte| byte == separator) {
+            let entry = if !null && entry.ends_with(b"\r") {
+                &entry[..entry.len() - 1]
+            } else {
+                entry
+            };
+            if entry.is_empty() {
+                continue;
+            }
+            let entry = String::from_utf8(entry.to_vec())
+                .chain_err(|| "a path in the list of files is not valid UTF-8")?;
+            paths.push(PathBuf::from(entry));
+        }
+        Ok(FileList(paths))
+    }
+
+    // Sorts the listed files by the source directory they are in,
+    // giving each as a path under that directory.  Directories in the
+    // list are left out, as their files are listed on their own.
+    pub fn split(self, sources: &[Source]) -> Result<Vec<Vec<PathBuf>>> {
+        let roots = sources
+            .iter()
+            .map(|source| {
+                source
+                    .path
+                    .canonicalize()
+                    .chain_err(|| format!("error finding source directory {:?}", source.path))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let mut split = vec![BTreeSet::new(); sources.len()];
+
+        for path in self.0 {
+            if path.is_dir() {
+                continue;
+            }
+            let (parent, name) = match (path.parent(), path.file_name()) {
+                (Some(parent), Some(name)) => (parent, name),
+                _ => bail!("{:?} in the list of files is not a file", path),
+            };
+            let parent = if parent.as_os_str().is_empty() {
+                PathBuf::from(".")
+            } else {
+                parent.to_path_buf()
+            };
+            let parent = parent
+                .canonicalize()
+                .chain_err(|| format!("error finding the directory of {:?}", path))?;
+
+            let index = roots
+                .iter()
+                .position(|root| parent.starts_with(root))
+                .ok_or_else(|| format!("{:?} is in none of the source directories", path))?;
+            let relative = parent.strip_prefix(&roots[index]).unwrap();
+            let _ = split[index].insert(sources[index].path.join(relative).join(name));
+        }
+
+        Ok(split
+            .into_iter()
+            .map(|paths| paths.into_iter().collect())
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use filelist::FileList;
+    use source::Source;
+    use std::fs;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_parse() {
+        let list = FileList::parse(b"a\r\nb c\n\nd\n", false).expect("parse");
+        let paths: Vec<_> = list.0.iter().map(|path| path.to_str().unwrap()).collect();
+        assert_eq!(paths, vec!["a", "b c", "d"]);
+
+        let list = FileList::parse(b"a\nb\0c\0", true).expect("parse");
+        let paths: Vec<_> = list.0.iter().map(|path| path.to_str().unwrap()).collect();
+        assert_eq!(paths, vec!["a\nb", "c"]);
+    }
+
+    #[test]
+    fn test_split() {
+        let temp_dir = TempDir::new("test_filelist").expect("TempDir::new");
+        let root = temp_dir.path().join("root");
+        fs::create_dir_all(root.join("dir")).expect("create_dir_all");
+        fs::File::create(root.join("dir").join("file")).expect("create");
+        let sources = Source::from_roots(&[&root]).expect("from_roots");
+
+        let list = FileList(vec![
+            root.join("dir").join("file"),
+            root.join("dir"),
+            root.join("dir").join("..").join("dir").join("file"),
+        ]);
+        let split = list.split(&sources).expect("split");
+        assert_eq!(split, vec![vec![root.join("dir").join("file")]]);
+
+        let outside = FileList(vec![temp_dir.path().join("elsewhere")]);
+        assert!(outside.split(&sources).is_err());
+    }
+}
@@ -0,0 +1,496 @@
+use consts::*;
+use errors::*;
+use report::{self, Skipped};
+use std::fs::{self, Metadata};
+use std::io::{self, Read};
+use std::path::Path as StdPath;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::time::{Duration, SystemTime};
+
+// A line from an ignore file, following the rules of gitignore.  A
+// pattern without a slash matches the name of a file or directory at
+// any depth; one with a slash is matched against the whole path below
+// the directory the pattern came from.
+#[derive(Debug)]
+struct Rule {
+    pattern: Vec<char>,
+    negated: bool,
+    dir_only: bool,
+    anchored: bool,
+}
+
+impl Rule {
+    fn parse(line: &str) -> Option<Self> {
+        let mut line = line.trim_right();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+
+        let negated = line.starts_with('!');
+        if negated {
+            line = &line[1..];
+        } else if line.starts_with("\\!") || line.starts_with("\\#") {
+            line = &line[1..];
+        }
+        let dir_only = line.ends_with('/');
+        let line = line.trim_right_matches('/');
+        let anchored = line.contains('/');
+        let line = line.trim_left_matches('/');
+        if line.is_empty() {
+            return None;
+        }
+
+        Some(Rule {
+            pattern: line.chars().collect(),
+            negated,
+            dir_only,
+            anchored,
+        })
+    }
+
+    fn matches(&self, relative: &StdPath, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        let subject = if self.anchored {
+            relative.to_string_lossy()
+        } else {
+            match relative.file_name() {
+                Some(name) => name.to_string_lossy(),
+                None => return false,
+            }
+        };
+        let subject: Vec<char> = subject.chars().collect();
+        glob(&self.pattern, &subject)
+    }
+}
+
+// Matches a path against a pattern, where '*' and '?' stay within a
+// path component and '**' crosses components.
+fn glob(pattern: &[char], subject: &[char]) -> bool {
+    match pattern.first().cloned() {
+        None => subject.is_empty(),
+        Some('*') if pattern.get(1) == Some(&'*') => {
+            let rest = &pattern[2..];
+            if rest.first() == Some(&'/') {
+                let rest = &rest[1..];
+                glob(rest, subject)
+                    || (0..subject.len())
+                        .any(|i| subject[i] == '/' && glob(rest, &subject[i + 1..]))
+            } else {
+                (0..subject.len() + 1).any(|i| glob(rest, &subject[i..]))
+            }
+        }
+        Some('*') => {
+            for i in 0..subject.len() + 1 {
+                if glob(&pattern[1..], &subject[i..]) {
+                    return true;
+                }
+                if i < subject.len() && subject[i] == '/' {
+                    break;
+                }
+            }
+            false
+        }
+        Some('?') => {
+            !subject.is_empty() && subject[0] != '/' && glob(&pattern[1..], &subject[1..])
+        }
+        Some('[') => match class(&pattern[1..], subject.first().cloned()) {
+            Some((matched, len)) => matched && glob(&pattern[1 + len..], &subject[1..]),
+            None => subject.first() == Some(&'[') && glob(&pattern[1..], &subject[1..]),
+        },
+        Some('\\') if pattern.len() > 1 => {
+            subject.first() == Some(&pattern[1]) && glob(&pattern[2..], &subject[1..])
+        }
+        Some(ch) => subject.first() == Some(&ch) && glob(&pattern[1..], &subject[1..]),
+    }
+}
+
+// Matches a character against a class such as [a-z] or [!0-9], given
+// the pattern after the opening bracket.  Returns whether it matched
+// and the length of the class, or None if the bracket is not closed.
+fn class(pattern: &[char], ch: Option<char>) -> Option<(bool, usize)> {
+    let negated = pattern.first() == Some(&'!') || pattern.first() == Some(&'^');
+    let mut i = if negated { 1 } else { 0 };
+    let start = i;
+    let mut matched = false;
+
+    while i < pattern.len() {
+        if pattern[i] == ']' && i > start {
+            let matched = match ch {
+                Some('/') | None => false,
+                Some(_) => matched != negated,
+            };
+            return Some((matched, i + 1));
+        }
+        if i + 2 < pattern.len() && pattern[i + 1] == '-' && pattern[i + 2] != ']' {
+            matched |= ch.map_or(false, |ch| pattern[i] <= ch && ch <= pattern[i + 2]);
+            i += 3;
+        } else {
+            matched |= ch == Some(pattern[i]);
+            i += 1;
+        }
+    }
+
+    None
+}
+
+fn read_rules(path: &StdPath) -> Result<Vec<Rule>> {
+    let mut contents = String::new();
+    fs::File::open(path)
+        .and_then(|mut file| file.read_to_string(&mut contents))
+        .chain_err(|| format!("error reading patterns from {:?}", path))?;
+    Ok(contents.lines().filter_map(Rule::parse).collect())
+}
+
+// Rules from one ignore file, along with those from the directories
+// above it.
+#[derive(Debug)]
+struct Layer {
+    base: PathBuf,
+    rules: Vec<Rule>,
+    parent: Option<Rc<Layer>>,
+}
+
+// Decides what the scan of the source directory leaves out.  Patterns
+// given on the command line or in a pattern file apply from the root
+// down; a .redbackupignore file in any directory adds patterns for
+// that directory and overrides the ones above it.
+#[derive(Debug)]
+pub struct Filter {
+    root: Rc<Layer>,
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+    min_age: Option<Duration>,
+    max_age: Option<Duration>,
+    exclude_caches: bool,
+    one_file_system: bool,
+    skipped: Option<Skipped>,
+    now: SystemTime,
+}
+
+impl Filter {
+    pub fn new<P: AsRef<StdPath>>(root: P) -> Self {
+        Filter {
+            root: Rc::new(Layer {
+                base: root.as_ref().into(),
+                rules: vec![],
+                parent: None,
+            }),
+            min_size: None,
+            max_size: None,
+            min_age: None,
+            max_age: None,
+            exclude_caches: false,
+            one_file_system: false,
+            skipped: None,
+            now: SystemTime::now(),
+        }
+    }
+
+    fn rule(mut self, rule: Option<Rule>) -> Self {
+        if let Some(rule) = rule {
+            Rc::get_mut(&mut self.root)
+                .expect("filter is shared while being built")
+                .rules
+                .push(rule);
+        }
+        self
+    }
+
+    pub fn exclude(self, pattern: &str) -> Self {
+        self.rule(Rule::parse(pattern))
+    }
+
+    pub fn include(self, pattern: &str) -> Self {
+        self.rule(Rule::parse(pattern).map(|rule| Rule {
+            negated: true,
+            ..rule
+        }))
+    }
+
+    pub fn patterns_from<P: AsRef<StdPath>>(self, path: P) -> Result<Self> {
+        Ok(read_rules(path.as_ref())?
+            .into_iter()
+            .fold(self, |filter, rule| filter.rule(Some(rule))))
+    }
+
+    pub fn min_size(mut self, len: u64) -> Self {
+        self.min_size = Some(len);
+        self
+    }
+
+    pub fn max_size(mut self, len: u64) -> Self {
+        self.max_size = Some(len);
+        self
+    }
+
+    // Leaves out files modified more recently than the given age.
+    pub fn min_age(mut self, age: Duration) -> Self {
+        self.min_age = Some(age);
+        self
+    }
+
+    // Leaves out files not modified within the given age.
+    pub fn max_age(mut self, age: Duration) -> Self {
+        self.max_age = Some(age);
+        self
+    }
+
+    // Leaves out directories tagged as caches with a CACHEDIR.TAG file.
+    pub fn exclude_caches(mut self, exclude_caches: bool) -> Self {
+        self.exclude_caches = exclude_caches;
+        self
+    }
+
+    // Leaves out directories on other file systems than the one they
+    // are found in, such as network mounts.
+    pub fn one_file_system(mut self, one_file_system: bool) -> Self {
+        self.one_file_system = one_file_system;
+        self
+    }
+
+    pub fn is_one_file_system(&self) -> bool {
+        self.one_file_system
+    }
+
+    // Leaves out files and directories that cannot be read, recording
+    // them instead of failing.
+    pub fn tolerate(mut self, skipped: Option<Skipped>) -> Self {
+        self.skipped = skipped;
+        self
+    }
+
+    pub fn scope(&self) -> Scope {
+        Scope {
+            filter: self,
+            layer: Rc::clone(&self.root),
+        }
+    }
+}
+
+// The filter as it applies inside one directory.
+#[derive(Clone, Debug)]
+pub struct Scope<'a> {
+    filter: &'a Filter,
+    layer: Rc<Layer>,
+}
+
+impl<'a> Scope<'a> {
+    // Returns the scope for a directory, with the patterns from its
+    // ignore file if it has one.
+    pub fn enter(&self, dir: &StdPath) -> Result<Scope<'a>> {
+        let ignore_file = dir.join(IGNORE_FILE);
+        if !ignore_file.is_file() {
+            return Ok(self.clone());
+        }
+
+        Ok(Scope {
+            filter: self.filter,
+            layer: Rc::new(Layer {
+                base: dir.into(),
+                rules: read_rules(&ignore_file)?,
+                parent: Some(Rc::clone(&self.layer)),
+            }),
+        })
+    }
+
+    // Passes on a result, or records an error and returns None if the
+    // filter tolerates errors.
+    pub fn tolerate<T>(&self, path: &StdPath, result: Result<T>) -> Result<Option<T>> {
+        report::tolerate(self.filter.skipped.as_ref(), path, result)
+    }
+
+    // The last matching rule wins, and rules from deeper ignore files
+    // come after those from above.
+    fn excluded_by_rules(&self, path: &StdPath, is_dir: bool) -> bool {
+        let mut layer = Some(&self.layer);
+        while let Some(current) = layer {
+            if let Ok(relative) = path.strip_prefix(&current.base) {
+                if let Some(rule) = current
+                    .rules
+                    .iter()
+                    .rev()
+                    .find(|rule| rule.matches(relative, is_dir))
+                {
+                    return !rule.negated;
+                }
+            }
+            layer = current.parent.as_ref();
+        }
+        false
+    }
+
+    pub fn excludes_dir(&self, path: &StdPath) -> Result<bool> {
+        Ok(self.excluded_by_rules(path, true) || (self.filter.exclude_caches && is_cache(path)?))
+    }
+
+    pub fn excludes_file(&self, path: &StdPath, metadata: &Metadata) -> Result<bool> {
+        if self.excluded_by_rules(path, false) {
+            return Ok(true);
+        }
+
+        let filter = self.filter;
+        let len = metadata.len();
+        if filter.min_size.map_or(false, |min| len < min)
+            || filter.max_size.map_or(false, |max| len > max)
+        {
+            return Ok(true);
+        }
+
+        if filter.min_age.is_some() || filter.max_age.is_some() {
+            let modified = metadata
+                .modified()
+                .chain_err(|| format!("error getting modification time of {:?}", path))?;
+            // Files from the future count as brand new.
+            let age = filter.now.duration_since(modified).unwrap_or_default();
+            if filter.min_age.map_or(false, |min| age < min)
+                || filter.max_age.map_or(false, |max| age > max)
+            {
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+}
+
+fn is_cache(dir: &StdPath) -> Result<bool> {
+    let tag = dir.join(CACHEDIR_TAG);
+    let mut signature = vec![0u8; CACHEDIR_TAG_SIGNATURE.len()];
+    match fs::File::open(&tag).and_then(|mut file| file.read_exact(&mut signature)) {
+        Ok(()) => Ok(signature == CACHEDIR_TAG_SIGNATURE),
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(err) => Err(Error::with_chain(err, format!("error reading {:?}", tag))),
+    }
+}
+
+// Tells whether a single gitignore-style pattern matches the path of a
+// file in a backup, or one of the directories it is in, as gitignore
+// takes a matching directory with everything below it.
+pub fn matches_file(pattern: &str, path: &StdPath) -> bool {
+    let rule = match Rule::parse(pattern) {
+        Some(rule) => rule,
+        None => return false,
+    };
+    let mut dirs: Vec<_> = path.ancestors()
+        .skip(1)
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .collect();
+    dirs.reverse();
+    dirs.iter().any(|dir| rule.matches(dir, true)) || rule.matches(path, false)
+}
+
+// Parses a size in bytes, optionally followed by K, M, G or T for
+// powers of 1024.
+pub fn parse_size(arg: &str) -> Result<u64> {
+    let arg = arg.trim();
+    let (number, unit) = match arg.char_indices().find(|&(_, ch)| !ch.is_ascii_digit()) {
+        Some((index, _)) => (&arg[..index], arg[index..].to_uppercase()),
+        None => (arg, String::new()),
+    };
+    let shift = match unit.trim_right_matches("IB").trim_right_matches('B') {
+        "" => 0,
+        "K" => 10,
+        "M" => 20,
+        "G" => 30,
+        "T" => 40,
+        _ => bail!("unknown unit in size {:?}", arg),
+    };
+    let number = number
+        .parse::<u64>()
+        .chain_err(|| format!("error parsing size {:?}", arg))?;
+    number
+        .checked_mul(1 << shift)
+        .ok_or_else(|| format!("size {:?} is too large", arg).into())
+}
+
+#[cfg(test)]
+mod test {
+    use consts::*;
+    use filter::{matches_file, parse_size, Filter, Rule};
+    use std::fs;
+    use std::io::Write;
+    use std::path::Path;
+    use tempdir::TempDir;
+
+    fn matches(pattern: &str, path: &str, is_dir: bool) -> bool {
+        Rule::parse(pattern)
+            .expect("Rule::parse")
+            .matches(Path::new(path), is_dir)
+    }
+
+    #[test]
+    fn test_matches_file() {
+        let matches = |pattern: &str, path: &str| matches_file(pattern, Path::new(path));
+        assert!(matches("photos", "photos/2020/a.jpg"));
+        assert!(matches("photos/", "photos/2020/a.jpg"));
+        assert!(matches("photos/", "home/photos/a.jpg"));
+        assert!(matches("/photos", "photos/a.jpg"));
+        assert!(!matches("/photos", "home/photos/a.jpg"));
+        assert!(matches("home/photos", "home/photos/2020/a.jpg"));
+        assert!(matches("photos/**", "photos/2020/a.jpg"));
+        assert!(matches("*.jpg", "photos/a.jpg"));
+        assert!(!matches("photos/", "photos"));
+        assert!(!matches("photos", "photos-old/a.jpg"));
+    }
+
+    #[test]
+    fn test_patterns() {
+        assert!(matches("target/", "a/b/target", true));
+        assert!(!matches("target/", "a/b/target", false));
+        assert!(matches("*.o", "src/main.o", false));
+        assert!(!matches("/*.o", "src/main.o", false));
+        assert!(matches("/*.o", "main.o", false));
+        assert!(matches("doc/*.txt", "doc/a.txt", false));
+        assert!(!matches("doc/*.txt", "doc/sub/a.txt", false));
+        assert!(matches("doc/**/*.txt", "doc/sub/deeper/a.txt", false));
+        assert!(matches("doc/**/*.txt", "doc/a.txt", false));
+        assert!(matches("**/node_modules", "web/app/node_modules", true));
+        assert!(matches("photo-[0-9][0-9].jpg", "photo-42.jpg", false));
+        assert!(!matches("photo-[!0-9].jpg", "photo-4.jpg", false));
+        assert!(matches("?.log", "a.log", false));
+        assert!(Rule::parse("# comment").is_none());
+        assert!(Rule::parse("   ").is_none());
+    }
+
+    #[test]
+    fn test_scopes() {
+        let temp_dir = TempDir::new("test_filter").expect("TempDir::new");
+        let root = temp_dir.path();
+        let project = root.join("project");
+        fs::create_dir_all(project.join("target")).expect("create_dir_all");
+        fs::create_dir_all(root.join("cache")).expect("create_dir_all");
+        fs::File::create(project.join(IGNORE_FILE))
+            .and_then(|mut file| file.write_all(b"!keep.log\n"))
+            .expect("write ignore file");
+        fs::File::create(root.join("cache").join(CACHEDIR_TAG))
+            .and_then(|mut file| file.write_all(CACHEDIR_TAG_SIGNATURE))
+            .expect("write cache tag");
+
+        let filter = Filter::new(root)
+            .exclude("target/")
+            .exclude("*.log")
+            .exclude_caches(true);
+        let scope = filter.scope().enter(root).expect("enter");
+        let inner = scope.enter(&project).expect("enter");
+
+        assert!(inner.excludes_dir(&project.join("target")).expect("excludes_dir"));
+        assert!(scope.excludes_dir(&root.join("cache")).expect("excludes_dir"));
+        assert!(!scope.excludes_dir(&project).expect("excludes_dir"));
+        assert!(scope.excluded_by_rules(&root.join("keep.log"), false));
+        assert!(!inner.excluded_by_rules(&project.join("keep.log"), false));
+        assert!(inner.excluded_by_rules(&project.join("other.log"), false));
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("100").expect("parse_size"), 100);
+        assert_eq!(parse_size("4k").expect("parse_size"), 4096);
+        assert_eq!(parse_size("2GiB").expect("parse_size"), 2 << 30);
+        assert!(parse_size("2X").is_err());
+        assert!(parse_size("M").is_err());
+    }
+}
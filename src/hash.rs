@@ -0,0 +1,165 @@
+use blake3;
+use errors::*;
+use sha1::Sha1;
+use sha2::{self, Digest};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::Path as StdPath;
+
+const BUFFER_SIZE: usize = 64 * 1024;
+
+// A hash of some contents, as long as its algorithm makes it.
+pub type Hash = Vec<u8>;
+
+// The algorithms files and blocks are hashed with.  Tables written
+// before there was a choice do not name one, and were hashed with
+// SHA-1.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Algorithm {
+    Sha1,
+    Sha256,
+    Blake3,
+}
+
+impl Default for Algorithm {
+    fn default() -> Self {
+        Algorithm::Sha1
+    }
+}
+
+impl Algorithm {
+    pub fn from_name(name: &str) -> Result<Self> {
+        match name {
+            "sha1" => Ok(Algorithm::Sha1),
+            "sha256" => Ok(Algorithm::Sha256),
+            "blake3" => Ok(Algorithm::Blake3),
+            _ => bail!("unknown hash algorithm {:?}", name),
+        }
+    }
+
+    // The length of the hashes in bytes.
+    pub fn len(&self) -> usize {
+        match *self {
+            Algorithm::Sha1 => 20,
+            Algorithm::Sha256 | Algorithm::Blake3 => 32,
+        }
+    }
+
+    pub fn hasher(&self) -> Hasher {
+        match *self {
+            Algorithm::Sha1 => Hasher::Sha1(Sha1::new()),
+            Algorithm::Sha256 => Hasher::Sha256(sha2::Sha256::new()),
+            Algorithm::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+        }
+    }
+
+    pub fn digest(&self, data: &[u8]) -> Hash {
+        let mut hasher = self.hasher();
+        hasher.update(data);
+        hasher.digest()
+    }
+
+    // Returns the hash of the contents of a file.
+    pub fn file(&self, path: &StdPath) -> Result<Hash> {
+        let mut file = fs::File::open(path).chain_err(|| ErrorKind::UnreadableFile(path.into()))?;
+        let mut hasher = self.hasher();
+        let mut buf = vec![0u8; BUFFER_SIZE];
+        loop {
+            match file.read(&mut buf) {
+                Ok(0) => break,
+                Ok(len) => hasher.update(&buf[..len]),
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err).chain_err(|| ErrorKind::UnreadableFile(path.into())),
+            }
+        }
+        Ok(hasher.digest())
+    }
+}
+
+// Hashes contents fed to it a piece at a time.
+pub enum Hasher {
+    Sha1(Sha1),
+    Sha256(sha2::Sha256),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Hasher {
+    pub fn update(&mut self, data: &[u8]) {
+        match *self {
+            Hasher::Sha1(ref mut sha1) => sha1.update(data),
+            Hasher::Sha256(ref mut sha256) => Digest::update(sha256, data),
+            Hasher::Blake3(ref mut blake3) => {
+                let _ = blake3.update(data);
+            }
+        }
+    }
+
+    // Returns the hash of what was fed so far.
+    pub fn digest(&self) -> Hash {
+        match *self {
+            Hasher::Sha1(ref sha1) => sha1.digest().bytes().to_vec(),
+            Hasher::Sha256(ref sha256) => sha256.clone().finalize().to_vec(),
+            Hasher::Blake3(ref blake3) => blake3.finalize().as_bytes().to_vec(),
+        }
+    }
+
+    pub fn reset(&mut self) {
+        match *self {
+            Hasher::Sha1(ref mut sha1) => sha1.reset(),
+            Hasher::Sha256(ref mut sha256) => Digest::reset(sha256),
+            Hasher::Blake3(ref mut blake3) => {
+                let _ = blake3.reset();
+            }
+        }
+    }
+}
+
+// Hashes whatever is written to it, such as the contents of a file as
+// it is decompressed.
+impl Write for Hasher {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.update(data);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Formats a hash as hexadecimal digits.
+pub fn hex(hash: &[u8]) -> String {
+    hash.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use hash::{hex, Algorithm};
+
+    #[test]
+    fn test_digest() {
+        assert_eq!(
+            hex(&Algorithm::Sha1.digest(b"abc")),
+            "a9993e364706816aba3e25717850c26c9cd0d89d"
+        );
+        assert_eq!(
+            hex(&Algorithm::Sha256.digest(b"abc")),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            hex(&Algorithm::Blake3.digest(b"abc")),
+            "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85"
+        );
+
+        for &algorithm in &[Algorithm::Sha1, Algorithm::Sha256, Algorithm::Blake3] {
+            let mut hasher = algorithm.hasher();
+            hasher.update(b"something else");
+            hasher.reset();
+            hasher.update(b"a");
+            hasher.update(b"bc");
+            assert_eq!(hasher.digest(), algorithm.digest(b"abc"));
+            assert_eq!(hasher.digest().len(), algorithm.len());
+        }
+    }
+}
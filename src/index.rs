@@ -1,18 +1,24 @@
 #[cfg(feature = "binary-tables")]
 use bincode;
+use compress::Algorithm;
 use errors::*;
+use hash::{self, Hash};
 use medium::Medium;
+use path::Path;
 use serde::{Deserialize, Serialize};
 #[cfg(not(feature = "binary-tables"))]
 use serde_json;
+use snapshot::Snapshot;
+use std::fs;
 use std::io::{Read, Write};
 use std::path::Path as StdPath;
 use std::path::PathBuf;
 use unit::File;
+use verifile::Verifile;
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct MediaTable {
-    identifier: &'static str,
+    identifier: String,
     table: Vec<(usize, String)>,
 }
 
@@ -22,16 +28,32 @@ impl MediaTable {
     }
 
     pub fn add(&mut self, medium: &Medium) -> usize {
+        self.add_name(&medium.name)
+    }
+
+    pub fn add_name(&mut self, name: &str) -> usize {
         let id = self.table.len();
-        self.table.push((id, medium.name.clone()));
+        self.table.push((id, name.into()));
         id
     }
+
+    pub fn name(&self, id: usize) -> Option<&str> {
+        self.table
+            .iter()
+            .find(|&&(medium_id, _)| medium_id == id)
+            .map(|&(_, ref name)| name.as_str())
+    }
+
+    // Returns the ids and the names of the media.
+    pub fn entries(&self) -> &[(usize, String)] {
+        &self.table
+    }
 }
 
 impl Default for MediaTable {
     fn default() -> Self {
         Self {
-            identifier: "Media Index Table",
+            identifier: "Media Index Table".into(),
             table: Default::default(),
         }
     }
@@ -43,7 +65,28 @@ pub struct FileEntry {
     medium_id: usize,
     path: String,
     size: u64,
+    // Set when the file changed while it was backed up, so its
+    // contents may not match its size or its block hashes.
+    #[serde(default)]
+    inconsistent: bool,
+    // Set when the file has the same contents as the file with this id,
+    // which is the only copy stored on the media.
+    #[serde(default)]
+    same_as: Option<usize>,
+    // The modification time and the hash of the contents, for
+    // comparing the file against later backups.
+    #[serde(default)]
+    modified: Option<(u64, u32)>,
+    #[serde(default)]
+    hash: Option<Hash>,
+    // How the file is compressed on its medium, and its size there.
+    // The size and the hash above are those of the original contents.
+    #[serde(default)]
+    compression: Option<Algorithm>,
+    #[serde(default)]
+    stored_size: Option<u64>,
     #[serde(skip)] actual_path: PathBuf,
+    #[serde(skip)] snapshot: Option<Snapshot>,
 }
 
 impl FileEntry {
@@ -55,24 +98,52 @@ impl FileEntry {
         self.medium_id
     }
 
-    #[allow(unused)]
     pub fn path(&self) -> &str {
         &self.path
     }
 
-    #[allow(unused)]
+    pub fn is_inconsistent(&self) -> bool {
+        self.inconsistent
+    }
+
     pub fn size(&self) -> u64 {
         self.size
     }
 
+    pub fn same_as(&self) -> Option<usize> {
+        self.same_as
+    }
+
+    pub fn modified(&self) -> Option<(u64, u32)> {
+        self.modified
+    }
+
+    pub fn hash(&self) -> Option<&[u8]> {
+        self.hash.as_ref().map(|hash| hash.as_slice())
+    }
+
+    pub fn compression(&self) -> Option<Algorithm> {
+        self.compression
+    }
+
+    pub fn stored_size(&self) -> Option<u64> {
+        self.stored_size
+    }
+
     pub fn actual_path(&self) -> &StdPath {
         &self.actual_path
     }
+
+    pub fn snapshot(&self) -> Option<Snapshot> {
+        self.snapshot
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct FileTable {
-    identifier: &'static str,
+    identifier: String,
+    #[serde(default)]
+    hash_algorithm: hash::Algorithm,
     table: Vec<FileEntry>,
 }
 
@@ -87,6 +158,16 @@ impl FileTable {
         Ok(file_index)
     }
 
+    // Records the algorithm the files are hashed with.
+    pub fn with_hash_algorithm(mut self, hash_algorithm: hash::Algorithm) -> Self {
+        self.hash_algorithm = hash_algorithm;
+        self
+    }
+
+    pub fn hash_algorithm(&self) -> hash::Algorithm {
+        self.hash_algorithm
+    }
+
     pub fn add_medium(&mut self, medium: &Medium) -> Result<()> {
         for file in medium.files().iter() {
             let _ = self.add(medium, file)?;
@@ -95,53 +176,143 @@ impl FileTable {
     }
 
     pub fn add(&mut self, medium: &Medium, file: &File) -> Result<usize> {
+        self.add_entry(medium.id(), file)
+    }
+
+    // Adds an entry for the file, followed by one for each of its
+    // duplicates, and returns the id of the first.
+    pub fn add_entry(&mut self, medium_id: usize, file: &File) -> Result<usize> {
         let id = self.table.len();
+        // A file stored compressed is read from its compressed copy,
+        // and was checked for changes as it was compressed.
+        let (size, actual_path, snapshot) = match file.compression {
+            Some(ref compression) => (
+                compression.original_len,
+                compression
+                    .stored
+                    .clone()
+                    .unwrap_or_else(|| file.path.to_path_buf()),
+                None,
+            ),
+            None => (file.len, file.path.to_path_buf(), file.snapshot),
+        };
+        let compression = file.compression
+            .as_ref()
+            .map(|compression| compression.algorithm);
+        let stored_size = compression.map(|_| file.len);
+        let hash = file.compression
+            .as_ref()
+            .and_then(|compression| compression.hash.clone());
+        let inconsistent = file.compression
+            .as_ref()
+            .map(|compression| compression.changed)
+            .unwrap_or(false);
+
         self.table.push(FileEntry {
             id,
-            medium_id: medium.id(),
-            path: file.path
-                .logical()?
-                .to_str()
-                .ok_or_else(|| ErrorKind::from("utf8 error"))?
-                .into(),
-            size: file.len,
-            actual_path: file.path.to_path_buf(),
+            medium_id,
+            path: logical_path(&file.path)?,
+            size,
+            inconsistent,
+            same_as: None,
+            modified: file.snapshot.map(|snapshot| snapshot.modified),
+            hash: hash.clone(),
+            compression,
+            stored_size,
+            actual_path,
+            snapshot,
         });
+        for duplicate in &file.duplicates {
+            let duplicate_id = self.table.len();
+            self.table.push(FileEntry {
+                id: duplicate_id,
+                medium_id,
+                path: logical_path(&duplicate.path)?,
+                size,
+                inconsistent,
+                same_as: Some(id),
+                modified: None,
+                hash: hash.clone(),
+                compression,
+                stored_size,
+                actual_path: duplicate.path.to_path_buf(),
+                snapshot: None,
+            });
+        }
         Ok(id)
     }
 
+    pub fn mark_inconsistent(&mut self, id: usize) {
+        self.table[id].inconsistent = true;
+    }
+
+    // Records the hash of the contents of a file, and of its
+    // duplicates.
+    pub fn set_hash(&mut self, id: usize, hash: &[u8]) {
+        for entry in self.table
+            .iter_mut()
+            .filter(|entry| entry.id == id || entry.same_as == Some(id))
+        {
+            entry.hash = Some(hash.to_vec());
+        }
+    }
+
     pub fn entries(&self) -> &[FileEntry] {
         &self.table
     }
 }
 
+pub fn logical_path(path: &Path) -> Result<String> {
+    Ok(path.logical()?
+        .to_str()
+        .ok_or_else(|| ErrorKind::from("utf8 error"))?
+        .into())
+}
+
 impl Default for FileTable {
     fn default() -> Self {
         Self {
-            identifier: "File Index Table",
+            identifier: "File Index Table".into(),
+            hash_algorithm: Default::default(),
             table: Default::default(),
         }
     }
 }
 
-#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct Block {
     file: usize,
     block: usize,
     size: u32,
-    hash: [u8; 20],
+    hash: Hash,
 }
 
 impl Block {
     pub fn new(file: usize, block: usize, size: u32, hash: &[u8]) -> Self {
-        let mut block = Self {
+        Self {
             file,
             block,
             size,
-            hash: Default::default(),
-        };
-        block.hash.copy_from_slice(hash);
-        block
+            hash: hash.to_vec(),
+        }
+    }
+
+    // The id of the file the block is in.
+    pub fn file(&self) -> usize {
+        self.file
+    }
+
+    // The position of the block in its file, in blocks.
+    pub fn block(&self) -> usize {
+        self.block
+    }
+
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    pub fn hash(&self) -> &[u8] {
+        &self.hash
     }
 }
 
@@ -160,7 +331,13 @@ pub enum RedundancyIndex {
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct RedundancyTable {
-    identifier: &'static str,
+    identifier: String,
+    #[serde(default)]
+    hash_algorithm: hash::Algorithm,
+    // the size of the blocks, unknown for tables written before it was
+    // recorded
+    #[serde(default)]
+    block_size: Option<u64>,
     table: Vec<RedundancyIndex>,
 }
 
@@ -169,15 +346,41 @@ impl RedundancyTable {
         Self::default()
     }
 
+    // Records the algorithm the blocks are hashed with.
+    pub fn with_hash_algorithm(mut self, hash_algorithm: hash::Algorithm) -> Self {
+        self.hash_algorithm = hash_algorithm;
+        self
+    }
+
+    pub fn hash_algorithm(&self) -> hash::Algorithm {
+        self.hash_algorithm
+    }
+
+    // Records the size of the blocks.
+    pub fn with_block_size(mut self, block_size: u64) -> Self {
+        self.block_size = Some(block_size);
+        self
+    }
+
+    pub fn block_size(&self) -> Option<u64> {
+        self.block_size
+    }
+
     pub fn add(&mut self, index: RedundancyIndex) {
         self.table.push(index);
     }
+
+    pub fn entries(&self) -> &[RedundancyIndex] {
+        &self.table
+    }
 }
 
 impl Default for RedundancyTable {
     fn default() -> Self {
         Self {
-            identifier: "Redundancy Index Table",
+            identifier: "Redundancy Index Table".into(),
+            hash_algorithm: Default::default(),
+            block_size: None,
             table: Default::default(),
         }
     }
@@ -187,7 +390,7 @@ impl Default for RedundancyTable {
 pub fn deserialise<R, T>(read: R) -> Result<T>
 where
     R: Read,
-    T: for<'a> Deserialize<'a>,
+    T: Versioned,
 {
     serde_json::from_reader(read).chain_err(|| "deserialisation")
 }
@@ -201,13 +404,55 @@ where
     serde_json::to_writer_pretty(write, table).chain_err(|| "serialisation")
 }
 
+// Binary tables begin with a header naming the layout they are
+// written in.  Those without one were written before tables recorded
+// their hash algorithm, when every hash was SHA-1.
+#[cfg(feature = "binary-tables")]
+const TABLE_MAGIC: &[u8] = b"IDEASTBL";
+#[cfg(feature = "binary-tables")]
+const TABLE_VERSION: u32 = 1;
+
+// A table, or a catalog, that can be read in the layout binary tables
+// had before they were versioned.
+#[cfg(feature = "binary-tables")]
+pub trait Versioned: Sized + for<'a> Deserialize<'a> {
+    type Unversioned: for<'a> Deserialize<'a> + Into<Self>;
+}
+
+// Tables in JSON name their fields, so those written before the header
+// fields came in read as they are.
+#[cfg(not(feature = "binary-tables"))]
+pub trait Versioned: for<'a> Deserialize<'a> {}
+
+#[cfg(not(feature = "binary-tables"))]
+impl<T> Versioned for T
+where
+    T: for<'a> Deserialize<'a>,
+{
+}
+
 #[cfg(feature = "binary-tables")]
 pub fn deserialise<R, T>(mut read: R) -> Result<T>
 where
     R: Read,
-    T: for<'a> Deserialize<'a>,
+    T: Versioned,
 {
-    bincode::deserialize_from(&mut read, bincode::Infinite).chain_err(|| "deserialisation")
+    let mut bytes = vec![];
+    let _ = read.read_to_end(&mut bytes).chain_err(|| "deserialisation")?;
+    if bytes.starts_with(TABLE_MAGIC) {
+        let mut rest = &bytes[TABLE_MAGIC.len()..];
+        let version: u32 = bincode::deserialize_from(&mut rest, bincode::Infinite)
+            .chain_err(|| "deserialisation")?;
+        if version != TABLE_VERSION {
+            bail!("tables of version {} are not supported", version);
+        }
+        bincode::deserialize_from(&mut rest, bincode::Infinite).chain_err(|| "deserialisation")
+    } else {
+        let unversioned: T::Unversioned =
+            bincode::deserialize_from(&mut &bytes[..], bincode::Infinite)
+                .chain_err(|| "deserialisation")?;
+        Ok(unversioned.into())
+    }
 }
 
 #[cfg(feature = "binary-tables")]
@@ -216,5 +461,279 @@ where
     W: Write,
     T: Serialize,
 {
+    write.write_all(TABLE_MAGIC).chain_err(|| "serialisation")?;
+    bincode::serialize_into(&mut write, &TABLE_VERSION, bincode::Infinite)
+        .chain_err(|| "serialisation")?;
     bincode::serialize_into(&mut write, table, bincode::Infinite).chain_err(|| "serialisation")
 }
+
+#[cfg(feature = "binary-tables")]
+impl Versioned for MediaTable {
+    type Unversioned = MediaTable;
+}
+
+#[cfg(feature = "binary-tables")]
+impl Versioned for FileTable {
+    type Unversioned = unversioned::FileTable;
+}
+
+#[cfg(feature = "binary-tables")]
+impl Versioned for RedundancyTable {
+    type Unversioned = unversioned::RedundancyTable;
+}
+
+// The tables as they were laid out before they were versioned.
+#[cfg(feature = "binary-tables")]
+pub mod unversioned {
+    use hash;
+    use index;
+
+    type Hash = [u8; 20];
+
+    // Files were only listed, with no hashes or anything else to
+    // compare them by.
+    #[derive(Debug, Deserialize)]
+    pub struct FileEntry {
+        pub id: usize,
+        pub medium_id: usize,
+        pub path: String,
+        pub size: u64,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct FileTable {
+        pub identifier: String,
+        pub table: Vec<FileEntry>,
+    }
+
+    impl From<FileTable> for index::FileTable {
+        fn from(table: FileTable) -> Self {
+            index::FileTable {
+                identifier: table.identifier,
+                hash_algorithm: hash::Algorithm::Sha1,
+                table: table.table
+                    .into_iter()
+                    .map(|entry| index::FileEntry {
+                        id: entry.id,
+                        medium_id: entry.medium_id,
+                        path: entry.path,
+                        size: entry.size,
+                        inconsistent: false,
+                        same_as: None,
+                        modified: None,
+                        hash: None,
+                        compression: None,
+                        stored_size: None,
+                        actual_path: Default::default(),
+                        snapshot: None,
+                    })
+                    .collect(),
+            }
+        }
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct Block {
+        pub file: usize,
+        pub block: usize,
+        pub size: u32,
+        pub hash: Hash,
+    }
+
+    impl From<Block> for index::Block {
+        fn from(block: Block) -> Self {
+            index::Block::new(block.file, block.block, block.size, &block.hash)
+        }
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub enum RedundancyIndex {
+        Redundancy {
+            left: Block,
+            right: Block,
+            redundancy: Block,
+        },
+        Replication {
+            original: Block,
+            replication: Block,
+        },
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct RedundancyTable {
+        pub identifier: String,
+        pub table: Vec<RedundancyIndex>,
+    }
+
+    impl From<RedundancyTable> for index::RedundancyTable {
+        fn from(table: RedundancyTable) -> Self {
+            index::RedundancyTable {
+                identifier: table.identifier,
+                hash_algorithm: hash::Algorithm::Sha1,
+                block_size: None,
+                table: table.table
+                    .into_iter()
+                    .map(|index| match index {
+                        RedundancyIndex::Redundancy {
+                            left,
+                            right,
+                            redundancy,
+                        } => index::RedundancyIndex::Redundancy {
+                            left: left.into(),
+                            right: right.into(),
+                            redundancy: redundancy.into(),
+                        },
+                        RedundancyIndex::Replication {
+                            original,
+                            replication,
+                        } => index::RedundancyIndex::Replication {
+                            original: original.into(),
+                            replication: replication.into(),
+                        },
+                    })
+                    .collect(),
+            }
+        }
+    }
+}
+
+// Reads a table, or a catalog, from a file.
+pub fn read<T>(path: &StdPath) -> Result<T>
+where
+    T: Versioned,
+{
+    let file = fs::File::open(path).chain_err(|| format!("error opening {:?}", path))?;
+    deserialise(file).chain_err(|| format!("error reading {:?}", path))
+}
+
+// Reads a table written with verifile, once verifile finds it as it
+// was written.
+pub fn read_verified<T>(path: &StdPath) -> Result<T>
+where
+    T: Versioned,
+{
+    let file = Verifile::open(path.to_str().ok_or("utf8 error")?)?;
+    file.verify()
+        .chain_err(|| format!("{:?} is not as it was written", path))?;
+    deserialise(file.read()?).chain_err(|| format!("error reading {:?}", path))
+}
+
+#[cfg(all(test, not(feature = "binary-tables")))]
+mod test {
+    use hash;
+    use index::{self, FileTable, RedundancyIndex, RedundancyTable};
+
+    // Tables written before the hash algorithm was recorded hold SHA-1
+    // hashes.
+    #[test]
+    fn test_read_sha1_tables() {
+        let sha1: Vec<u8> = (0..20).collect();
+        let file_table = format!(
+            r#"{{"identifier": "File Index Table",
+                "table": [{{"id": 0, "medium_id": 0, "path": "a", "size": 3, "hash": {:?}}}]}}"#,
+            sha1
+        );
+        let file_table: FileTable =
+            index::deserialise(file_table.as_bytes()).expect("deserialise");
+        assert_eq!(file_table.hash_algorithm(), hash::Algorithm::Sha1);
+        assert_eq!(file_table.entries()[0].hash(), Some(&sha1[..]));
+
+        let block = format!(r#"{{"file": 0, "block": 0, "size": 3, "hash": {:?}}}"#, sha1);
+        let redun_table = format!(
+            r#"{{"identifier": "Redundancy Index Table",
+                "table": [{{"Replication": {{"original": {}, "replication": {}}}}}]}}"#,
+            block, block
+        );
+        let redun_table: RedundancyTable =
+            index::deserialise(redun_table.as_bytes()).expect("deserialise");
+        assert_eq!(redun_table.hash_algorithm, hash::Algorithm::Sha1);
+        match redun_table.table[0] {
+            RedundancyIndex::Replication { ref original, .. } => {
+                assert_eq!(original.hash, sha1)
+            }
+            _ => panic!("expecting a replication"),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "binary-tables"))]
+mod binary_test {
+    use hash;
+    use index::{self, FileTable, MediaTable, RedundancyIndex, RedundancyTable};
+
+    // The bytes bincode wrote for integers, sequences and strings.
+    fn int(n: u64) -> Vec<u8> {
+        (0..8).map(|i| (n >> (8 * i)) as u8).collect()
+    }
+
+    fn string(s: &str) -> Vec<u8> {
+        let mut bytes = int(s.len() as u64);
+        bytes.extend_from_slice(s.as_bytes());
+        bytes
+    }
+
+    fn block(file: u64, block: u64, size: u32, hash: &[u8]) -> Vec<u8> {
+        let mut bytes = [int(file), int(block)].concat();
+        bytes.extend((0..4).map(|i| (size >> (8 * i)) as u8));
+        bytes.extend_from_slice(hash);
+        bytes
+    }
+
+    // Binary tables written before they were versioned have no header,
+    // list files without hashes, and hold SHA-1 hashes of blocks.
+    #[test]
+    fn test_read_unversioned_tables() {
+        let media_table = [
+            string("Media Index Table"),
+            int(1),
+            int(0),
+            string("Apple"),
+        ].concat();
+        let media_table: MediaTable = index::deserialise(&media_table[..]).expect("deserialise");
+        assert_eq!(media_table.name(0), Some("Apple"));
+
+        let file_table = [
+            string("File Index Table"),
+            int(1),
+            int(0),
+            int(1),
+            string("d1/a"),
+            int(3),
+        ].concat();
+        let file_table: FileTable = index::deserialise(&file_table[..]).expect("deserialise");
+        assert_eq!(file_table.hash_algorithm(), hash::Algorithm::Sha1);
+        let entry = &file_table.entries()[0];
+        assert_eq!((entry.medium_id(), entry.path(), entry.size()), (1, "d1/a", 3));
+        assert_eq!((entry.hash(), entry.modified()), (None, None));
+
+        let sha1 = [7u8; 20];
+        let redun_table = [
+            string("Redundancy Index Table"),
+            int(1),
+            // the second variant, a replication
+            vec![1, 0, 0, 0],
+            block(0, 1, 3, &sha1),
+            block(2, 1, 3, &sha1),
+        ].concat();
+        let redun_table: RedundancyTable =
+            index::deserialise(&redun_table[..]).expect("deserialise");
+        assert_eq!(redun_table.hash_algorithm(), hash::Algorithm::Sha1);
+        match redun_table.entries()[0] {
+            RedundancyIndex::Replication {
+                ref original,
+                ref replication,
+            } => {
+                assert_eq!((original.block(), original.hash()), (1, &sha1[..]));
+                assert_eq!(replication.file(), 2);
+            }
+            _ => panic!("expecting a replication"),
+        }
+
+        // Tables written now carry the header, and their algorithm.
+        let mut bytes = vec![];
+        let table = RedundancyTable::new().with_hash_algorithm(hash::Algorithm::Blake3);
+        index::serialise(&mut bytes, &table).expect("serialise");
+        let table: RedundancyTable = index::deserialise(&bytes[..]).expect("deserialise");
+        assert_eq!(table.hash_algorithm(), hash::Algorithm::Blake3);
+    }
+}
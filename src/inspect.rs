@@ -0,0 +1,368 @@
+use errors::*;
+use hash::{self, hex};
+use index::{Block, RedundancyIndex};
+use mounted::Mounted;
+use serde_json;
+use std::borrow::Cow;
+use std::fmt::{self, Display, Formatter};
+use std::io::Write;
+use std::result::Result as StdResult;
+
+// The tables that can be dumped.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Table {
+    Media,
+    Files,
+    Redundancy,
+}
+
+impl Table {
+    pub fn from_name(name: &str) -> Result<Self> {
+        match name {
+            "media" => Ok(Table::Media),
+            "files" => Ok(Table::Files),
+            "redundancy" => Ok(Table::Redundancy),
+            _ => bail!("unknown table {:?}", name),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Json,
+    Csv,
+}
+
+impl Format {
+    pub fn from_name(name: &str) -> Result<Self> {
+        match name {
+            "json" => Ok(Format::Json),
+            "csv" => Ok(Format::Csv),
+            _ => bail!("unknown format {:?}", name),
+        }
+    }
+}
+
+// What the file table says about one medium of a group.  Duplicates
+// take no room of their own.
+#[derive(Debug, Default, PartialEq)]
+pub struct MediumSummary {
+    pub name: String,
+    pub files: u64,
+    pub duplicates: u64,
+    pub bytes: u64,
+    pub stored_bytes: u64,
+}
+
+// What the tables on a medium say about its group.
+#[derive(Debug)]
+pub struct Summary {
+    pub name: String,
+    pub is_redundancy: bool,
+    pub hash_algorithm: hash::Algorithm,
+    pub block_size: Option<usize>,
+    pub media: Vec<MediumSummary>,
+    pub inconsistent: u64,
+    // pairs of blocks XORed together, and blocks copied as they are
+    // because the other data medium has nothing to pair them with
+    pub redundancy_blocks: u64,
+    pub replication_blocks: u64,
+}
+
+impl Summary {
+    pub fn new(medium: &Mounted) -> Self {
+        let mut media: Vec<_> = medium
+            .media_table()
+            .entries()
+            .iter()
+            .map(|&(_, ref name)| MediumSummary {
+                name: name.clone(),
+                ..MediumSummary::default()
+            })
+            .collect();
+        let mut inconsistent = 0;
+        for entry in medium.file_table().entries() {
+            let summary = match media.get_mut(entry.medium_id()) {
+                Some(summary) => summary,
+                None => continue,
+            };
+            if entry.same_as().is_some() {
+                summary.duplicates += 1;
+            } else {
+                summary.files += 1;
+                summary.bytes += entry.size();
+                summary.stored_bytes += entry.stored_size().unwrap_or_else(|| entry.size());
+            }
+            if entry.is_inconsistent() {
+                inconsistent += 1;
+            }
+        }
+
+        let (redundancy, replication): (Vec<_>, Vec<_>) = medium
+            .redun_table()
+            .entries()
+            .iter()
+            .partition(|index| match **index {
+                RedundancyIndex::Redundancy { .. } => true,
+                RedundancyIndex::Replication { .. } => false,
+            });
+        Summary {
+            name: medium.name().into(),
+            is_redundancy: medium.is_redundancy(),
+            hash_algorithm: medium.file_table().hash_algorithm(),
+            block_size: medium.block_size(),
+            media,
+            inconsistent,
+            redundancy_blocks: redundancy.len() as u64,
+            replication_blocks: replication.len() as u64,
+        }
+    }
+}
+
+impl Display for Summary {
+    fn fmt(&self, f: &mut Formatter) -> StdResult<(), fmt::Error> {
+        let names: Vec<_> = self.media.iter().map(|medium| medium.name.as_str()).collect();
+        writeln!(
+            f,
+            "Medium {}, the {} medium of group {}",
+            self.name,
+            if self.is_redundancy { "redundancy" } else { "data" },
+            names.join(", ")
+        )?;
+        let block_size = match self.block_size {
+            Some(block_size) => format!("{} bytes", block_size),
+            None => "unknown".into(),
+        };
+        writeln!(
+            f,
+            "hash algorithm: {:?}, block size: {}",
+            self.hash_algorithm, block_size
+        )?;
+        writeln!(
+            f,
+            "{:<24} {:>10} {:>10} {:>16} {:>16}",
+            "medium", "files", "duplicates", "bytes", "stored bytes"
+        )?;
+        for medium in &self.media {
+            writeln!(
+                f,
+                "{:<24} {:>10} {:>10} {:>16} {:>16}",
+                medium.name, medium.files, medium.duplicates, medium.bytes, medium.stored_bytes
+            )?;
+        }
+        if self.inconsistent > 0 {
+            writeln!(f, "{} files changed while they were backed up", self.inconsistent)?;
+        }
+        write!(
+            f,
+            "{} blocks of redundancy, {} blocks of replication",
+            self.redundancy_blocks, self.replication_blocks
+        )
+    }
+}
+
+// Writes out one of the tables on a medium in full.  JSON keeps the
+// table as it is stored; CSV makes a row of every entry, with hashes in
+// hexadecimal.
+pub fn dump<W: Write>(medium: &Mounted, table: Table, format: Format, mut write: W) -> Result<()> {
+    match format {
+        Format::Json => {
+            let result = match table {
+                Table::Media => serde_json::to_writer_pretty(&mut write, medium.media_table()),
+                Table::Files => serde_json::to_writer_pretty(&mut write, medium.file_table()),
+                Table::Redundancy => {
+                    serde_json::to_writer_pretty(&mut write, medium.redun_table())
+                }
+            };
+            result.chain_err(|| "error writing the table")?;
+            writeln!(write).chain_err(|| "error writing the table")
+        }
+        Format::Csv => dump_csv(medium, table, &mut write).chain_err(|| "error writing the table"),
+    }
+}
+
+fn dump_csv<W: Write>(medium: &Mounted, table: Table, write: &mut W) -> ::std::io::Result<()> {
+    match table {
+        Table::Media => {
+            writeln!(write, "id,name")?;
+            for &(id, ref name) in medium.media_table().entries() {
+                writeln!(write, "{},{}", id, csv_field(name))?;
+            }
+        }
+        Table::Files => {
+            writeln!(
+                write,
+                concat!(
+                    "id,medium_id,path,size,stored_size,compression,same_as,",
+                    "modified,hash,inconsistent"
+                )
+            )?;
+            for entry in medium.file_table().entries() {
+                writeln!(
+                    write,
+                    "{},{},{},{},{},{},{},{},{},{}",
+                    entry.id(),
+                    entry.medium_id(),
+                    csv_field(entry.path()),
+                    entry.size(),
+                    optional(entry.stored_size()),
+                    optional(entry.compression().map(|algorithm| algorithm.extension())),
+                    optional(entry.same_as()),
+                    optional(
+                        entry
+                            .modified()
+                            .map(|(secs, nanos)| format!("{}.{:09}", secs, nanos))
+                    ),
+                    optional(entry.hash().map(hex)),
+                    entry.is_inconsistent()
+                )?;
+            }
+        }
+        Table::Redundancy => {
+            let columns = |prefix: &str| {
+                ["file", "block", "size", "hash"]
+                    .iter()
+                    .map(|column| format!("{}_{}", prefix, column))
+                    .collect::<Vec<_>>()
+                    .join(",")
+            };
+            // A replicated block takes the place of the left one, and
+            // its copy that of the redundancy.
+            writeln!(
+                write,
+                "kind,{},{},{}",
+                columns("left"),
+                columns("right"),
+                columns("redundancy")
+            )?;
+            for index in medium.redun_table().entries() {
+                match *index {
+                    RedundancyIndex::Redundancy {
+                        ref left,
+                        ref right,
+                        ref redundancy,
+                    } => writeln!(
+                        write,
+                        "redundancy,{},{},{}",
+                        block_fields(left),
+                        block_fields(right),
+                        block_fields(redundancy)
+                    )?,
+                    RedundancyIndex::Replication {
+                        ref original,
+                        ref replication,
+                    } => writeln!(
+                        write,
+                        "replication,{},,,,,{}",
+                        block_fields(original),
+                        block_fields(replication)
+                    )?,
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn block_fields(block: &Block) -> String {
+    format!(
+        "{},{},{},{}",
+        block.file(),
+        block.block(),
+        block.size(),
+        hex(block.hash())
+    )
+}
+
+fn optional<T: ToString>(value: Option<T>) -> String {
+    value.map(|value| value.to_string()).unwrap_or_default()
+}
+
+// Quotes a field that would otherwise be taken apart, doubling the
+// quotes in it.
+fn csv_field<'a>(field: &'a str) -> Cow<'a, str> {
+    if field.contains(&[',', '"', '\n', '\r'][..]) {
+        Cow::Owned(format!("\"{}\"", field.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(field)
+    }
+}
+
+#[cfg(all(test, not(feature = "binary-tables")))]
+mod test {
+    use consts::*;
+    use inspect::{csv_field, dump, Format, Summary, Table};
+    use mounted::Mounted;
+    use std::fs;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_inspect() {
+        let temp_dir = TempDir::new("test_inspect").expect("TempDir::new");
+        let root = temp_dir.path().join("Banana");
+        fs::create_dir(&root).expect("create_dir");
+        let write = |name: &str, contents: &str| {
+            fs::write(root.join(name), contents).expect("write");
+        };
+        write(
+            MEDIA_TABLE_FILE,
+            r#"{"identifier": "Media Table",
+                "table": [[0, "Apple"], [1, "Avocado"], [2, "Banana"]]}"#,
+        );
+        write(
+            FILE_TABLE_FILE,
+            r#"{"identifier": "File Index Table", "hash_algorithm": "blake3", "table": [
+                {"id": 0, "medium_id": 0, "path": "a,b", "size": 300, "hash": [1, 2]},
+                {"id": 1, "medium_id": 0, "path": "c", "size": 300, "same_as": 0},
+                {"id": 2, "medium_id": 1, "path": "d", "size": 100},
+                {"id": 3, "medium_id": 2, "path": "0000000000", "size": 432}]}"#,
+        );
+        let block = |file: usize, block: usize, size: u32| {
+            format!(
+                r#"{{"file": {}, "block": {}, "size": {}, "hash": [255]}}"#,
+                file, block, size
+            )
+        };
+        write(
+            REDUN_TABLE_FILE,
+            &format!(
+                r#"{{"identifier": "Redundancy Index Table", "hash_algorithm": "blake3",
+                    "table": [{{"Redundancy": {{"left": {}, "right": {}, "redundancy": {}}}}},
+                              {{"Replication": {{"original": {}, "replication": {}}}}},
+                              {{"Replication": {{"original": {}, "replication": {}}}}}]}}"#,
+                block(0, 0, 128),
+                block(2, 0, 100),
+                block(3, 0, 128),
+                block(0, 1, 128),
+                block(3, 1, 128),
+                block(0, 2, 44),
+                block(3, 2, 44)
+            ),
+        );
+
+        let medium = Mounted::open(&root).expect("open");
+        let summary = Summary::new(&medium);
+        assert!(summary.is_redundancy);
+        assert_eq!(summary.block_size, Some(128));
+        assert_eq!((summary.media[0].files, summary.media[0].duplicates), (1, 1));
+        assert_eq!(summary.media[0].bytes, 300);
+        assert_eq!(summary.media[2].stored_bytes, 432);
+        assert_eq!((summary.redundancy_blocks, summary.replication_blocks), (1, 2));
+        assert!(summary.to_string().contains("the redundancy medium of group Apple"));
+
+        let mut out = vec![];
+        dump(&medium, Table::Files, Format::Csv, &mut out).expect("dump");
+        let out = String::from_utf8(out).expect("utf8");
+        let lines: Vec<_> = out.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert_eq!(lines[1], "0,0,\"a,b\",300,,,,,0102,false");
+        assert_eq!(lines[2], "1,0,c,300,,,0,,,false");
+
+        let mut out = vec![];
+        dump(&medium, Table::Redundancy, Format::Csv, &mut out).expect("dump");
+        let out = String::from_utf8(out).expect("utf8");
+        assert_eq!(out.lines().nth(2), Some("replication,0,1,128,ff,,,,,3,1,128,ff"));
+
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+    }
+}
@@ -0,0 +1,197 @@
+use consts::*;
+use errors::*;
+use serde_json;
+use std::fs;
+use std::path::Path as StdPath;
+use std::path::PathBuf;
+use verifile::Verifile;
+
+const JOURNAL_IDENTIFIER: &str = "Build Journal";
+
+// A file written for a group, as it was when it was written.  The path
+// is relative to the work directory.  Every such file is written with
+// verifile, which keeps a checksum of its own to check it against, so
+// its contents are not hashed again here.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct WrittenFile {
+    pub path: PathBuf,
+    pub len: u64,
+}
+
+// A group whose redundancy and tables are written.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct CompletedGroup {
+    pub group_id: usize,
+    // The redundancy files in the order they are in the tables.
+    pub redundancy: Vec<WrittenFile>,
+    // The index tables and the encryption key.
+    pub tables: Vec<WrittenFile>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct JournalFile {
+    identifier: String,
+    groups: Vec<CompletedGroup>,
+}
+
+// What a build has done so far, kept in its work directory next to
+// the plan, so that the build can be resumed if it is interrupted.
+// Every group is recorded as soon as it is complete.
+#[derive(Debug)]
+pub struct Journal {
+    dir: PathBuf,
+    groups: Vec<CompletedGroup>,
+}
+
+impl Journal {
+    pub fn new(dir: &StdPath) -> Self {
+        Journal {
+            dir: dir.into(),
+            groups: vec![],
+        }
+    }
+
+    // Reads the journal in the work directory, keeping the groups whose
+    // files are all still as they were written.  The others are left to
+    // be built again.
+    pub fn load(dir: &StdPath) -> Result<Self> {
+        let path = dir.join(JOURNAL_FILE);
+        let file = fs::File::open(&path).chain_err(|| format!("error opening {:?}", path))?;
+        let journal_file: JournalFile = serde_json::from_reader(file)
+            .chain_err(|| format!("error reading the journal from {:?}", path))?;
+        if journal_file.identifier != JOURNAL_IDENTIFIER {
+            bail!("{:?} is not a build journal", path);
+        }
+
+        let mut journal = Self::new(dir);
+        for group in journal_file.groups {
+            let check = group
+                .redundancy
+                .iter()
+                .chain(&group.tables)
+                .map(|file| journal.check(file))
+                .collect::<Result<Vec<_>>>();
+            match check {
+                Ok(_) => journal.groups.push(group),
+                Err(err) => warn!("building group {} again: {}", group.group_id, err),
+            }
+        }
+        Ok(journal)
+    }
+
+    pub fn completed(&self) -> &[CompletedGroup] {
+        &self.groups
+    }
+
+    // Records a group as complete with the files written for it, given
+    // their full paths, replacing what was recorded for it before.
+    pub fn complete(
+        &mut self,
+        group_id: usize,
+        redundancy: &[PathBuf],
+        tables: &[PathBuf],
+    ) -> Result<()> {
+        let group = CompletedGroup {
+            group_id,
+            redundancy: self.written(redundancy)?,
+            tables: self.written(tables)?,
+        };
+        self.groups.retain(|group| group.group_id != group_id);
+        self.groups.push(group);
+        self.save()
+    }
+
+    // Writes the journal out, in place of the last one only once it is
+    // written in full.
+    pub fn save(&self) -> Result<()> {
+        let path = self.dir.join(JOURNAL_FILE);
+        let partial = path.with_extension("partial");
+        let file = fs::File::create(&partial)
+            .chain_err(|| format!("error creating {:?}", partial))?;
+        serde_json::to_writer_pretty(
+            file,
+            &JournalFile {
+                identifier: JOURNAL_IDENTIFIER.into(),
+                groups: self.groups.clone(),
+            },
+        ).chain_err(|| format!("error writing the journal to {:?}", partial))?;
+        fs::rename(&partial, &path)
+            .chain_err(|| format!("error renaming {:?} to {:?}", partial, path))
+    }
+
+    fn written(&self, paths: &[PathBuf]) -> Result<Vec<WrittenFile>> {
+        paths
+            .iter()
+            .map(|path| {
+                let len = path.metadata()
+                    .chain_err(|| format!("error getting metadata of {:?}", path))?
+                    .len();
+                Ok(WrittenFile {
+                    path: path.strip_prefix(&self.dir)
+                        .chain_err(|| format!("{:?} is outside {:?}", path, self.dir))?
+                        .into(),
+                    len,
+                })
+            })
+            .collect()
+    }
+
+    fn check(&self, file: &WrittenFile) -> Result<()> {
+        let path = self.dir.join(&file.path);
+        let len = path.metadata()
+            .chain_err(|| format!("error getting metadata of {:?}", path))?
+            .len();
+        if len != file.len {
+            bail!("{:?} is not as it was written", path);
+        }
+        Verifile::open(path.to_str().ok_or("utf8 error")?)?
+            .verify()
+            .chain_err(|| format!("{:?} is not as it was written", path))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use journal::Journal;
+    use std::fs;
+    use std::io::Write;
+    use tempdir::TempDir;
+    use verifile::Verifile;
+
+    #[test]
+    fn test_journal() {
+        let dir = TempDir::new("test_journal").expect("TempDir::new");
+        let write = |name: &str, contents: &[u8]| {
+            let path = dir.path().join(name);
+            let mut file = Verifile::new(path.to_str().unwrap()).expect("Verifile::new");
+            let mut write = file.write().expect("write");
+            write.write_all(contents).expect("write_all");
+            write.close().expect("close");
+            path
+        };
+        let redundancy = [write("redundancy-0", b"xor"), write("redundancy-1", b"xor")];
+        let tables = [write("table-0", b"{}"), write("table-1", b"{}")];
+
+        let mut journal = Journal::new(dir.path());
+        journal
+            .complete(0, &redundancy[..1], &tables[..1])
+            .expect("complete");
+        journal
+            .complete(1, &redundancy[1..], &tables[1..])
+            .expect("complete");
+        // The last record of a group replaces the earlier ones.
+        journal
+            .complete(0, &redundancy[..1], &tables[..1])
+            .expect("complete");
+        let loaded = Journal::load(dir.path()).expect("load");
+        assert_eq!(loaded.completed(), journal.completed());
+        assert_eq!(loaded.completed().len(), 2);
+
+        // A group with a file that changed since is dropped.
+        fs::write(dir.path().join("redundancy-1"), b"not xor").expect("write");
+        let loaded = Journal::load(dir.path()).expect("load");
+        assert_eq!(loaded.completed().len(), 1);
+        assert_eq!(loaded.completed()[0].group_id, 0);
+        assert_eq!(loaded.completed()[0].redundancy[0].path.to_str(), Some("redundancy-0"));
+    }
+}
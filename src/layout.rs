@@ -2,10 +2,13 @@ use consts::*;
 use errors::*;
 use itertools::Itertools;
 use libc;
+use progress::Progress;
 use slog::Logger;
+use snapshot::Snapshot;
 use std::cell::RefCell;
 use std::env;
 use std::fs;
+use std::io;
 use std::mem;
 use std::ops::Deref;
 #[cfg(target_family = "unix")]
@@ -79,16 +82,49 @@ impl Location {
 #[derive(Debug, Default)]
 struct LocationOptions(Vec<Location>, usize);
 
+// How the files are put in place in the layout.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LinkMode {
+    // Clone files where the file system can, and hard-link or copy
+    // them where it cannot.
+    Auto,
+    // Hard-link files, copying those on other file systems.
+    Hardlink,
+    // Clone files, sharing their data until either copy is written to.
+    // The file system has to support it.
+    Reflink,
+    Copy,
+    Symlink,
+}
+
+impl LinkMode {
+    pub fn from_name(name: &str) -> Result<Self> {
+        match name {
+            "auto" => Ok(LinkMode::Auto),
+            "hardlink" => Ok(LinkMode::Hardlink),
+            "reflink" => Ok(LinkMode::Reflink),
+            "copy" => Ok(LinkMode::Copy),
+            "symlink" => Ok(LinkMode::Symlink),
+            _ => bail!("unknown link mode {:?}", name),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Layout {
     locations: LocationOptions,
     closed: bool,
+    keep_on_failure: bool,
+    link_mode: LinkMode,
+    // Set once cloning a file fails for want of support, so the rest
+    // are not tried in the auto mode.
+    no_reflink: bool,
 
     // pairs of target directory and items that will be placed into
     // it.
     orders: Vec<(PathBuf, Item)>,
 
-    temp_dir: Option<TempDir>,
+    work_dir: Option<PathBuf>,
     log: Logger,
 }
 
@@ -117,35 +153,61 @@ impl Layout {
         }
         let selection = selection.chain_err(|| "none of the locations are writable")?;
 
-        let temp_dir = TempDir::new_in(&locations[selection].path, WORK_DIR).chain_err(|| {
-            format!("error making a temp dir in {:?}", locations[selection].path)
-        })?;
-        slog_info!(&log, "Create temporary directory: {:?}", temp_dir.path());
+        let work_dir = make_work_dir(&locations[selection].path)?;
+        slog_info!(&log, "Create temporary directory: {:?}", work_dir);
 
         Ok(Layout {
             locations: LocationOptions(locations, selection),
             closed: false,
+            keep_on_failure: false,
+            link_mode: LinkMode::Auto,
+            no_reflink: false,
             orders: Default::default(),
-            temp_dir: Some(temp_dir),
+            work_dir: Some(work_dir),
             log,
         })
     }
 
+    pub fn link_mode(mut self, link_mode: LinkMode) -> Self {
+        self.link_mode = link_mode;
+        self
+    }
+
+    // Keeps the work directory when the layout is dropped without being
+    // closed, as it is when a build fails, so the build can be resumed.
+    pub fn keep_on_failure(mut self) -> Self {
+        self.keep_on_failure = true;
+        self
+    }
+
     pub fn force_location<P: AsRef<StdPath>>(&mut self, path: P) -> Result<()> {
         let path = path.as_ref();
         let source = self.locations.0.pop().expect("locations not set up").source;
         self.locations = LocationOptions(vec![Location::new(path, &source)?], 0);
-        self.temp_dir = Some(TempDir::new_in(path, WORK_DIR)
-            .chain_err(|| format!("error making a temp dir in {:?}", path))?);
-        slog_info!(&self.log, "Force: {:?}", self.temp_dir.as_ref().unwrap());
+        self.remove_work_dir()?;
+        self.work_dir = Some(make_work_dir(path)?);
+        slog_info!(&self.log, "Force: {:?}", self.work_dir.as_ref().unwrap());
+        Ok(())
+    }
+
+    // Takes over the work directory of an earlier layout, with whatever
+    // it holds.
+    pub fn resume<P: AsRef<StdPath>>(&mut self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        if !path.is_dir() {
+            bail!("there is no work directory at {:?}", path);
+        }
+        let parent = path.parent().chain_err(|| format!("{:?} has no parent", path))?;
+        let source = self.locations.0.pop().expect("locations not set up").source;
+        self.locations = LocationOptions(vec![Location::new(parent, &source)?], 0);
+        self.remove_work_dir()?;
+        self.work_dir = Some(path.to_owned());
+        slog_info!(&self.log, "Resume: {:?}", path);
         Ok(())
     }
 
     pub fn location(&self) -> PathBuf {
-        self.temp_dir
-            .as_ref()
-            .map(|temp_dir| temp_dir.path().to_owned())
-            .unwrap()
+        self.work_dir.clone().unwrap()
     }
 
     pub fn dir<P: AsRef<StdPath>>(&mut self, dir: P) -> Dir {
@@ -153,63 +215,127 @@ impl Layout {
         Dir(Rc::new(RefCell::new(self)), path)
     }
 
-    pub fn materialise(&mut self) -> Result<()> {
-        for (dir, orders) in &mem::replace(&mut self.orders, vec![])
-            .into_iter()
-            .group_by(|&(ref dir, _)| dir.clone())
-        {
+    // Links, clones or copies the files into place, as the link mode
+    // says.  Returns the files that no longer match the snapshots they
+    // were taken with, before or after they were placed.
+    pub fn materialise(&mut self) -> Result<Vec<PathBuf>> {
+        let mut changed = vec![];
+        let orders = mem::replace(&mut self.orders, vec![]);
+        let progress = Progress::new("layout");
+        progress.add_total(
+            orders.iter().map(|order| order.1.len()).sum(),
+            orders.len() as u64,
+        );
+        for (dir, orders) in &orders.into_iter().group_by(|&(ref dir, _)| dir.clone()) {
             fs::create_dir_all(&dir).chain_err(|| format!("error making directory {:?}", dir))?;
             for order in orders {
+                let len = (order.1).len();
+                let snapshot = (order.1).snapshot;
                 let item = (order.1).source;
                 let dest = dir.join((order.1).name);
-                match fs::hard_link(&item, &dest) {
-                    Err(err) => match err.raw_os_error() {
-                        Some(errno) if errno == libc::EXDEV => {
-                            slog_debug!(&self.log, "file from another filesystem";
-                                       o!("path" => format!("{:?}", item)));
-                            fallback_to_copy(&item, &dest)?;
-                            Ok(())
-                        }
-                        _ => Err(err),
-                    },
-                    ok => ok,
-                }.chain_err(|| format!("error hard-linking {:?} to {:?}", item, dest))?;
+                let unchanged = |item: &StdPath| -> Result<bool> {
+                    match snapshot {
+                        Some(snapshot) => Ok(Snapshot::take(item)? == snapshot),
+                        None => Ok(true),
+                    }
+                };
+                // A file that changed is placed all the same, so that
+                // the medium holds every file its table lists.
+                let before = unchanged(&item)?;
+                self.place(&item, &dest)?;
+                if !before || !unchanged(&item)? {
+                    changed.push(item);
+                }
+                progress.advance(len, 1);
             }
         }
-        Ok(())
+        progress.finish();
+        Ok(changed)
+    }
+
+    fn place(&mut self, item: &StdPath, dest: &StdPath) -> Result<()> {
+        match self.link_mode {
+            LinkMode::Auto if !self.no_reflink => match reflink(item, dest) {
+                Err(ref err) if reflink_unsupported(err) => {
+                    // Files on other file systems cannot be cloned even
+                    // where cloning works.
+                    if err.raw_os_error() != Some(libc::EXDEV) {
+                        slog_info!(&self.log, "cloning files is not supported, linking them";
+                                   o!("error" => format!("{}", err)));
+                        self.no_reflink = true;
+                    }
+                    self.hard_link(item, dest)
+                }
+                result => {
+                    result.chain_err(|| format!("error cloning {:?} to {:?}", item, dest))
+                }
+            },
+            LinkMode::Auto | LinkMode::Hardlink => self.hard_link(item, dest),
+            LinkMode::Reflink => {
+                reflink(item, dest).chain_err(|| format!("error cloning {:?} to {:?}", item, dest))
+            }
+            LinkMode::Copy => fallback_to_copy(item, dest),
+            LinkMode::Symlink => symlink(item, dest),
+        }
+    }
+
+    fn hard_link(&self, item: &StdPath, dest: &StdPath) -> Result<()> {
+        match fs::hard_link(item, dest) {
+            Err(err) => match err.raw_os_error() {
+                Some(errno) if errno == libc::EXDEV => {
+                    slog_debug!(&self.log, "file from another filesystem";
+                               o!("path" => format!("{:?}", item)));
+                    fallback_to_copy(item, dest)?;
+                    Ok(())
+                }
+                _ => Err(err),
+            },
+            ok => ok,
+        }.chain_err(|| format!("error hard-linking {:?} to {:?}", item, dest))
     }
 
     pub fn close(&mut self) -> Result<()> {
         self.closed = true;
-        let temp_dir = self.temp_dir.take().unwrap();
-
-        // We are making a copy of the path for the unlikely case of
-        // an IO error, but closing Layout only happens once so we are
-        // not too worried about it.
-        let path = temp_dir.path().to_owned();
+        self.remove_work_dir()
+    }
 
-        temp_dir
-            .close()
-            .chain_err(|| format!("error closing temp dir {:?}", path))
+    fn remove_work_dir(&mut self) -> Result<()> {
+        match self.work_dir.take() {
+            Some(path) => fs::remove_dir_all(&path)
+                .chain_err(|| format!("error closing temp dir {:?}", path)),
+            None => Ok(()),
+        }
     }
 }
 
+// Makes a work directory with a name of its own in the directory.
+fn make_work_dir(dir: &StdPath) -> Result<PathBuf> {
+    Ok(TempDir::new_in(dir, WORK_DIR)
+        .chain_err(|| format!("error making a temp dir in {:?}", dir))?
+        .into_path())
+}
+
 cfg_if! {
     if #[cfg(not(feature = "debug"))] {
         impl Drop for Layout {
             fn drop(&mut self) {
-                if !self.closed {
-                    if let Err(err) = self.close() {
-                        slog_error!(&self.log, "error closing Layout {:?}", err);
-                    }
+                if self.closed {
+                    // already removed
+                } else if self.keep_on_failure {
+                    slog_warn!(&self.log, "kept the work directory {:?} to resume the build",
+                               self.location());
+                } else if let Err(err) = self.close() {
+                    slog_error!(&self.log, "error closing Layout {:?}", err);
                 }
             }
         }
     } else {
         impl Drop for Layout {
             fn drop(&mut self) {
+                // The work directory is left for inspection, and to
+                // resume the build from.
                 if !self.closed {
-                    let _ = self.temp_dir.take().unwrap().into_path();
+                    slog_info!(&self.log, "left the work directory {:?}", self.location());
                 }
             }
         }
@@ -220,6 +346,17 @@ cfg_if! {
 struct Item {
     name: String,
     source: PathBuf,
+    snapshot: Option<Snapshot>,
+}
+
+impl Item {
+    // The length of the file to put in place, as far as it is known.
+    fn len(&self) -> u64 {
+        match self.snapshot {
+            Some(snapshot) => snapshot.len,
+            None => self.source.metadata().map(|metadata| metadata.len()).unwrap_or(0),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -263,6 +400,7 @@ impl<'a> Dir<'a> {
                         .chain_err(|| format!("utf8 encoding error {:?}", entry.file_name()))?
                         .into(),
                     source: entry.path(),
+                    snapshot: None,
                 },
             ));
         }
@@ -281,12 +419,15 @@ impl<'a> Deref for Dir<'a> {
 pub struct File<'a>(Rc<RefCell<&'a mut Layout>>, PathBuf, String);
 
 impl<'a> File<'a> {
-    pub fn link(self, path: &StdPath) {
+    // Links a file, which is expected to match the snapshot if there
+    // is one.
+    pub fn link(self, path: &StdPath, snapshot: Option<Snapshot>) {
         self.0.borrow_mut().orders.push((
             self.1,
             Item {
                 name: self.2,
                 source: path.into(),
+                snapshot,
             },
         ));
     }
@@ -308,3 +449,137 @@ where
         format!("error copying {:?} to {:?}", from.as_ref(), to.as_ref())
     })
 }
+
+// Clones a file, so that the clone shares the data of the original
+// until either is written to.
+#[cfg(target_os = "linux")]
+fn reflink(from: &StdPath, to: &StdPath) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    // _IOW(0x94, 9, int) from linux/fs.h
+    const FICLONE: u32 = 0x4004_9409;
+
+    let source = fs::File::open(from)?;
+    let target = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(to)?;
+    if unsafe { libc::ioctl(target.as_raw_fd(), FICLONE as _, source.as_raw_fd()) } == -1 {
+        let err = io::Error::last_os_error();
+        drop(target);
+        let _ = fs::remove_file(to);
+        return Err(err);
+    }
+    target.set_permissions(source.metadata()?.permissions())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn reflink(_from: &StdPath, _to: &StdPath) -> io::Result<()> {
+    Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP))
+}
+
+// Tells whether cloning failed because the file systems cannot do it,
+// rather than because of the files.
+fn reflink_unsupported(err: &io::Error) -> bool {
+    match err.raw_os_error() {
+        Some(errno) => [
+            libc::EOPNOTSUPP,
+            libc::ENOTTY,
+            libc::EINVAL,
+            libc::EXDEV,
+            libc::ENOSYS,
+        ].contains(&errno),
+        None => false,
+    }
+}
+
+// Links to a file by its absolute path, so the link works wherever the
+// layout is moved to on the same machine.
+#[cfg(target_family = "unix")]
+fn symlink(from: &StdPath, to: &StdPath) -> Result<()> {
+    let from = from.canonicalize()
+        .chain_err(|| format!("error resolving {:?}", from))?;
+    ::std::os::unix::fs::symlink(&from, to)
+        .chain_err(|| format!("error symlinking {:?} to {:?}", from, to))
+}
+
+#[cfg(not(target_family = "unix"))]
+fn symlink(from: &StdPath, to: &StdPath) -> Result<()> {
+    bail!("error symlinking {:?} to {:?}: not supported on this platform", from, to)
+}
+
+#[cfg(test)]
+mod test {
+    use layout::{Layout, LinkMode};
+    use slog::{Discard, Logger};
+    use snapshot::Snapshot;
+    use std::fs;
+    use std::io::{Read, Write};
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_link_modes() {
+        let temp_dir = TempDir::new("test_layout").expect("TempDir::new");
+        let source = temp_dir.path().join("source");
+        fs::File::create(&source)
+            .and_then(|mut file| file.write_all(b"contents"))
+            .expect("write");
+        let log = Logger::root(Discard, o!());
+
+        for &link_mode in &[
+            LinkMode::Auto,
+            LinkMode::Hardlink,
+            LinkMode::Copy,
+            LinkMode::Symlink,
+        ] {
+            let mut layout = Layout::new(temp_dir.path(), &log)
+                .expect("Layout::new")
+                .link_mode(link_mode);
+            layout.force_location(temp_dir.path()).expect("force_location");
+            let dest = {
+                let file = layout.dir("files").file("dest").expect("file");
+                let dest = file.join("dest");
+                file.link(&source, None);
+                dest
+            };
+            assert!(layout.materialise().expect("materialise").is_empty());
+
+            let mut contents = String::new();
+            let _ = fs::File::open(&dest)
+                .and_then(|mut file| file.read_to_string(&mut contents))
+                .expect("read");
+            assert_eq!(contents, "contents", "{:?}", link_mode);
+            let is_symlink = dest.symlink_metadata()
+                .expect("symlink_metadata")
+                .file_type()
+                .is_symlink();
+            assert_eq!(is_symlink, link_mode == LinkMode::Symlink);
+            layout.close().expect("close");
+        }
+    }
+
+    #[test]
+    fn test_changed_file() {
+        let temp_dir = TempDir::new("test_layout").expect("TempDir::new");
+        let source = temp_dir.path().join("source");
+        fs::write(&source, b"contents").expect("write");
+        let snapshot = Snapshot::take(&source).expect("take");
+        fs::write(&source, b"other contents").expect("write");
+        let log = Logger::root(Discard, o!());
+
+        let mut layout = Layout::new(temp_dir.path(), &log)
+            .expect("Layout::new")
+            .link_mode(LinkMode::Copy);
+        layout.force_location(temp_dir.path()).expect("force_location");
+        let dest = {
+            let file = layout.dir("files").file("dest").expect("file");
+            let dest = file.join("dest");
+            file.link(&source, Some(snapshot));
+            dest
+        };
+        // Changed files are reported, but still placed.
+        assert_eq!(layout.materialise().expect("materialise"), vec![source]);
+        assert_eq!(fs::read(&dest).expect("read"), b"other contents");
+        layout.close().expect("close");
+    }
+}
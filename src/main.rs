@@ -3,6 +3,7 @@
 
 #[cfg(feature = "binary-tables")]
 extern crate bincode;
+extern crate blake3;
 #[macro_use]
 extern crate cfg_if;
 extern crate clap;
@@ -11,12 +12,14 @@ extern crate crypto;
 extern crate error_chain;
 extern crate itertools;
 extern crate libc;
+extern crate num_cpus;
 extern crate rand;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
 extern crate serde_json;
 extern crate sha1;
+extern crate sha2;
 #[macro_use(o, kv, slog_kv, slog_log, slog_record, slog_record_static, slog_b, slog_info,
             slog_error, slog_warn, slog_debug, slog_crit)]
 extern crate slog;
@@ -28,108 +31,989 @@ extern crate slog_term;
 extern crate tempdir;
 extern crate tempfile;
 extern crate time;
+extern crate toml;
 extern crate verifile;
+extern crate zstd;
 
 mod autofill;
 mod block;
 mod block_size;
+mod capacity;
+mod catalog;
+mod compress;
+mod config;
 mod consts;
+mod dedup;
 mod disperse;
 mod errors;
+mod filelist;
+mod filter;
+mod hash;
 mod index;
+mod inspect;
+mod journal;
 mod layout;
 mod medium;
+mod mounted;
+mod naming;
 mod path;
+mod plan;
+mod planner;
+mod pool;
+mod profile;
+mod progress;
 mod redundancy;
+mod report;
+mod restore;
+mod snapshot;
+mod source;
 mod stats;
 mod unit;
 mod unitset;
 
-use autofill::AutoFill;
 use block_size::BlockSize;
-use clap::{App, Arg};
+use capacity::CapacityModel;
+use catalog::{Catalog, Changes};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use config::Settings;
 use consts::*;
-use disperse::Disperse;
 use error_chain::{ChainedError, ExitCode};
 use errors::*;
+use filelist::FileList;
+use filter::Filter;
 use index::{Block, FileTable, MediaTable, RedundancyIndex, RedundancyTable};
 use itertools::Itertools;
-use layout::Layout;
+use journal::{CompletedGroup, Journal};
+use layout::{Layout, LinkMode};
 use medium::Medium;
+use mounted::Mounted;
+use naming::{Namer, Role};
 use path::Path;
+use plan::Plan;
+use planner::{PathOrder, Planner};
+use profile::Profiles;
+use progress::Progress;
 use redundancy::{generate_key, PartialIndexKind, Redundancy};
+use report::Skipped;
 use slog::{Drain, Logger};
+use snapshot::ChangePolicy;
+use source::Source;
 use stats::Stats;
-use std::fs::OpenOptions;
-use std::io::Write;
+use std::cmp;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path as StdPath;
+use std::path::PathBuf;
+use std::result::Result as StdResult;
+use std::time::Duration;
 use unitset::UnitSet;
 use verifile::Verifile;
 
-fn disperse_over(est_media_count: usize, sets: &mut Vec<UnitSet>, log: &Logger) -> Result<()> {
-    assert!(sets.len() <= est_media_count);
-    while sets.len() < est_media_count {
-        sets.push(Default::default());
-    }
+// Arguments that describe how to plan a backup.  They are optional
+// when the plan is read from a file instead, or when they are set in a
+// profile of a configuration file.
+fn plan_args<'a, 'b>(required_unless: &'a [&'a str]) -> Vec<Arg<'a, 'b>> {
+    let start_path = Arg::with_name("START-PATH")
+        .index(1)
+        .help("Specify the source directory for a backup")
+        .required_unless_one(required_unless);
+    let medium = Arg::with_name("MEDIUM")
+        .index(2)
+        .help(concat!(
+            "Specify the profile of the backup media, such as bd-r-25 or lto-7, ",
+            "or the size of the backup media in MiB"
+        ))
+        .required_unless_one(required_unless);
 
-    let mut disperse = Disperse::new(sets, 5., log);
-    disperse.disperse();
+    vec![
+        Arg::with_name("CONFIG")
+            .long("config")
+            .help("Read the settings of the backup from a profile of the specified TOML file")
+            .takes_value(true)
+            .requires("PROFILE"),
+        Arg::with_name("PROFILE")
+            .long("profile")
+            .help(concat!(
+                "Choose the profile of the configuration file; the options given ",
+                "on the command line take precedence over its settings"
+            ))
+            .takes_value(true)
+            .requires("CONFIG"),
+        Arg::with_name("SOURCE")
+            .short("s")
+            .long("source")
+            .help(concat!(
+                "Add another source directory to the backup; each source ",
+                "directory is restored into a tree named after it"
+            ))
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1),
+        Arg::with_name("FILES-FROM")
+            .long("files-from")
+            .help(concat!(
+                "Back up exactly the files listed in the specified file, or in ",
+                "standard input if it is -, instead of scanning the source directories"
+            ))
+            .takes_value(true),
+        Arg::with_name("NULL")
+            .short("0")
+            .long("null")
+            .help("Separate the files in the list with NULs rather than newlines")
+            .requires("FILES-FROM"),
+        Arg::with_name("KEEP-GOING")
+            .short("k")
+            .long("keep-going")
+            .help(concat!(
+                "Skip files and directories that cannot be read instead of failing, ",
+                "and report them at the end"
+            )),
+        Arg::with_name("REPORT")
+            .long("report")
+            .help(concat!(
+                "Write the list of skipped files to the specified file, instead of ",
+                "ideas-skipped.json beside the plan or in the work directory"
+            ))
+            .takes_value(true)
+            .requires("KEEP-GOING"),
+        Arg::with_name("MEDIUM-PROFILES")
+            .long("medium-profiles")
+            .help("Read additional medium profiles from the specified JSON file")
+            .takes_value(true),
+        Arg::with_name("PLANNER")
+            .long("planner")
+            .help("Choose how units are distributed over the media")
+            .takes_value(true)
+            .possible_values(&["pack", "path-order", "locality"])
+            .default_value("pack"),
+        Arg::with_name("LOCALITY-WEIGHT")
+            .long("locality-weight")
+            .help(concat!(
+                "Weigh keeping directory trees together against filling media ",
+                "evenly, from 0 to 1, for the locality planner"
+            ))
+            .takes_value(true)
+            .default_value("0.5")
+            .validator(|arg| match arg.parse::<f64>() {
+                Ok(weight) if weight >= 0. && weight <= 1. => Ok(()),
+                _ => Err("expecting a weight between 0 and 1".into()),
+            }),
+        Arg::with_name("NAMING")
+            .long("naming")
+            .help(concat!(
+                "Choose how media are named: fruit, seq:PREFIX, date[:PREFIX], ",
+                "words:FILE or template:TEMPLATE, where the template may use ",
+                "{seq}, {group}, {role} and {date} [default: fruit]"
+            ))
+            .takes_value(true),
+        Arg::with_name("EXCLUDE")
+            .long("exclude")
+            .help("Leave out files and directories matching the gitignore-style pattern")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1),
+        Arg::with_name("INCLUDE")
+            .long("include")
+            .help("Keep files and directories matching the pattern despite --exclude")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1),
+        Arg::with_name("EXCLUDE-FROM")
+            .long("exclude-from")
+            .help("Read gitignore-style patterns from the specified file")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1),
+        Arg::with_name("MIN-SIZE")
+            .long("min-size")
+            .help("Leave out files smaller than the size, such as 512 or 4K")
+            .takes_value(true)
+            .validator(validate_size),
+        Arg::with_name("MAX-SIZE")
+            .long("max-size")
+            .help("Leave out files larger than the size, such as 100M or 2G")
+            .takes_value(true)
+            .validator(validate_size),
+        Arg::with_name("MIN-AGE")
+            .long("min-age")
+            .help("Leave out files modified within the specified number of days")
+            .takes_value(true)
+            .validator(validate_days),
+        Arg::with_name("MAX-AGE")
+            .long("max-age")
+            .help("Leave out files not modified within the specified number of days")
+            .takes_value(true)
+            .validator(validate_days),
+        Arg::with_name("EXCLUDE-CACHES")
+            .long("exclude-caches")
+            .help("Leave out directories tagged with a CACHEDIR.TAG file"),
+        Arg::with_name("ONE-FILE-SYSTEM")
+            .short("x")
+            .long("one-file-system")
+            .help("Stay on the file system of each source directory, skipping mount points"),
+        Arg::with_name("PREVIOUS")
+            .long("previous")
+            .help(concat!(
+                "Back up only the files that changed since the backup on the medium ",
+                "mounted at the specified directory, referring to it for the rest"
+            ))
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1),
+        Arg::with_name("COMPRESS")
+            .long("compress")
+            .help(concat!(
+                "Store files compressed with zstd, except those of types that are ",
+                "compressed already or that barely compress"
+            )),
+        Arg::with_name("DEDUP")
+            .long("dedup")
+            .help(concat!(
+                "Store files with identical contents only once, reading every file ",
+                "that has the same size as another"
+            )),
+        Arg::with_name("HASH")
+            .long("hash")
+            .help("Choose the algorithm files and blocks are hashed with in the tables")
+            .takes_value(true)
+            .possible_values(&["sha1", "sha256", "blake3"])
+            .default_value("sha256"),
+        Arg::with_name("BLOCK-SIZE")
+            .long("block-size")
+            .help(concat!(
+                "Use blocks of the size, such as 64K, instead of the size that loses ",
+                "the least to padding"
+            ))
+            .takes_value(true)
+            .validator(validate_size),
+        Arg::with_name("SMALL-FILE-THRESHOLD")
+            .long("small-file-threshold")
+            .help(concat!(
+                "Keep directories with no file larger than the size together with ",
+                "their parents [default: 10M]"
+            ))
+            .takes_value(true)
+            .validator(validate_size),
+        Arg::with_name("REDUNDANCY-FILE-SIZE")
+            .long("redundancy-file-size")
+            .help("Split the redundancy of a group into files of up to the size [default: 1G]")
+            .takes_value(true)
+            .validator(validate_size),
+        start_path,
+        medium,
+    ]
+}
 
-    let autofill = AutoFill::new(&format!(
-        concat!(
-            "After dispersing, standard deviation of the used space is {:.2}M.",
-            "  This is {:.2} percents of the mean size of {:.2}MB."
-        ),
-        disperse.measure() / 1024. / 1024.,
-        disperse.measure() * 100.0 / disperse.mean(),
-        disperse.mean() / 1024. / 1024.
-    ));
-    autofill.foreach(|line| slog_info!(log, "{}", line));
+fn validate_size(arg: String) -> StdResult<(), String> {
+    filter::parse_size(&arg)
+        .map(|_| ())
+        .map_err(|err| err.to_string())
+}
 
-    Ok(())
+fn validate_days(arg: String) -> StdResult<(), String> {
+    arg.parse::<u64>()
+        .map(|_| ())
+        .map_err(|_| "expecting a number of days".into())
 }
 
-fn run(log: &Logger) -> Result<()> {
-    info!("started");
+// Builds the filter for the source scan from the settings and the
+// command line.  Patterns from files come first, so --exclude and
+// --include take precedence.
+fn make_filter(
+    matches: &ArgMatches,
+    config: &Settings,
+    root: &StdPath,
+    skipped: Option<&Skipped>,
+) -> Result<Filter> {
+    let mut filter = Filter::new(root).tolerate(skipped.cloned());
+    for path in &config.exclude_from {
+        filter = filter.patterns_from(path)?;
+    }
+    for pattern in &config.exclude {
+        filter = filter.exclude(pattern);
+    }
+    for pattern in &config.include {
+        filter = filter.include(pattern);
+    }
+    if let Some(size) = matches.value_of("MIN-SIZE") {
+        filter = filter.min_size(filter::parse_size(size)?);
+    }
+    if let Some(size) = matches.value_of("MAX-SIZE") {
+        filter = filter.max_size(filter::parse_size(size)?);
+    }
+    let days = |arg: &str| Duration::from_secs(arg.parse::<u64>().unwrap() * 24 * 60 * 60);
+    if let Some(arg) = matches.value_of("MIN-AGE") {
+        filter = filter.min_age(days(arg));
+    }
+    if let Some(arg) = matches.value_of("MAX-AGE") {
+        filter = filter.max_age(days(arg));
+    }
+    Ok(filter
+        .exclude_caches(matches.is_present("EXCLUDE-CACHES"))
+        .one_file_system(matches.is_present("ONE-FILE-SYSTEM")))
+}
 
-    let matches = App::new("Redundant Backup")
+// Arguments that name the media to read, as the directories they are
+// mounted at.
+fn medium_arg<'a, 'b>(help: &'b str) -> Arg<'a, 'b> {
+    Arg::with_name("MEDIUM")
+        .help(help)
+        .required(true)
+        .multiple(true)
+}
+
+fn app<'a, 'b>() -> App<'a, 'b> {
+    App::new("Redundant Backup")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .after_help(concat!(
+            "EXIT STATUS:\n",
+            "    0     success\n",
+            "    1     failure\n",
+            "    2     the backup is complete apart from files that could not be read\n",
+            "    3     files on the media do not match their index tables\n",
+            "    64    the command line is not valid"
+        ))
         .arg(
-            Arg::with_name("WORK-DIR")
-                .short("w")
-                .long("work-dir")
-                .help("Use the specified directory as work directory")
-                .takes_value(true),
+            Arg::with_name("LOG-LEVEL")
+                .long("log-level")
+                .help(concat!(
+                    "Choose the least severe level logged to the log files; the terminal ",
+                    "shows warnings and worse"
+                ))
+                .takes_value(true)
+                .possible_values(&["error", "warning", "info", "debug", "trace"])
+                .default_value("debug")
+                .global(true),
         )
         .arg(
-            Arg::with_name("START-PATH")
-                .required(true)
-                .index(1)
-                .help("Specify the source directory for a backup"),
+            Arg::with_name("LOG-FILE")
+                .long("log-file")
+                .help("Append the log to the specified file")
+                .takes_value(true)
+                .default_value(LOG_PATH)
+                .global(true),
         )
         .arg(
-            Arg::with_name("MEDIUM-SIZE")
-                .required(true)
-                .index(2)
-                .help("Specify the size of the backup media")
-                .validator(|arg| {
-                    arg.parse::<u64>()
-                        .map(|_| ())
-                        .map_err(|_| "expecting the size of the backup media in MiB".into())
-                }),
+            Arg::with_name("JSON-LOG-FILE")
+                .long("json-log-file")
+                .help("Append the log as JSON records to the specified file")
+                .takes_value(true)
+                .default_value(LOG_PATH_JSON)
+                .global(true),
+        )
+        .subcommand(
+            SubCommand::with_name("plan")
+                .about("Plan a backup without reading any file contents, unless deduplicating")
+                .args(&plan_args(&["PROFILE"]))
+                .arg(
+                    Arg::with_name("OUTPUT")
+                        .short("o")
+                        .long("output")
+                        .help("Write the plan to the specified file")
+                        .takes_value(true)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("build")
+                .about("Build the media for a backup")
+                .arg(
+                    Arg::with_name("WORK-DIR")
+                        .short("w")
+                        .long("work-dir")
+                        .help("Use the specified directory as work directory")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("PLAN")
+                        .long("plan")
+                        .help("Execute the plan in the specified file instead of planning")
+                        .takes_value(true)
+                        .conflicts_with("CONFIG"),
+                )
+                .arg(
+                    Arg::with_name("RESUME")
+                        .long("resume")
+                        .help(concat!(
+                            "Resume the interrupted build in the specified work directory, ",
+                            "keeping the groups it completed"
+                        ))
+                        .takes_value(true)
+                        .conflicts_with_all(&["WORK-DIR", "PLAN", "START-PATH", "CONFIG"]),
+                )
+                .arg(
+                    Arg::with_name("ON-CHANGE")
+                        .long("on-change")
+                        .help(concat!(
+                            "Choose what to do about files that change during the backup: ",
+                            "read them again, fail, or mark them inconsistent in the index"
+                        ))
+                        .takes_value(true)
+                        .possible_values(&["retry", "fail", "mark"])
+                        .default_value("fail"),
+                )
+                .arg(
+                    Arg::with_name("JOBS")
+                        .short("j")
+                        .long("jobs")
+                        .help(concat!(
+                            "Build the redundancy of up to the specified number of groups ",
+                            "at a time, by default as many as there are CPUs"
+                        ))
+                        .takes_value(true)
+                        .validator(|arg| match arg.parse::<usize>() {
+                            Ok(jobs) if jobs > 0 => Ok(()),
+                            _ => Err("expecting a positive number of jobs".into()),
+                        }),
+                )
+                .arg(
+                    Arg::with_name("LINK-MODE")
+                        .long("link-mode")
+                        .visible_alias("output-mode")
+                        .help(concat!(
+                            "Choose how files are put in place on the media: cloned where the ",
+                            "file system supports it and hard-linked otherwise, hard-linked, ",
+                            "cloned, copied or symlinked [default: auto]"
+                        ))
+                        .takes_value(true)
+                        .possible_values(&["auto", "hardlink", "reflink", "copy", "symlink"]),
+                )
+                .arg(
+                    Arg::with_name("MEMORY-LIMIT")
+                        .long("memory-limit")
+                        .help(concat!(
+                            "Keep the blocks held in memory while building redundancy within ",
+                            "the size, such as 512M or 2G, building fewer groups at a time ",
+                            "if need be [default: 1G]"
+                        ))
+                        .takes_value(true)
+                        .validator(|arg| {
+                            filter::parse_size(&arg)
+                                .map(|_| ())
+                                .map_err(|err| err.to_string())
+                        }),
+                )
+                .args(&plan_args(&["PLAN", "RESUME", "PROFILE"])),
+        )
+        .subcommand(
+            SubCommand::with_name("verify")
+                .about("Check the files on media against their index tables")
+                .arg(medium_arg("Specify the directories the media are mounted at")),
+        )
+        .subcommand(
+            SubCommand::with_name("restore")
+                .about("Restore the files on data media")
+                .arg(
+                    Arg::with_name("TARGET")
+                        .short("t")
+                        .long("target")
+                        .help("Restore the files into the specified directory")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("ONLY")
+                        .long("only")
+                        .help("Restore only the files matching the gitignore-style pattern")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::with_name("GENERATION")
+                        .short("g")
+                        .long("generation")
+                        .help(concat!(
+                            "Restore the tree as it was in the specified generation of the ",
+                            "backup, from the media of that and earlier generations"
+                        ))
+                        .takes_value(true)
+                        .validator(|arg| match arg.parse::<u64>() {
+                            Ok(_) => Ok(()),
+                            _ => Err("expecting the number of a generation".into()),
+                        }),
+                )
+                .arg(medium_arg("Specify the directories the media are mounted at")),
+        )
+        .subcommand(
+            SubCommand::with_name("recover")
+                .about(concat!(
+                    "Recover the lost data medium of a group from the other data medium ",
+                    "and the redundancy medium"
+                ))
+                .arg(
+                    Arg::with_name("TARGET")
+                        .short("t")
+                        .long("target")
+                        .help("Write the recovered medium into the specified directory")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    medium_arg("Specify the directories the two media are mounted at")
+                        .number_of_values(2),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("ls")
+                .about("List the files on a medium")
+                .arg(
+                    Arg::with_name("LONG")
+                        .short("l")
+                        .long("long")
+                        .help("Show the size and modification time of every file"),
+                )
+                .arg(
+                    Arg::with_name("MEDIUM")
+                        .help("Specify the directory the medium is mounted at")
+                        .required(true),
+                )
+                .arg(Arg::with_name("PREFIX").help("List only the files under the path")),
         )
-        .get_matches();
+        .subcommand(
+            SubCommand::with_name("find")
+                .about("Find the media that hold files in the catalog of a backup")
+                .arg(
+                    Arg::with_name("MEDIUM")
+                        .help("Specify the directory any medium of the backup is mounted at")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("PATTERN")
+                        .help("Find the files matching the gitignore-style pattern")
+                        .required(true)
+                        .multiple(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("inspect")
+                .about("Summarise the index tables on media, or dump one of them")
+                .arg(
+                    Arg::with_name("DUMP")
+                        .long("dump")
+                        .help("Write out the whole of a table of a single medium")
+                        .takes_value(true)
+                        .possible_values(&["media", "files", "redundancy"]),
+                )
+                .arg(
+                    Arg::with_name("FORMAT")
+                        .long("format")
+                        .help("Dump the table in the format, with hashes in hexadecimal in CSV")
+                        .takes_value(true)
+                        .possible_values(&["json", "csv"])
+                        .default_value("json"),
+                )
+                .arg(medium_arg("Specify the directories the media are mounted at")),
+        )
+        .subcommand(
+            SubCommand::with_name("diff")
+                .about("Compare the catalogs of two backups")
+                .arg(
+                    Arg::with_name("OLD")
+                        .help("Specify the directory a medium of the older backup is mounted at")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("NEW")
+                        .help("Specify the directory a medium of the newer backup is mounted at")
+                        .required(true),
+                ),
+        )
+}
+
+fn run(matches: &ArgMatches, log: &Logger) -> Result<()> {
+    info!("started");
+
+    let skipped = Skipped::new();
+    // Where the list of skipped files goes, unless an option says.
+    let mut report_dir = PathBuf::new();
+    let mut report = None;
+    let tolerated = |matches: &ArgMatches| {
+        if matches.is_present("KEEP-GOING") {
+            Some(skipped.clone())
+        } else {
+            None
+        }
+    };
+
+    match matches.subcommand() {
+        ("plan", Some(matches)) => {
+            let plan = make_plan(matches, tolerated(matches).as_ref(), log)?;
+            let output = matches.value_of("OUTPUT").unwrap();
+            plan.save(output)?;
+            info!("wrote the plan to {}", output);
+            report_dir = StdPath::new(output)
+                .parent()
+                .map(|dir| dir.to_path_buf())
+                .unwrap_or_default();
+            report = matches.value_of("REPORT").map(PathBuf::from);
+        }
+        ("build", Some(matches)) => {
+            let (mut plan, work_dir) = match matches.value_of("RESUME") {
+                Some(dir) => {
+                    info!("resume the build in {}", dir);
+                    (Plan::load(StdPath::new(dir).join(PLAN_FILE))?, WorkDir::Resume(dir))
+                }
+                None => {
+                    let plan = match matches.value_of("PLAN") {
+                        Some(path) => {
+                            info!("read the plan from {}", path);
+                            Plan::load(path)?
+                        }
+                        None => make_plan(matches, tolerated(matches).as_ref(), log)?,
+                    };
+                    match matches.value_of("WORK-DIR") {
+                        Some(dir) => (plan, WorkDir::In(dir)),
+                        None => (plan, WorkDir::New),
+                    }
+                }
+            };
+            let policy = ChangePolicy::from_name(matches.value_of("ON-CHANGE").unwrap())?;
+            if let Some(link_mode) = matches.value_of("LINK-MODE") {
+                plan.config.link_mode = Some(link_mode.into());
+            }
+            let link_mode = LinkMode::from_name(plan.config.link_mode())?;
+            let resources = Resources {
+                jobs: match matches.value_of("JOBS") {
+                    Some(jobs) => jobs.parse().unwrap(),
+                    None => num_cpus::get(),
+                },
+                memory: match matches.value_of("MEMORY-LIMIT") {
+                    Some(size) => filter::parse_size(size)?,
+                    None => MEMORY_LIMIT,
+                },
+            };
+            report_dir = build(
+                plan,
+                work_dir,
+                link_mode,
+                policy,
+                tolerated(matches).as_ref(),
+                resources,
+                log,
+            )?;
+            report = matches.value_of("REPORT").map(PathBuf::from);
+        }
+        ("verify", Some(matches)) => {
+            let mut damaged = 0;
+            for root in matches.values_of("MEDIUM").unwrap() {
+                let medium = Mounted::open(root)?;
+                let outcome = restore::verify(&medium)?;
+                println!(
+                    "Medium {}: {} files with {} bytes verified, {} damaged",
+                    medium.name(),
+                    outcome.files,
+                    outcome.bytes,
+                    outcome.damaged.len()
+                );
+                damaged += outcome.damaged.len();
+            }
+            if damaged > 0 {
+                bail!(ErrorKind::VerificationFailed(damaged));
+            }
+        }
+        ("restore", Some(matches)) => {
+            let target = StdPath::new(matches.value_of("TARGET").unwrap());
+            let patterns: Vec<_> = matches.values_of("ONLY").into_iter().flat_map(|v| v).collect();
+            let mut damaged = 0;
+            if let Some(generation) = matches.value_of("GENERATION") {
+                let generation = generation.parse().unwrap();
+                let media = matches
+                    .values_of("MEDIUM")
+                    .unwrap()
+                    .map(Mounted::open)
+                    .collect::<Result<Vec<_>>>()?;
+                let outcome = restore::restore_generation(&media, generation, target, &patterns)?;
+                println!(
+                    "Generation {}: restored {} files with {} bytes into {:?}",
+                    generation,
+                    outcome.files,
+                    outcome.bytes,
+                    target
+                );
+                damaged += outcome.damaged.len();
+            } else {
+                for root in matches.values_of("MEDIUM").unwrap() {
+                    let medium = Mounted::open(root)?;
+                    let outcome = restore::restore(&medium, target, &patterns)?;
+                    println!(
+                        "Medium {}: restored {} files with {} bytes into {:?}",
+                        medium.name(),
+                        outcome.files,
+                        outcome.bytes,
+                        target
+                    );
+                    damaged += outcome.damaged.len();
+                }
+            }
+            if damaged > 0 {
+                bail!(ErrorKind::VerificationFailed(damaged));
+            }
+        }
+        ("recover", Some(matches)) => {
+            let target = StdPath::new(matches.value_of("TARGET").unwrap());
+            let mut media = matches
+                .values_of("MEDIUM")
+                .unwrap()
+                .map(Mounted::open)
+                .collect::<Result<Vec<_>>>()?;
+            media.sort_by_key(|medium| medium.is_redundancy());
+            let outcome = restore::recover(&media[0], &media[1], target)?;
+            println!(
+                "recovered {} files with {} bytes into {:?}",
+                outcome.files,
+                outcome.bytes,
+                target
+            );
+            if !outcome.damaged.is_empty() {
+                bail!(ErrorKind::VerificationFailed(outcome.damaged.len()));
+            }
+        }
+        ("ls", Some(matches)) => {
+            let medium = Mounted::open(matches.value_of("MEDIUM").unwrap())?;
+            let prefix = matches.value_of("PREFIX").unwrap_or("");
+            let entries = medium
+                .file_table()
+                .entries()
+                .iter()
+                .filter(|entry| entry.medium_id() == medium.id())
+                .filter(|entry| StdPath::new(entry.path()).starts_with(prefix));
+            for entry in entries {
+                if matches.is_present("LONG") {
+                    println!(
+                        "{:>14} {} {}",
+                        entry.size(),
+                        format_modified(entry.modified())?,
+                        entry.path()
+                    );
+                } else {
+                    println!("{}", entry.path());
+                }
+            }
+        }
+        ("find", Some(matches)) => {
+            let catalog = Catalog::load(StdPath::new(matches.value_of("MEDIUM").unwrap()))?;
+            let patterns: Vec<_> = matches.values_of("PATTERN").unwrap().collect();
+            let found = catalog.entries().iter().filter(|entry| {
+                patterns
+                    .iter()
+                    .any(|pattern| filter::matches_file(pattern, StdPath::new(&entry.path)))
+            });
+            for entry in found {
+                println!("{}\t{}", entry.medium, entry.path);
+            }
+        }
+        ("inspect", Some(matches)) => {
+            let roots: Vec<_> = matches.values_of("MEDIUM").unwrap().collect();
+            match matches.value_of("DUMP") {
+                Some(table) => {
+                    if roots.len() > 1 {
+                        bail!("only the tables of one medium can be dumped at a time");
+                    }
+                    let medium = Mounted::open(roots[0])?;
+                    let table = inspect::Table::from_name(table)?;
+                    let format = inspect::Format::from_name(matches.value_of("FORMAT").unwrap())?;
+                    let stdout = io::stdout();
+                    inspect::dump(&medium, table, format, stdout.lock())?;
+                }
+                None => for root in roots {
+                    let medium = Mounted::open(root)?;
+                    println!("{}", inspect::Summary::new(&medium));
+                },
+            }
+        }
+        ("diff", Some(matches)) => {
+            let old = Catalog::load(StdPath::new(matches.value_of("OLD").unwrap()))?;
+            let new = Catalog::load(StdPath::new(matches.value_of("NEW").unwrap()))?;
+            let diff = old.diff(&new);
+            for entry in &diff.added {
+                println!("+ {}", entry.path);
+            }
+            for entry in &diff.deleted {
+                println!("- {}", entry.path);
+            }
+            for &(_, entry) in &diff.changed {
+                println!("M {}", entry.path);
+            }
+            info!(
+                "generation {} to {}: {} files added, {} deleted, {} changed",
+                old.generation(),
+                new.generation(),
+                diff.added.len(),
+                diff.deleted.len(),
+                diff.changed.len()
+            );
+        }
+        _ => unreachable!(),
+    }
+
+    if !skipped.is_empty() {
+        let report = report.unwrap_or_else(|| report_dir.join(SKIPPED_REPORT_FILE));
+        skipped.save(&report)?;
+        for line in skipped.summary().lines() {
+            warn!("{}", line);
+        }
+        warn!("wrote the list of skipped files to {:?}", report);
+        bail!(ErrorKind::FilesSkipped(skipped.len()));
+    }
+
+    info!("finished");
+    Ok(())
+}
+
+// Formats a modification time from the tables as ls does, in local
+// time.
+fn format_modified(modified: Option<(u64, u32)>) -> Result<String> {
+    match modified {
+        Some((secs, nanos)) => {
+            let tm = time::at(time::Timespec::new(secs as i64, nanos as i32));
+            time::strftime("%Y-%m-%d %H:%M", &tm).chain_err(|| "error formatting a time")
+        }
+        None => Ok(format!("{:16}", "")),
+    }
+}
+
+// Works out the settings of a backup from the profile of the
+// configuration file, if there is one, and the command line.  Sources
+// given with --source are added to those of the profile unless a
+// start path is given as well.
+fn settings(matches: &ArgMatches) -> Result<Settings> {
+    let profile = match (matches.value_of("CONFIG"), matches.value_of("PROFILE")) {
+        (Some(path), Some(profile)) => {
+            info!("read the settings of profile {} from {}", profile, path);
+            Settings::load(path, profile)?
+        }
+        _ => Settings::default(),
+    };
+    let size = |name: &str| -> Result<Option<u64>> {
+        match matches.value_of(name) {
+            Some(arg) => Ok(Some(filter::parse_size(arg)?)),
+            None => Ok(None),
+        }
+    };
+    let values = |name: &str| -> Vec<String> {
+        matches
+            .values_of(name)
+            .into_iter()
+            .flat_map(|v| v)
+            .map(String::from)
+            .collect()
+    };
+
+    let more_sources: Vec<PathBuf> = values("SOURCE").into_iter().map(PathBuf::from).collect();
+    let (sources, more_sources) = match matches.value_of("START-PATH") {
+        Some(start_path) => {
+            let mut sources = vec![PathBuf::from(start_path)];
+            sources.extend(more_sources);
+            (sources, vec![])
+        }
+        None => (vec![], more_sources),
+    };
+    let cli = Settings {
+        sources,
+        exclude: values("EXCLUDE"),
+        include: values("INCLUDE"),
+        exclude_from: values("EXCLUDE-FROM").into_iter().map(PathBuf::from).collect(),
+        medium: matches.value_of("MEDIUM").map(String::from),
+        medium_profiles: matches.value_of("MEDIUM-PROFILES").map(PathBuf::from),
+        naming: matches.value_of("NAMING").map(String::from),
+        link_mode: matches.value_of("LINK-MODE").map(String::from),
+        block_size: size("BLOCK-SIZE")?,
+        small_file_threshold: size("SMALL-FILE-THRESHOLD")?,
+        redundancy_file_size: size("REDUNDANCY-FILE-SIZE")?,
+        ..Settings::default()
+    };
+    let mut config = profile.merge(cli);
+    config.sources.extend(more_sources);
+    config.effective()
+}
+
+// Scans the source directories and decides what goes on which
+// medium, without reading any file contents.
+fn make_plan(matches: &ArgMatches, skipped: Option<&Skipped>, log: &Logger) -> Result<Plan> {
+    let config = settings(matches)?;
+    let sources = Source::from_roots(&config.sources)?;
+    let mut profiles = Profiles::builtin();
+    if let Some(ref path) = config.medium_profiles {
+        profiles = profiles.load(path)?;
+    }
+    let profile = profiles.resolve(config.medium.as_ref().expect("Settings::medium"))?;
+    let medium_size = profile.capacity;
+    info!(
+        "medium profile: {} ({}), {} bytes usable",
+        profile.name,
+        profile.description,
+        medium_size
+    );
+
+    let file_lists = match matches.value_of("FILES-FROM") {
+        Some(path) => Some(FileList::read(path, matches.is_present("NULL"))?.split(&sources)?),
+        None => None,
+    };
 
-    let work_dir = matches.value_of("WORK-DIR");
-    let start_path = matches.value_of("START-PATH").unwrap();
-    let medium_size: u64 = matches
-        .value_of("MEDIUM-SIZE")
-        .unwrap()
-        .parse::<u64>()
-        .unwrap() * 1024 * 1024;
+    // Units from all the sources are packed together, so media are
+    // shared across them.
+    let mut unit_set = UnitSet::default();
+    for (index, source) in sources.iter().enumerate() {
+        let mut source_set = match file_lists {
+            Some(ref lists) => UnitSet::from_files(source.root(), &lists[index], skipped, log)?,
+            None => {
+                let filter = make_filter(matches, &config, &source.path, skipped)?;
+                UnitSet::from_path(source.root(), &filter, log)?
+            }
+        };
+        debug_assert_eq!(
+            source_set.len(),
+            source_set.0.iter().fold(0, |s, u| s + u.len)
+        );
+        let plan = source_set.plan_merges(config.small_file_threshold());
+        source_set.execute_merges(&plan);
+        if sources.len() > 1 {
+            info!("{:?} under {:?}: {}", source.path, source.namespace, source_set);
+        }
+        unit_set.append(source_set);
+    }
 
-    let mut unit_set = UnitSet::from_path(Path::with_prefix(&start_path).path(&start_path), log)?;
-    debug_assert_eq!(unit_set.len(), unit_set.0.iter().fold(0, |s, u| s + u.len));
-    let plan = unit_set.plan_merges();
-    unit_set.execute_merges(&plan);
+    let previous = Catalog::latest(
+        matches
+            .values_of("PREVIOUS")
+            .into_iter()
+            .flat_map(|v| v)
+            .map(|path| Catalog::load(StdPath::new(path)))
+            .collect::<Result<Vec<_>>>()?,
+    );
+    let (generation, changes) = match previous {
+        Some(previous) => {
+            let changes = previous.compare(&mut unit_set, log)?;
+            info!(
+                "{} files with {} bytes unchanged since generation {}, {} files deleted",
+                changes.unchanged.len(),
+                changes.bytes,
+                previous.generation(),
+                changes.deleted.len()
+            );
+            (previous.generation() + 1, changes)
+        }
+        None => (0, Changes::default()),
+    };
+
+    if matches.is_present("DEDUP") {
+        let savings = dedup::deduplicate(&mut unit_set, skipped, log)?;
+        info!(
+            "deduplication saved {} bytes in {} duplicate files",
+            savings.bytes,
+            savings.files
+        );
+    }
+
+    if matches.is_present("COMPRESS") {
+        let savings = compress::plan(&mut unit_set, skipped, log)?;
+        info!(
+            "compression saves {} bytes in {} files",
+            savings.bytes,
+            savings.files
+        );
+    }
 
     if let Some(unit) = unit_set.0.iter().find(|unit| unit.len > medium_size) {
         crit!("Unit with length larger than the medium size isn't supported.");
@@ -137,202 +1021,409 @@ fn run(log: &Logger) -> Result<()> {
         panic!("Unit with length larger than the medium size isn't supported.");
     }
 
-    let mut est_media_count = ((unit_set.len() + (medium_size - 1)) / medium_size) as usize;
     info!("{}", unit_set);
-    info!("estimated media count: {}", est_media_count);
-    if est_media_count & 0x1 == 0x1 {
-        // make even number
-        est_media_count += 1;
+    let mount_points = unit_set.mount_points().to_vec();
+    if !mount_points.is_empty() {
+        info!("skipped {} mount points", mount_points.len());
     }
 
-    let mut sets = Vec::with_capacity(est_media_count);
-    sets.push(unit_set);
-    loop {
-        disperse_over(est_media_count, &mut sets, log)?;
-        sets.iter().for_each(|set| info!("{}", set));
+    let block_size = match config.block_size {
+        Some(block_size) => {
+            if block_size % profile.sector_size != 0 {
+                bail!(
+                    "block size {} is not a multiple of the sector size {} of the media",
+                    block_size,
+                    profile.sector_size
+                );
+            }
+            info!("use the block size {}", block_size);
+            block_size
+        }
+        None => {
+            /*
+             * I've got the list of file sizes here, and I can use the
+             * list to calculate the optimal block size.  This block size
+             * is fixed for all the media in this backup.
+             */
+            let files: Vec<_> = unit_set
+                .0
+                .iter()
+                .flat_map(|unit| unit.files.0.iter())
+                .collect();
 
-        if sets.iter().all(|set| set.len() <= medium_size) {
-            break;
-        } else {
-            info!("{} media weren't enough.", est_media_count);
-            est_media_count += 2;
-        }
-    }
-
-    let medium_names = vec![
-        "Apple",
-        "Avocado",
-        "Banana",
-        "Blueberry",
-        "Cherry",
-        "Cranberry",
-    ];
-    let mut medium_name_iter = medium_names.iter();
-
-    let media: Vec<_> = sets.into_iter()
-        .map(|unit_set| {
-            Medium::new(
-                medium_name_iter
-                    .next()
-                    .expect("we ran out of names for media"),
-                medium_size,
-            ).unit_set(unit_set)
-        })
-        .collect();
+            /*
+             *Calculate the optimal block size.
+             */
+            let stats = Stats::new().files(&files)?;
+            BlockSize::new(stats, log).profile(&profile).block_size()
+        }
+    };
+    let hash_algorithm = hash::Algorithm::from_name(matches.value_of("HASH").unwrap())?;
+    let capacity = CapacityModel::new(&profile, block_size)
+        .hash_algorithm(hash_algorithm)
+        .redundancy_file_size(config.redundancy_file_size())
+        .catalog(
+            &Catalog::planned(generation, hash_algorithm, &unit_set, &changes)?
+                .with_config(&config),
+        )?;
 
-    // Insert redundancy media.
-    let mut media: Vec<_> = media
-        .into_iter()
-        .enumerate()
-        .flat_map(|(n, item)| {
-            if n & 1 == 1 {
-                vec![
-                    item,
-                    Medium::new(
-                        medium_name_iter
-                            .next()
-                            .expect("we ran out of names for media"),
-                        medium_size,
-                    ).redundancy(true),
-                ]
-            } else {
-                vec![item]
-            }
+    let planner = planner::planner(
+        matches.value_of("PLANNER").unwrap(),
+        matches
+            .value_of("LOCALITY-WEIGHT")
+            .unwrap()
+            .parse::<f64>()
+            .unwrap(),
+    )?;
+
+    /*
+     * The planners only see the lengths of the units.  Reserve room
+     * on each medium for the tables, the key and the sectors lost to
+     * rounding, growing the reserve until every medium, parity
+     * included, fits.
+     */
+    let mut reserve = 0;
+    let sets = loop {
+        let sets = planner.plan(unit_set.clone(), medium_size - reserve, log)?;
+        let overflow = capacity.overflow(&sets)?;
+        if overflow == 0 {
+            break sets;
+        }
+
+        reserve += overflow;
+        info!(
+            "a medium overflows by {} bytes, reserving {} bytes on each medium",
+            overflow,
+            reserve
+        );
+        if reserve >= medium_size {
+            bail!("the overhead of the backup leaves no room on the media");
+        }
+    };
+
+    if planner.name() != PathOrder.name() {
+        let baseline = PathOrder.plan(unit_set, medium_size - reserve, log)?;
+        info!(
+            "planner {} uses {} data media, {} uses {}: {} media saved",
+            planner.name(),
+            sets.len(),
+            PathOrder.name(),
+            baseline.len(),
+            baseline.len() as isize - sets.len() as isize
+        );
+    }
+
+    let mut namer = Namer::new(naming::scheme(config.naming())?);
+
+    // Insert redundancy media after every pair of data media, naming
+    // them all in the order they end up in.
+    let mut media = vec![];
+    for (n, unit_set) in sets.into_iter().enumerate() {
+        let group = n / 2;
+        media.push(Medium::new(&namer.next(group, Role::Data)?, &profile).unit_set(unit_set));
+        if n & 1 == 1 {
+            let name = namer.next(group, Role::Redundancy)?;
+            media.push(Medium::new(&name, &profile).redundancy(true));
+        }
+    }
+
+    media.iter().foreach(|medium| slog_info!(log, "{}", medium));
+    for (top, names) in planner::top_level_spans(&media)? {
+        info!("{:?} is on {} media: {}", top, names.len(), names.join(", "));
+    }
+
+    let fills = media
+        .chunks(3)
+        .map(|group| {
+            let left: Vec<_> = group[0].files().iter().collect();
+            let right: Vec<_> = group[1].files().iter().collect();
+            capacity.group(&left, &right)
         })
+        .collect::<Result<Vec<_>>>()?
+        .into_iter()
+        .flat_map(|fills| fills.to_vec())
         .collect();
 
-    media.iter().foreach(|medium| slog_info!(log, "{}", medium));
+    Ok(Plan {
+        sources,
+        mount_points,
+        generation,
+        unchanged: changes.unchanged,
+        deleted: changes.deleted,
+        profile,
+        block_size,
+        hash_algorithm,
+        planner: planner.name().into(),
+        config,
+        media,
+        fills,
+    })
+}
+
+// Where the work directory of a build is.
+#[derive(Clone, Copy, Debug)]
+enum WorkDir<'a> {
+    // A new one, wherever there is room.
+    New,
+    // A new one in the given directory.
+    In(&'a str),
+    // The one of an interrupted build, to carry on with it.
+    Resume(&'a str),
+}
+
+// How the files of every group are cut into blocks and hashed.
+#[derive(Clone, Copy, Debug)]
+struct Blocks {
+    size: u64,
+    hash_algorithm: hash::Algorithm,
+    // the largest size of a redundancy file
+    file_size: u64,
+}
+
+// How much of the machine building the redundancy may take: the
+// number of groups built at a time and the memory for their blocks.
+#[derive(Clone, Copy, Debug)]
+struct Resources {
+    jobs: usize,
+    memory: u64,
+}
+
+// The tables of a group of media, kept until the files are in place
+// in case any of them changes on the way.
+struct GroupTables {
+    media_table: MediaTable,
+    file_table: FileTable,
+    redun_table: RedundancyTable,
+    enckey: Box<[u8]>,
+}
+
+// Builds the media as planned and returns the directory the work
+// directory was made in, which outlasts it.
+fn build(
+    plan: Plan,
+    work_dir: WorkDir,
+    link_mode: LinkMode,
+    policy: ChangePolicy,
+    skipped: Option<&Skipped>,
+    resources: Resources,
+    log: &Logger,
+) -> Result<PathBuf> {
+    #[cfg_attr(feature = "debug", allow(unused_mut))]
+    let mut layout = lay_out(plan, work_dir, link_mode, policy, skipped, resources, log)?;
+    let work_dir = layout
+        .location()
+        .parent()
+        .map(|dir| dir.to_path_buf())
+        .unwrap_or_default();
+    #[cfg(not(feature = "debug"))]
+    layout.close()?;
+
+    Ok(work_dir)
+}
+
+// Reads the files, builds redundancy and index tables, and lays out
+// the media as planned.  Each group is recorded in the journal once it
+// is complete, and left as it is when the build is resumed.
+fn lay_out(
+    plan: Plan,
+    work_dir: WorkDir,
+    link_mode: LinkMode,
+    policy: ChangePolicy,
+    skipped: Option<&Skipped>,
+    resources: Resources,
+    log: &Logger,
+) -> Result<Layout> {
+    // The work directory is chosen to suit the first source; files from
+    // the others are copied if they cannot be linked.
+    let mut layout = Layout::new(&plan.sources[0].path, log)?
+        .link_mode(link_mode)
+        .keep_on_failure();
+    let mut journal = match work_dir {
+        WorkDir::Resume(dir) => {
+            layout.resume(dir)?;
+            // The media are laid out again from scratch.
+            let layout_dir = layout.dir(LAYOUT_SUBDIR).to_owned();
+            if layout_dir.exists() {
+                fs::remove_dir_all(&layout_dir)
+                    .chain_err(|| format!("error removing {:?}", layout_dir))?;
+            }
+            Journal::load(&layout.location())?
+        }
+        WorkDir::In(_) | WorkDir::New => {
+            if let WorkDir::In(dir) = work_dir {
+                layout.force_location(dir)?;
+            }
+            plan.save(layout.location().join(PLAN_FILE))?;
+            let journal = Journal::new(&layout.location());
+            journal.save()?;
+            journal
+        }
+    };
 
-    let block_size = {
-        /*
-         * I've got the list of file sizes here, and I can use the
-         * list to calculate the optimal block size.  This block size
-         * is fixed for all the media in this backup.
-         */
-        let files: Vec<_> = media.iter().flat_map(Medium::files).collect();
-
-        /*
-         *Calculate the optimal block size.
-         */
-        let stats = Stats::new().files(&files)?;
-        BlockSize::new(stats, log).block_size()
+    let Plan {
+        generation,
+        unchanged,
+        deleted,
+        profile,
+        block_size,
+        hash_algorithm,
+        config,
+        mut media,
+        ..
+    } = plan;
+    let capacity = CapacityModel::new(&profile, block_size).hash_algorithm(hash_algorithm);
+    let blocks = Blocks {
+        size: block_size,
+        hash_algorithm,
+        file_size: config.redundancy_file_size(),
     };
 
-    let mut layout = Layout::new(&start_path, log)?;
-    if let Some(work_dir) = work_dir {
-        layout.force_location(work_dir)?;
+    let compressed_dir = layout.dir(COMPRESSED_SUBDIR).ensure()?.to_owned();
+    for medium in &mut media {
+        compress_files(medium, &compressed_dir, hash_algorithm, policy, skipped)?;
     }
 
     for (group_id, group) in media.chunks_mut(3).enumerate() {
-        let mut media_table = MediaTable::new();
-        let enckey = generate_key()?;
         for medium in group.iter_mut() {
             medium.set_group_id(group_id);
-            let id = media_table.add(medium);
-            medium.set_id(id);
         }
+    }
+    let mut completed: Vec<Option<GroupTables>> = (0..media.len() / 3).map(|_| None).collect();
+    for group in journal.completed() {
+        info!("group {} was completed before", group.group_id);
+        let media = &mut media[group.group_id * 3..group.group_id * 3 + 3];
+        completed[group.group_id] = Some(resume_group(media, group, &mut layout)?);
+    }
+    let group_ids: Vec<_> = (0..completed.len())
+        .filter(|&group_id| completed[group_id].is_none())
+        .collect();
+    let built = build_groups(
+        &mut media,
+        &group_ids,
+        &mut layout,
+        &mut journal,
+        blocks,
+        policy,
+        skipped,
+        resources,
+    )?;
+    for (&group_id, group) in group_ids.iter().zip(built) {
+        completed[group_id] = Some(group);
+    }
+    let mut groups: Vec<_> = completed
+        .into_iter()
+        .map(|group| group.expect("group built"))
+        .collect();
 
-        let mut file_table = FileTable::new(group)?;
-        let mut redun_table = RedundancyTable::new();
+    info!("link files in appropriate locations");
+    for medium in &media {
+        link_files(medium, &mut layout)?;
+    }
 
-        info!(
-            "build redundancy for: {} and {}",
-            group[0].name,
-            group[1].name
-        );
-        let redun_dir = layout
-            .dir(REDUNDANCY_SUBDIR)
-            .dir(format!("{}", group_id))
-            .ensure()?
-            .to_owned();
-        let (media, redun) = group.split_at_mut(2);
-        let partial_indices = {
-            let mut redundancy = Redundancy::new(
-                block_size as usize,
-                &redun_dir,
-                &media[0],
-                &media[1],
-                &mut redun[0],
-                &file_table,
-            ).key(&enckey);
-            redundancy.build()?;
-            redundancy.partial_indices()
-        };
+    /*
+     * Files are checked once more as they are linked in place.  Those
+     * that changed since they were read are no longer what the tables
+     * say.
+     */
+    let mut attempt = 0;
+    loop {
+        info!("build layout");
+        let changed = layout.materialise()?;
+        if changed.is_empty() {
+            break;
+        }
 
-        info!("build redundancy index table");
-        for file in redun[0].files() {
-            let file_id = file_table.add(&redun[0], file)?;
-            let partial_indices = &partial_indices[&file.path.to_path_buf()];
-            for partial_index in partial_indices {
-                let index = match partial_index.kind {
-                    PartialIndexKind::Redundancy { left, right } => RedundancyIndex::Redundancy {
-                        left,
-                        right,
-                        redundancy: Block::new(
-                            file_id,
-                            partial_index.id,
-                            partial_index.len,
-                            &partial_index.hash,
-                        ),
-                    },
-                    PartialIndexKind::Replication { original } => RedundancyIndex::Replication {
-                        original,
-                        replication: Block::new(
-                            file_id,
-                            partial_index.id,
-                            partial_index.len,
-                            &partial_index.hash,
-                        ),
-                    },
-                };
-                redun_table.add(index);
+        match policy {
+            ChangePolicy::Fail => bail!(ErrorKind::FileChanged(changed[0].clone())),
+            ChangePolicy::Mark => {
+                for group in &mut groups {
+                    mark_inconsistent(&mut group.file_table, &changed)?;
+                }
+                break;
+            }
+            ChangePolicy::Retry => {
+                attempt += 1;
+                if attempt > CHANGE_RETRIES {
+                    bail!(ErrorKind::FileChanged(changed[0].clone()));
+                }
+                let mut refreshed = vec![];
+                for (group_id, group) in media.chunks_mut(3).enumerate() {
+                    let mut any = false;
+                    for medium in group.iter_mut() {
+                        any |= refresh_files(
+                            medium,
+                            &changed,
+                            &compressed_dir,
+                            hash_algorithm,
+                            policy,
+                            skipped,
+                        )?;
+                    }
+                    if any {
+                        warn!("files changed, rebuilding group {}", group_id);
+                        refreshed.push(group_id);
+                    }
+                }
+
+                let rebuilt = build_groups(
+                    &mut media,
+                    &refreshed,
+                    &mut layout,
+                    &mut journal,
+                    blocks,
+                    policy,
+                    skipped,
+                    resources,
+                )?;
+                for (&group_id, group) in refreshed.iter().zip(rebuilt) {
+                    groups[group_id] = group;
+                    for medium in &media[group_id * 3..group_id * 3 + 3] {
+                        let files_dir = layout
+                            .dir(LAYOUT_SUBDIR)
+                            .dir(&medium.name)
+                            .dir(FILES_SUBDIR)
+                            .to_owned();
+                        if files_dir.exists() {
+                            fs::remove_dir_all(&files_dir)
+                                .chain_err(|| format!("error removing {:?}", files_dir))?;
+                        }
+                        link_files(medium, &mut layout)?;
+                    }
+                }
             }
         }
+    }
 
-        info!("write index tables");
-        let index_dir = layout
-            .dir(INDEX_SUBDIR)
-            .dir(format!("{}", group_id))
-            .ensure()?
-            .to_owned();
-        let mut media_table_file = Verifile::new(index_dir.join("media-table").to_str().unwrap())?;
-        let mut write = media_table_file.write()?;
-        index::serialise(&mut write, &media_table)?;
-        write.close()?;
-        let mut file_table_file = Verifile::new(index_dir.join("file-table").to_str().unwrap())?;
-        let mut write = file_table_file.write()?;
-        index::serialise(&mut write, &file_table)?;
-        write.close()?;
-        let mut redun_table_file = Verifile::new(index_dir.join("redun-table").to_str().unwrap())?;
-        let mut write = redun_table_file.write()?;
-        index::serialise(&mut write, &redun_table)?;
-        write.close()?;
-
-        info!("write encryption key");
-        let enc_key_dir = layout
-            .dir(ENCRYPTION_KEY_SUBDIR)
-            .dir(format!("{}", group_id))
-            .ensure()?
-            .to_owned();
-        let mut enc_key_file = Verifile::new(enc_key_dir.join("encryption-key").to_str().unwrap())?;
-        let mut write = enc_key_file.write()?;
-        write
-            .write(&enckey)
-            .chain_err(|| format!("error writing to {:?}", write))?;
-        write.close()?;
+    /*
+     * Duplicates are never read, so they are only checked against their
+     * snapshots, and only once the files they duplicate are settled.
+     */
+    for (group, media) in groups.iter_mut().zip(media.chunks_mut(3)) {
+        for medium in media.iter_mut() {
+            let changed = medium.check_duplicates(policy)?;
+            mark_inconsistent(&mut group.file_table, &changed)?;
+        }
     }
 
-    info!("link files in appropriate locations");
+    for (group_id, group) in groups.iter().enumerate() {
+        let _ = write_tables(group_id, group, &mut layout)?;
+    }
+
+    info!("write the catalog of generation {}", generation);
+    let mut catalog = Catalog::new(generation).with_config(&config);
+    for group in &groups {
+        catalog.add_tables(&group.media_table, &group.file_table)?;
+    }
+    catalog.add_changes(&unchanged, &deleted);
+    let catalog_dir = layout.dir(CATALOG_SUBDIR).ensure()?.to_owned();
+    catalog.save(&catalog_dir.join(CATALOG_FILE))?;
+
+    info!("link index tables, encryption keys and the catalog");
     for medium in &media {
-        for file in medium.files() {
-            let logical = file.path.logical()?;
-            layout
-                .dir(LAYOUT_SUBDIR)
-                .dir(&medium.name)
-                .dir(FILES_SUBDIR)
-                .file(logical)?
-                .link(&file.path.canonical()?);
-        }
+        layout
+            .dir(LAYOUT_SUBDIR)
+            .dir(&medium.name)
+            .link_all(&catalog_dir)?;
 
         // link the index tables
         let index_dir = layout
@@ -357,28 +1448,516 @@ fn run(log: &Logger) -> Result<()> {
                 .link_all(&enc_key_dir)?;
         }
     }
+    let _ = layout.materialise()?;
 
-    info!("build layout");
-    layout.materialise()?;
-    #[cfg(not(feature = "debug"))]
-    layout.close()?;
+    for medium in &media {
+        let len = capacity.measure(&layout.dir(LAYOUT_SUBDIR).dir(&medium.name))?;
+        if len > capacity.capacity() {
+            bail!(ErrorKind::MediumOverflow(
+                medium.name.clone(),
+                len,
+                capacity.capacity()
+            ));
+        }
+        info!("Medium {} takes {} of {} bytes", medium.name, len, capacity.capacity());
+    }
 
-    info!("finished");
+    Ok(layout)
+}
+
+// Builds the redundancy and the tables of the groups with the given
+// ids, as many groups at a time as the resources allow.  A group is read
+// again if a file changes while it is read and the policy says to
+// retry, or without a file that cannot be read if that is tolerated.
+// The tables of each group are written, and the group recorded in the
+// journal, as soon as it is built.
+#[allow(clippy::too_many_arguments)]
+fn build_groups(
+    media: &mut [Medium],
+    group_ids: &[usize],
+    layout: &mut Layout,
+    journal: &mut Journal,
+    blocks: Blocks,
+    policy: ChangePolicy,
+    skipped: Option<&Skipped>,
+    resources: Resources,
+) -> Result<Vec<GroupTables>> {
+    let min_memory = redundancy::min_memory(blocks.size);
+    if resources.memory < min_memory {
+        warn!(
+            "building redundancy takes at least {} bytes of memory with blocks of {} bytes",
+            min_memory,
+            blocks.size
+        );
+    }
+    let jobs = cmp::max(cmp::min(resources.jobs as u64, resources.memory / min_memory), 1);
+    let memory_limit = cmp::max(resources.memory / jobs, min_memory);
+    let jobs = jobs as usize;
+    let compressed_dir = layout.dir(COMPRESSED_SUBDIR).to_owned();
+    let mut attempts = vec![0; group_ids.len()];
+    let mut built: Vec<Option<GroupTables>> = group_ids.iter().map(|_| None).collect();
+
+    loop {
+        let pending: Vec<_> = (0..group_ids.len())
+            .filter(|&index| built[index].is_none())
+            .collect();
+        if pending.is_empty() {
+            break;
+        }
+
+        let progress = Progress::new("redundancy");
+        let mut prepared = vec![];
+        let mut redundancies = vec![];
+        for &index in &pending {
+            let group = &mut media[group_ids[index] * 3..group_ids[index] * 3 + 3];
+            let (group_prep, redundancy) =
+                prepare_group(group, layout, blocks, memory_limit, policy, &progress)?;
+            prepared.push(Some(group_prep));
+            redundancies.push(redundancy);
+        }
+
+        info!(
+            "build redundancy for {} groups, {} at a time",
+            pending.len(),
+            cmp::min(jobs, pending.len())
+        );
+        pool::each(
+            redundancies,
+            jobs,
+            |mut redundancy: Redundancy| redundancy.build().map(|_| redundancy),
+            |position, result| {
+                let index = pending[position];
+                let group_id = group_ids[index];
+                let group = &mut media[group_id * 3..group_id * 3 + 3];
+                let group_prep = prepared[position].take().expect("group prepared");
+                let err = match result {
+                    Ok(redundancy) => {
+                        let tables = finish_group(group, group_prep, redundancy)?;
+                        let written = write_tables(group_id, &tables, layout)?;
+                        let redun_files: Vec<_> = group[2]
+                            .files()
+                            .iter()
+                            .map(|file| file.path.to_path_buf())
+                            .collect();
+                        journal.complete(group_id, &redun_files, &written)?;
+                        built[index] = Some(tables);
+                        return Ok(());
+                    }
+                    Err(err) => err,
+                };
+                let retry = match *err.kind() {
+                    ErrorKind::FileChanged(ref path)
+                        if policy == ChangePolicy::Retry && attempts[index] < CHANGE_RETRIES =>
+                    {
+                        attempts[index] += 1;
+                        warn!("{:?} changed while it was read, reading it again", path);
+                        for medium in group.iter_mut() {
+                            let _ = refresh_files(
+                                medium,
+                                &[path.clone()],
+                                &compressed_dir,
+                                blocks.hash_algorithm,
+                                policy,
+                                skipped,
+                            )?;
+                        }
+                        true
+                    }
+                    ErrorKind::UnreadableFile(ref path) if skipped.is_some() => {
+                        skipped.unwrap().record(path, &err);
+                        for medium in group.iter_mut() {
+                            let _ = medium.remove_file(path);
+                        }
+                        true
+                    }
+                    _ => false,
+                };
+                if retry {
+                    Ok(())
+                } else {
+                    Err(err)
+                }
+            },
+        )?;
+        progress.finish();
+    }
+
+    Ok(built.into_iter().map(|group| group.expect("group built")).collect())
+}
+
+// The tables of a group while its redundancy is built.
+struct PreparedGroup {
+    media_table: MediaTable,
+    file_table: FileTable,
+    enckey: Box<[u8]>,
+    redun_dir: PathBuf,
+}
+
+// Sets up the tables of a group, and the work on its redundancy to run
+// on a thread of its own.
+fn prepare_group(
+    group: &mut [Medium],
+    layout: &mut Layout,
+    blocks: Blocks,
+    memory_limit: u64,
+    policy: ChangePolicy,
+    progress: &Progress,
+) -> Result<(PreparedGroup, Redundancy)> {
+    let group_id = group[0].group_id();
+    let mut media_table = MediaTable::new();
+    let enckey = generate_key()?;
+    for medium in group.iter_mut() {
+        let id = media_table.add(medium);
+        medium.set_id(id);
+    }
+    group[2].clear_files();
+
+    let file_table = FileTable::new(group)?.with_hash_algorithm(blocks.hash_algorithm);
+
+    info!(
+        "build redundancy for: {} and {}",
+        group[0].name,
+        group[1].name
+    );
+    let redun_dir = layout
+        .dir(REDUNDANCY_SUBDIR)
+        .dir(format!("{}", group_id))
+        .to_owned();
+    if redun_dir.exists() {
+        fs::remove_dir_all(&redun_dir).chain_err(|| format!("error removing {:?}", redun_dir))?;
+    }
+    let redun_dir = layout
+        .dir(REDUNDANCY_SUBDIR)
+        .dir(format!("{}", group_id))
+        .ensure()?
+        .to_owned();
+    let redundancy = Redundancy::new(
+        blocks.size as usize,
+        &redun_dir,
+        &file_table,
+        group[0].id(),
+        group[1].id(),
+    ).key(&enckey)
+        .hash_algorithm(blocks.hash_algorithm)
+        .file_size(blocks.file_size)
+        .memory_limit(memory_limit)
+        .progress(progress)
+        .tolerate_changes(policy == ChangePolicy::Mark);
+
+    Ok((
+        PreparedGroup {
+            media_table,
+            file_table,
+            enckey,
+            redun_dir,
+        },
+        redundancy,
+    ))
+}
+
+// Puts the redundancy of a group on its redundancy medium and
+// completes its tables.
+fn finish_group(
+    group: &mut [Medium],
+    prepared: PreparedGroup,
+    mut redundancy: Redundancy,
+) -> Result<GroupTables> {
+    let PreparedGroup {
+        media_table,
+        mut file_table,
+        enckey,
+        redun_dir,
+    } = prepared;
+    let mut redun_table = RedundancyTable::new()
+        .with_hash_algorithm(file_table.hash_algorithm())
+        .with_block_size(redundancy.block_size() as u64);
+
+    for &(ref path, len) in redundancy.redun_files() {
+        group[2].push_file(unit::File::new(Path::with_prefix(&redun_dir).path(path), len));
+    }
+    let partial_indices = redundancy.partial_indices();
+
+    // The hashes of the files stored compressed are those of their
+    // compressed copies; those of the originals came with compressing.
+    for &(id, ref hash) in redundancy.file_hashes() {
+        if file_table.entries()[id].compression().is_none() {
+            file_table.set_hash(id, hash);
+        }
+    }
+    for &id in redundancy.changed_files() {
+        warn!(
+            "{:?} changed while it was read, marking it inconsistent",
+            file_table.entries()[id].actual_path()
+        );
+        file_table.mark_inconsistent(id);
+    }
+
+    info!("build redundancy index table");
+    for file in group[2].files() {
+        let file_id = file_table.add(&group[2], file)?;
+        let partial_indices = &partial_indices[&file.path.to_path_buf()];
+        for partial_index in partial_indices {
+            let index = match partial_index.kind {
+                PartialIndexKind::Redundancy {
+                    ref left,
+                    ref right,
+                } => RedundancyIndex::Redundancy {
+                    left: left.clone(),
+                    right: right.clone(),
+                    redundancy: Block::new(
+                        file_id,
+                        partial_index.id,
+                        partial_index.len,
+                        &partial_index.hash,
+                    ),
+                },
+                PartialIndexKind::Replication { ref original } => RedundancyIndex::Replication {
+                    original: original.clone(),
+                    replication: Block::new(
+                        file_id,
+                        partial_index.id,
+                        partial_index.len,
+                        &partial_index.hash,
+                    ),
+                },
+            };
+            redun_table.add(index);
+        }
+    }
+
+    Ok(GroupTables {
+        media_table,
+        file_table,
+        redun_table,
+        enckey,
+    })
+}
+
+// Marks the entries of the files among the given ones as
+// inconsistent.
+fn mark_inconsistent(file_table: &mut FileTable, changed: &[PathBuf]) -> Result<()> {
+    let ids: Vec<_> = file_table
+        .entries()
+        .iter()
+        .filter(|entry| {
+            entry
+                .actual_path()
+                .canonicalize()
+                .map(|path| changed.contains(&path))
+                .unwrap_or(false)
+        })
+        .map(|entry| entry.id())
+        .collect();
+    for id in ids {
+        warn!(
+            "{:?} changed while it was linked, marking it inconsistent",
+            file_table.entries()[id].actual_path()
+        );
+        file_table.mark_inconsistent(id);
+    }
+    Ok(())
+}
+
+// Compresses the files of a medium that are stored compressed.  A
+// file that cannot be read is left out if that is tolerated.
+fn compress_files(
+    medium: &mut Medium,
+    dir: &StdPath,
+    hash_algorithm: hash::Algorithm,
+    policy: ChangePolicy,
+    skipped: Option<&Skipped>,
+) -> Result<()> {
+    loop {
+        let result = medium.compress_files(dir, hash_algorithm, policy);
+        if let Err(ref err) = result {
+            if let ErrorKind::UnreadableFile(ref path) = *err.kind() {
+                if let Some(skipped) = skipped {
+                    skipped.record(path, err);
+                    let _ = medium.remove_file(path);
+                    continue;
+                }
+            }
+        }
+        return result;
+    }
+}
+
+// Takes new snapshots of the files of a medium among the given ones,
+// and compresses again those stored compressed.  Returns whether there
+// were any.
+fn refresh_files(
+    medium: &mut Medium,
+    changed: &[PathBuf],
+    dir: &StdPath,
+    hash_algorithm: hash::Algorithm,
+    policy: ChangePolicy,
+    skipped: Option<&Skipped>,
+) -> Result<bool> {
+    let refreshed = medium.refresh_files(changed)?;
+    if refreshed {
+        compress_files(medium, dir, hash_algorithm, policy, skipped)?;
+    }
+    Ok(refreshed)
+}
+
+fn link_files(medium: &Medium, layout: &mut Layout) -> Result<()> {
+    for file in medium.files() {
+        let logical = file.path.logical()?;
+        let files_dir = layout.dir(LAYOUT_SUBDIR).dir(&medium.name).dir(FILES_SUBDIR);
+        match file.compression {
+            Some(ref compression) => {
+                let mut name = logical.into_os_string();
+                name.push(".");
+                name.push(compression.algorithm.extension());
+                let stored = match compression.stored {
+                    Some(ref stored) => stored,
+                    None => bail!("{:?} has not been compressed", file.path),
+                };
+                files_dir.file(name)?.link(stored, None);
+            }
+            None => files_dir
+                .file(logical)?
+                .link(&file.path.canonical()?, file.snapshot),
+        }
+    }
     Ok(())
 }
 
+// Writes the index tables and the encryption key of a group, and
+// returns the paths written.
+fn write_tables(group_id: usize, group: &GroupTables, layout: &mut Layout) -> Result<Vec<PathBuf>> {
+    info!("write index tables");
+    let index_dir = layout
+        .dir(INDEX_SUBDIR)
+        .dir(format!("{}", group_id))
+        .ensure()?
+        .to_owned();
+    let mut media_table_file = Verifile::new(index_dir.join(MEDIA_TABLE_FILE).to_str().unwrap())?;
+    let mut write = media_table_file.write()?;
+    index::serialise(&mut write, &group.media_table)?;
+    write.close()?;
+    let mut file_table_file = Verifile::new(index_dir.join(FILE_TABLE_FILE).to_str().unwrap())?;
+    let mut write = file_table_file.write()?;
+    index::serialise(&mut write, &group.file_table)?;
+    write.close()?;
+    let mut redun_table_file = Verifile::new(index_dir.join(REDUN_TABLE_FILE).to_str().unwrap())?;
+    let mut write = redun_table_file.write()?;
+    index::serialise(&mut write, &group.redun_table)?;
+    write.close()?;
+
+    info!("write encryption key");
+    let enc_key_dir = layout
+        .dir(ENCRYPTION_KEY_SUBDIR)
+        .dir(format!("{}", group_id))
+        .ensure()?
+        .to_owned();
+    let mut enc_key_file = Verifile::new(enc_key_dir.join(ENCRYPTION_KEY_FILE).to_str().unwrap())?;
+    let mut write = enc_key_file.write()?;
+    write
+        .write(&group.enckey)
+        .chain_err(|| format!("error writing to {:?}", write))?;
+    write.close()?;
+    Ok(vec![
+        media_table_file.path().to_owned(),
+        file_table_file.path().to_owned(),
+        redun_table_file.path().to_owned(),
+        enc_key_file.path().to_owned(),
+    ])
+}
+
+// Takes up a group completed by an interrupted build, reading back its
+// tables and putting its redundancy files on its redundancy medium as
+// finish_group did.
+fn resume_group(
+    group: &mut [Medium],
+    completed: &CompletedGroup,
+    layout: &mut Layout,
+) -> Result<GroupTables> {
+    let mut media_table = MediaTable::new();
+    for medium in group.iter_mut() {
+        let id = media_table.add(medium);
+        medium.set_id(id);
+    }
+    group[2].clear_files();
+    let location = layout.location();
+    for file in &completed.redundancy {
+        let path = location.join(&file.path);
+        let redun_dir = path.parent().expect("redundancy file has no parent");
+        group[2].push_file(unit::File::new(Path::with_prefix(redun_dir).path(&path), file.len));
+    }
+
+    let index_dir = layout
+        .dir(INDEX_SUBDIR)
+        .dir(format!("{}", completed.group_id))
+        .to_owned();
+    let enc_key_path = layout
+        .dir(ENCRYPTION_KEY_SUBDIR)
+        .dir(format!("{}", completed.group_id))
+        .join(ENCRYPTION_KEY_FILE);
+    Ok(GroupTables {
+        media_table,
+        file_table: index::read(&index_dir.join(FILE_TABLE_FILE))?,
+        redun_table: index::read(&index_dir.join(REDUN_TABLE_FILE))?,
+        enckey: fs::read(&enc_key_path)
+            .chain_err(|| format!("error reading {:?}", enc_key_path))?
+            .into(),
+    })
+}
+
+// Returns the value of a global argument, given after the subcommand
+// or before it.
+fn global_value<'a>(matches: &'a ArgMatches, name: &str) -> &'a str {
+    match matches.subcommand() {
+        (_, Some(sub_matches)) if sub_matches.occurrences_of(name) > 0 => {
+            sub_matches.value_of(name).unwrap()
+        }
+        _ => matches.value_of(name).unwrap(),
+    }
+}
+
+fn log_level(name: &str) -> slog::Level {
+    match name {
+        "error" => slog::Level::Error,
+        "warning" => slog::Level::Warning,
+        "info" => slog::Level::Info,
+        "debug" => slog::Level::Debug,
+        _ => slog::Level::Trace,
+    }
+}
+
 fn main_log() -> i32 {
+    let matches = match app().get_matches_safe() {
+        Ok(matches) => matches,
+        Err(err) => {
+            if err.use_stderr() {
+                eprintln!("{}", err.message);
+                return 64;
+            }
+            println!("{}", err.message);
+            return 0;
+        }
+    };
+    let level = log_level(global_value(&matches, "LOG-LEVEL"));
+    let log_path = global_value(&matches, "LOG-FILE");
+    let log_path_json = global_value(&matches, "JSON-LOG-FILE");
+
     let log_file_json = OpenOptions::new()
         .create(true)
         .append(true)
-        .open(LOG_PATH_JSON)
-        .expect(&format!("failed to open log file {}", LOG_PATH_JSON));
+        .open(log_path_json)
+        .expect(&format!("failed to open log file {}", log_path_json));
     let log_file = OpenOptions::new()
         .create(true)
         .append(true)
-        .open(LOG_PATH)
-        .expect(&format!("failed to open log file {}", LOG_PATH));
+        .open(log_path)
+        .expect(&format!("failed to open log file {}", log_path));
 
+    // The terminal shows warnings and worse, or less if the files do.
+    let term_level = if level.is_at_least(slog::Level::Warning) {
+        level
+    } else {
+        slog::Level::Warning
+    };
     let term_decor = slog_term::TermDecorator::new().build();
     let file_drain = slog_json::Json::default(log_file_json);
     let term_drain = slog_term::CompactFormat::new(term_decor).build().fuse();
@@ -386,8 +1965,8 @@ fn main_log() -> i32 {
     let plain_drain = slog_term::CompactFormat::new(plain_decor).build().fuse();
     let drain = slog_async::Async::new(
         slog::Duplicate::new(
-            slog::LevelFilter::new(term_drain, slog::Level::Warning),
-            slog::Duplicate::new(file_drain, plain_drain),
+            slog::LevelFilter::new(term_drain, term_level),
+            slog::LevelFilter::new(slog::Duplicate::new(file_drain, plain_drain), level),
         ).fuse(),
     ).chan_size(1_000_000)
         .build()
@@ -396,11 +1975,16 @@ fn main_log() -> i32 {
     let log = Logger::root(drain, o!());
     let _scope_guard = slog_scope::set_global_logger(log.new(o!()));
 
-    match run(&log) {
+    match run(&matches, &log) {
         Ok(ret) => ExitCode::code(ret),
         Err(ref e) => {
             slog_error!(&log, "{}", ChainedError::display_chain(e));
-            1
+            match *e.kind() {
+                // The backup is complete apart from the skipped files.
+                ErrorKind::FilesSkipped(_) => 2,
+                ErrorKind::VerificationFailed(_) => 3,
+                _ => 1,
+            }
         }
     }
 }
@@ -408,3 +1992,302 @@ fn main_log() -> i32 {
 fn main() {
     ::std::process::exit(main_log());
 }
+
+#[cfg(test)]
+mod test {
+    use compress;
+    use consts::*;
+    use dedup;
+    use filter::Filter;
+    use hash;
+    use index::FileTable;
+    use layout::{Layout, LinkMode};
+    use medium::Medium;
+    use mounted::Mounted;
+    use path::Path;
+    use profile::MediumProfile;
+    use restore;
+    use slog::{Discard, Logger};
+    use snapshot::ChangePolicy;
+    use std::fs;
+    use std::path::Path as StdPath;
+    use std::slice;
+    use tempdir::TempDir;
+    use unitset::UnitSet;
+    use {app, compress_files, lay_out, link_files, make_plan, refresh_files, Resources, WorkDir};
+
+    fn contents(seed: u8, len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i as u8).wrapping_mul(31) ^ seed).collect()
+    }
+
+    // Lays out the media of a backup of source in a work directory in
+    // dir, with more arguments for planning it, and returns the layout
+    // and the names of the media.
+    fn back_up(source: &StdPath, dir: &StdPath, args: &[&str]) -> (Layout, Vec<String>) {
+        fs::create_dir(dir).expect("create_dir");
+        let log = Logger::root(Discard, o!());
+        let mut all_args = vec![
+            "ideas",
+            "build",
+            source.to_str().unwrap(),
+            "1",
+            "--small-file-threshold",
+            "0",
+        ];
+        all_args.extend_from_slice(args);
+        let matches = app().get_matches_from(all_args);
+        let matches = matches.subcommand_matches("build").unwrap();
+        let plan = make_plan(matches, None, &log).expect("make_plan");
+        let names = plan.media.iter().map(|medium| medium.name.clone()).collect();
+        let layout = lay_out(
+            plan,
+            WorkDir::In(dir.to_str().unwrap()),
+            LinkMode::Copy,
+            ChangePolicy::Fail,
+            None,
+            Resources {
+                jobs: 1,
+                memory: MEMORY_LIMIT,
+            },
+            &log,
+        ).expect("lay_out");
+        (layout, names)
+    }
+
+    // Builds media from two files too large to share a medium, then gets
+    // them back from the media, and from what is left when one data
+    // medium is lost.
+    #[test]
+    fn test_round_trip() {
+        let temp_dir = TempDir::new("test_round_trip").expect("TempDir::new");
+        let source = temp_dir.path().join("source");
+        let files = [("d1/x", contents(1, 600_000)), ("d2/y", contents(2, 500_000))];
+        for &(path, ref bytes) in &files {
+            let path = source.join(path);
+            fs::create_dir_all(path.parent().unwrap()).expect("create_dir_all");
+            fs::write(&path, bytes).expect("write");
+        }
+        let (mut layout, names) = back_up(&source, &temp_dir.path().join("work"), &[]);
+        assert_eq!(names.len(), 3);
+        let media_dir = layout.location().join(LAYOUT_SUBDIR);
+        let open = |name: &str| Mounted::open(media_dir.join(name)).expect("Mounted::open");
+        let check = |target: &StdPath, path: &str, bytes: &[u8]| {
+            assert!(fs::read(target.join(path)).expect("read") == bytes, "{}", path);
+        };
+
+        let restored = temp_dir.path().join("restored");
+        for name in &names {
+            let medium = open(name);
+            assert!(restore::verify(&medium).expect("verify").damaged.is_empty());
+            if !medium.is_redundancy() {
+                let outcome = restore::restore(&medium, &restored, &[]).expect("restore");
+                assert!(outcome.damaged.is_empty());
+            }
+        }
+        for &(path, ref bytes) in &files {
+            check(&restored, path, bytes);
+        }
+
+        let (data, redun) = (open(&names[1]), open(&names[2]));
+        assert_eq!(redun.redun_table().block_size(), redun.block_size().map(|size| size as u64));
+        assert!(redun.block_size().is_some());
+        let lost = open(&names[0]);
+        let lost_files: Vec<_> = lost
+            .stored_entries()
+            .iter()
+            .map(|entry| entry.path().to_owned())
+            .collect();
+        assert!(!lost_files.is_empty());
+        fs::remove_dir_all(media_dir.join(&names[0])).expect("remove_dir_all");
+
+        let recovered = temp_dir.path().join(&names[0]);
+        let outcome = restore::recover(&data, &redun, &recovered).expect("recover");
+        assert!(outcome.damaged.is_empty());
+        assert_eq!(outcome.files as usize, lost_files.len());
+        let medium = Mounted::open(&recovered).expect("Mounted::open");
+        assert!(restore::verify(&medium).expect("verify").damaged.is_empty());
+        for &(path, ref bytes) in &files {
+            if lost_files.iter().any(|lost| lost == path) {
+                check(&recovered.join(FILES_SUBDIR), path, bytes);
+            }
+        }
+        layout.close().expect("close");
+    }
+
+    // A file stored compressed that changes during a build is compressed
+    // again, so its tables and its place on the medium describe what it
+    // holds now.
+    #[test]
+    fn test_refresh_compressed() {
+        let temp_dir = TempDir::new("test_refresh_compressed").expect("TempDir::new");
+        let root = temp_dir.path().join("root");
+        let compressed_dir = temp_dir.path().join("compressed");
+        fs::create_dir(&root).expect("create_dir");
+        let path = root.join("log.txt");
+        fs::write(&path, "a line of a log file that repeats\n".repeat(10000)).expect("write");
+        let log = Logger::root(Discard, o!());
+
+        let filter = Filter::new(&root);
+        let mut unit_set = UnitSet::from_path(Path::with_prefix(&root).path(&root), &filter, &log)
+            .expect("UnitSet::from_path");
+        let _ = compress::plan(&mut unit_set, None, &log).expect("plan");
+        let profile = MediumProfile {
+            name: "test".into(),
+            description: Default::default(),
+            capacity: 1 << 30,
+            sector_size: 0x800,
+            block_size: None,
+        };
+        let mut medium = Medium::new("Apple", &profile);
+        medium.set_id(0);
+        for file in unit_set.0.remove(0).files.0 {
+            medium.push_file(file);
+        }
+        let (algorithm, policy) = (hash::Algorithm::Blake3, ChangePolicy::Retry);
+        compress_files(&mut medium, &compressed_dir, algorithm, policy, None).expect("compress");
+
+        let contents = "another line that repeats, longer than the first\n".repeat(20000);
+        fs::write(&path, &contents).expect("write");
+        let changed = vec![path.canonicalize().expect("canonicalize")];
+        assert!(
+            refresh_files(&mut medium, &changed, &compressed_dir, algorithm, policy, None)
+                .expect("refresh_files")
+        );
+
+        let file = &medium.files()[0];
+        let compression = file.compression.as_ref().expect("compression");
+        assert_eq!(compression.hash, Some(algorithm.digest(contents.as_bytes())));
+        let stored = compression.stored.as_ref().expect("stored");
+        assert_eq!(fs::metadata(stored).expect("metadata").len(), file.len);
+        assert_eq!(medium.len(), file.len);
+
+        let file_table = FileTable::new(slice::from_ref(&medium)).expect("FileTable::new");
+        let entry = &file_table.entries()[0];
+        assert_eq!(entry.size(), contents.len() as u64);
+        assert_eq!(entry.stored_size(), Some(file.len));
+        assert_eq!(entry.actual_path(), stored.as_path());
+
+        let mut layout = Layout::new(&root, &log).expect("Layout::new");
+        layout.force_location(temp_dir.path()).expect("force_location");
+        link_files(&medium, &mut layout).expect("link_files");
+        assert!(layout.materialise().expect("materialise").is_empty());
+        let linked = layout
+            .location()
+            .join(LAYOUT_SUBDIR)
+            .join("Apple")
+            .join(FILES_SUBDIR)
+            .join("log.txt.zst");
+        let mut restored = vec![];
+        compress::decompress(
+            compress::Algorithm::Zstd,
+            fs::File::open(linked).expect("open"),
+            &mut restored,
+        ).expect("decompress");
+        assert!(restored == contents.as_bytes());
+        layout.close().expect("close");
+    }
+
+    // A duplicate that changed is caught as the original would be, and
+    // is still taken for a duplicate if its contents are the same.
+    #[test]
+    fn test_check_duplicates() {
+        let temp_dir = TempDir::new("test_check_duplicates").expect("TempDir::new");
+        let root = temp_dir.path();
+        fs::write(root.join("a"), contents(1, 1000)).expect("write");
+        fs::write(root.join("b"), contents(1, 1000)).expect("write");
+        let log = Logger::root(Discard, o!());
+
+        let filter = Filter::new(root);
+        let mut unit_set = UnitSet::from_path(Path::with_prefix(root).path(root), &filter, &log)
+            .expect("UnitSet::from_path");
+        let _ = dedup::deduplicate(&mut unit_set, None, &log).expect("deduplicate");
+        let mut medium = Medium::new("Apple", &MediumProfile::file(1 << 20));
+        for file in unit_set.0.remove(0).files.0 {
+            medium.push_file(file);
+        }
+        assert_eq!(medium.files()[0].duplicates.len(), 1);
+        assert!(medium.check_duplicates(ChangePolicy::Fail).expect("check").is_empty());
+
+        // the same contents in a new file
+        let duplicate = root.join("b");
+        fs::remove_file(&duplicate).expect("remove_file");
+        fs::write(&duplicate, contents(1, 1000)).expect("write");
+        assert!(medium.check_duplicates(ChangePolicy::Fail).is_err());
+        assert!(medium.check_duplicates(ChangePolicy::Retry).expect("check").is_empty());
+        assert!(medium.check_duplicates(ChangePolicy::Fail).expect("check").is_empty());
+
+        fs::write(&duplicate, contents(2, 2000)).expect("write");
+        assert_eq!(
+            medium.check_duplicates(ChangePolicy::Mark).expect("check"),
+            vec![duplicate.canonicalize().expect("canonicalize")]
+        );
+        assert!(medium.check_duplicates(ChangePolicy::Retry).is_err());
+    }
+
+    // Each generation of an incremental backup is restored as it was,
+    // from its own media and those of the earlier generations.
+    #[test]
+    fn test_restore_generations() {
+        let temp_dir = TempDir::new("test_restore_generations").expect("TempDir::new");
+        let source = temp_dir.path().join("source");
+        let write = |path: &str, bytes: &[u8]| {
+            let path = source.join(path);
+            fs::create_dir_all(path.parent().unwrap()).expect("create_dir_all");
+            fs::write(&path, bytes).expect("write");
+        };
+        let first = [
+            ("d1/x", contents(1, 600_000)),
+            ("d2/y", contents(2, 300_000)),
+            ("d3/z", contents(3, 100_000)),
+        ];
+        for &(path, ref bytes) in &first {
+            write(path, bytes);
+        }
+        let (mut first_layout, first_names) = back_up(&source, &temp_dir.path().join("0"), &[]);
+        let first_dir = first_layout.location().join(LAYOUT_SUBDIR);
+
+        let second = [
+            ("d1/x", contents(1, 600_000)),
+            ("d2/y", contents(4, 200_000)),
+            ("d4/w", contents(5, 100_000)),
+        ];
+        for &(path, ref bytes) in &second {
+            write(path, bytes);
+        }
+        fs::remove_dir_all(source.join("d3")).expect("remove_dir_all");
+        let previous = first_dir.join(&first_names[0]);
+        let (mut second_layout, second_names) = back_up(
+            &source,
+            &temp_dir.path().join("1"),
+            &["--previous", previous.to_str().unwrap()],
+        );
+        let second_dir = second_layout.location().join(LAYOUT_SUBDIR);
+
+        let mut media = vec![];
+        for name in &second_names {
+            media.push(Mounted::open(second_dir.join(name)).expect("Mounted::open"));
+        }
+        // The unchanged file is only on the media of the first generation.
+        let target = temp_dir.path().join("without");
+        let err = restore::restore_generation(&media, 1, &target, &[]).unwrap_err();
+        assert!(err.to_string().contains("of generation 0"), "{}", err);
+        for name in &first_names {
+            media.push(Mounted::open(first_dir.join(name)).expect("Mounted::open"));
+        }
+
+        for (generation, files) in vec![first, second].into_iter().enumerate() {
+            let target = temp_dir.path().join(format!("restored-{}", generation));
+            let outcome = restore::restore_generation(&media, generation as u64, &target, &[])
+                .expect("restore_generation");
+            assert!(outcome.damaged.is_empty());
+            assert_eq!(outcome.files, 3);
+            for &(path, ref bytes) in &files {
+                assert!(fs::read(target.join(path)).expect("read") == *bytes, "{}", path);
+            }
+            let deleted = if generation == 0 { "d4" } else { "d3" };
+            assert!(!target.join(deleted).exists());
+        }
+        first_layout.close().expect("close");
+        second_layout.close().expect("close");
+    }
+}
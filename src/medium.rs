@@ -1,4 +1,13 @@
+use compress;
+use dedup;
+use errors::*;
+use hash;
+use path::Path;
+use profile::MediumProfile;
+use snapshot::{ChangePolicy, Snapshot};
 use std::fmt::{self, Display, Formatter};
+use std::path::Path as StdPath;
+use std::path::PathBuf;
 use unit::File;
 use unitset::UnitSet;
 
@@ -7,20 +16,24 @@ pub struct Medium {
     id: Option<usize>,
     group_id: Option<usize>,
     pub name: String,
+    profile: String,
     size: u64,
     len: u64,
+    units: Vec<Path>,
     files: Vec<File>,
     redundancy: bool,
 }
 
 impl Medium {
-    pub fn new(name: &str, size: u64) -> Self {
+    pub fn new(name: &str, profile: &MediumProfile) -> Self {
         Medium {
             id: None,
             group_id: None,
             name: name.into(),
-            size,
+            profile: profile.name.clone(),
+            size: profile.capacity,
             len: 0,
+            units: Default::default(),
             files: Default::default(),
             redundancy: false,
         }
@@ -33,10 +46,19 @@ impl Medium {
 
     pub fn unit_set(mut self, units: UnitSet) -> Self {
         self.len = units.len();
+        self.units = units.0.iter().map(|unit| unit.path.clone()).collect();
         self.files = units.into();
         self
     }
 
+    pub fn units(&self) -> &[Path] {
+        &self.units
+    }
+
+    pub fn push_unit(&mut self, unit: Path) {
+        self.units.push(unit);
+    }
+
     pub fn set_id(&mut self, id: usize) {
         self.id = Some(id);
     }
@@ -67,19 +89,105 @@ impl Medium {
     }
 
     pub fn push_file(&mut self, file: File) {
+        self.len += file.len;
         self.files.push(file);
     }
+
+    // Takes a file out of the medium.  Returns whether it was there.
+    pub fn remove_file(&mut self, path: &StdPath) -> bool {
+        match self.files.iter().position(|file| &*file.path == path) {
+            Some(index) => {
+                let file = self.files.remove(index);
+                self.len -= file.len;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn clear_files(&mut self) {
+        self.len = 0;
+        self.files.clear();
+    }
+
+    // Compresses the files stored compressed into the directory.
+    pub fn compress_files(
+        &mut self,
+        dir: &StdPath,
+        hash_algorithm: hash::Algorithm,
+        policy: ChangePolicy,
+    ) -> Result<()> {
+        for file in &mut self.files {
+            self.len -= file.len;
+            let result = compress::store(file, dir, hash_algorithm, policy);
+            self.len += file.len;
+            result?;
+        }
+        Ok(())
+    }
+
+    // Takes new snapshots of the files among the given ones.  Returns
+    // whether there were any.
+    pub fn refresh_files(&mut self, changed: &[PathBuf]) -> Result<bool> {
+        let changed = changed
+            .iter()
+            .filter_map(|path| path.canonicalize().ok())
+            .collect::<Vec<_>>();
+        let mut refreshed = false;
+        for file in &mut self.files {
+            if changed.contains(&file.path.canonical()?) {
+                self.len -= file.len;
+                file.refresh()?;
+                self.len += file.len;
+                refreshed = true;
+            }
+        }
+        Ok(refreshed)
+    }
+
+    // Checks the duplicates of the files against their snapshots, once
+    // the files themselves are settled.  A duplicate that changed fails
+    // the backup or is returned to be marked, as the policy says, or is
+    // compared with its file again.  If it no longer has the same
+    // contents, there is no room planned for it, and the backup fails.
+    pub fn check_duplicates(&mut self, policy: ChangePolicy) -> Result<Vec<PathBuf>> {
+        let mut changed = vec![];
+        for file in &mut self.files {
+            for duplicate in &mut file.duplicates {
+                if duplicate.is_unchanged()? {
+                    continue;
+                }
+                match policy {
+                    ChangePolicy::Fail => {
+                        bail!(ErrorKind::FileChanged(duplicate.path.to_path_buf()))
+                    }
+                    ChangePolicy::Mark => changed.push(duplicate.path.canonical()?),
+                    ChangePolicy::Retry => {
+                        let snapshot = Snapshot::take(&duplicate.path)?;
+                        if !dedup::same_contents(&file.path, &duplicate.path)?
+                            || Snapshot::take(&duplicate.path)? != snapshot
+                        {
+                            bail!(ErrorKind::FileChanged(duplicate.path.to_path_buf()));
+                        }
+                        duplicate.snapshot = Some(snapshot);
+                    }
+                }
+            }
+        }
+        Ok(changed)
+    }
 }
 
 impl Display for Medium {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         write!(
             f,
-            "{} files using {}/{} in Medium {} {}",
+            "{} files using {}/{} in Medium {} ({}) {}",
             self.files.len(),
             self.len,
             self.size,
             self.name,
+            self.profile,
             if self.is_redundancy() {
                 "(redundancy)"
             } else {
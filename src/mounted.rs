@@ -0,0 +1,154 @@
+use consts::*;
+use errors::*;
+use index::{self, FileEntry, FileTable, MediaTable, RedundancyIndex, RedundancyTable};
+use redundancy::Nonce;
+use std::collections::HashMap;
+use std::fs;
+use std::mem;
+use std::path::Path as StdPath;
+use std::path::PathBuf;
+
+// The media of a group are added to its tables in the order they are
+// planned in: two data media, then the one with their redundancy.
+const REDUNDANCY_MEDIUM_ID: usize = 2;
+
+// A medium of a backup where it is mounted, or wherever it was copied
+// to, with the tables of its group.
+#[derive(Debug)]
+pub struct Mounted {
+    root: PathBuf,
+    id: usize,
+    media_table: MediaTable,
+    file_table: FileTable,
+    redun_table: RedundancyTable,
+}
+
+impl Mounted {
+    // Reads the tables on the medium at root, verified.  The tables
+    // cover the whole group, so the medium is told apart from the
+    // others by the name of the directory, as the layout names it, or
+    // else by the files it holds.
+    pub fn open<P: AsRef<StdPath>>(root: P) -> Result<Self> {
+        let root = root.as_ref();
+        let media_table: MediaTable = index::read_verified(&root.join(MEDIA_TABLE_FILE))?;
+        let file_table: FileTable = index::read_verified(&root.join(FILE_TABLE_FILE))?;
+        let redun_table: RedundancyTable = index::read_verified(&root.join(REDUN_TABLE_FILE))?;
+
+        let mut mounted = Mounted {
+            root: root.into(),
+            id: 0,
+            media_table,
+            file_table,
+            redun_table,
+        };
+        let by_name = root.file_name().and_then(|name| name.to_str()).and_then(|name| {
+            mounted
+                .media_table
+                .entries()
+                .iter()
+                .find(|&&(_, ref medium)| medium == name)
+                .map(|&(id, _)| id)
+        });
+        let by_files = || {
+            mounted
+                .file_table
+                .entries()
+                .iter()
+                .find(|entry| entry.same_as().is_none() && mounted.stored_path(entry).is_file())
+                .map(|entry| entry.medium_id())
+        };
+        mounted.id = match by_name.or_else(by_files) {
+            Some(id) => id,
+            None => bail!("cannot tell which medium of its group {:?} is", root),
+        };
+        Ok(mounted)
+    }
+
+    pub fn root(&self) -> &StdPath {
+        &self.root
+    }
+
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    pub fn name(&self) -> &str {
+        self.media_table.name(self.id).unwrap_or("?")
+    }
+
+    pub fn is_redundancy(&self) -> bool {
+        self.id == REDUNDANCY_MEDIUM_ID
+    }
+
+    pub fn media_table(&self) -> &MediaTable {
+        &self.media_table
+    }
+
+    pub fn file_table(&self) -> &FileTable {
+        &self.file_table
+    }
+
+    pub fn redun_table(&self) -> &RedundancyTable {
+        &self.redun_table
+    }
+
+    // Returns the entries of the files stored on this medium, leaving
+    // out duplicates, which are stored only once.
+    pub fn stored_entries(&self) -> Vec<&FileEntry> {
+        self.file_table
+            .entries()
+            .iter()
+            .filter(|entry| entry.medium_id() == self.id && entry.same_as().is_none())
+            .collect()
+    }
+
+    // Returns where the contents of the file are on the medium, which
+    // for a duplicate is where the file it duplicates is.
+    pub fn stored_path(&self, entry: &FileEntry) -> PathBuf {
+        let entry = match entry.same_as() {
+            Some(id) => &self.file_table.entries()[id],
+            None => entry,
+        };
+        let mut path = self.root.join(FILES_SUBDIR).join(entry.path()).into_os_string();
+        if let Some(compression) = entry.compression() {
+            path.push(".");
+            path.push(compression.extension());
+        }
+        path.into()
+    }
+
+    // Returns the size of the blocks of the group, as its redundancy
+    // table records it.  Tables written before it was recorded give it
+    // away by the redundancy files with the most blocks, each of which
+    // holds a nonce and a block for each of their entries.  Groups with
+    // no redundancy then give no clue.
+    pub fn block_size(&self) -> Option<usize> {
+        if let Some(block_size) = self.redun_table.block_size() {
+            return Some(block_size as usize);
+        }
+        let mut blocks = HashMap::new();
+        for index in self.redun_table.entries() {
+            let block = match *index {
+                RedundancyIndex::Redundancy { ref redundancy, .. } => redundancy,
+                RedundancyIndex::Replication {
+                    ref replication, ..
+                } => replication,
+            };
+            *blocks.entry(block.file()).or_insert(0) += 1;
+        }
+        blocks
+            .iter()
+            .max_by_key(|&(&file, &count)| (count, file))
+            .map(|(&file, &count)| {
+                let len = self.file_table.entries()[file].size() as usize;
+                len / count - mem::size_of::<Nonce>()
+            })
+    }
+
+    // Reads the key the redundancy of the group is encrypted with,
+    // which only the data media hold.
+    pub fn enc_key(&self) -> Result<Vec<u8>> {
+        let path = self.root.join(ENCRYPTION_KEY_FILE);
+        fs::read(&path).chain_err(|| format!("error reading {:?}", path))
+    }
+}
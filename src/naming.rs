@@ -0,0 +1,245 @@
+use errors::*;
+use std::collections::HashSet;
+use std::fs;
+use std::io::Read;
+use time;
+
+// ISO 9660 volume labels hold at most 32 characters.
+pub const MAX_NAME_LEN: usize = 32;
+
+const FRUITS: &[&str] = &[
+    "Apple",
+    "Avocado",
+    "Banana",
+    "Blueberry",
+    "Cherry",
+    "Cranberry",
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Role {
+    Data,
+    Redundancy,
+}
+
+impl Role {
+    fn as_str(&self) -> &'static str {
+        match *self {
+            Role::Data => "data",
+            Role::Redundancy => "parity",
+        }
+    }
+}
+
+// Where a medium sits in the backup.  Both numbers count from 1, as
+// they are meant for labels.
+#[derive(Debug)]
+pub struct NameContext {
+    pub seq: usize,
+    pub group: usize,
+    pub role: Role,
+}
+
+pub trait NamingScheme {
+    fn name(&mut self, context: &NameContext) -> String;
+}
+
+// Numbers media after a prefix, as in ARCH-2026-001.
+struct Sequential {
+    prefix: String,
+}
+
+impl NamingScheme for Sequential {
+    fn name(&mut self, context: &NameContext) -> String {
+        format!("{}{:03}", self.prefix, context.seq)
+    }
+}
+
+// Takes names from a list of words, starting over with a number
+// appended once the list runs out.
+struct Words {
+    words: Vec<String>,
+}
+
+impl NamingScheme for Words {
+    fn name(&mut self, context: &NameContext) -> String {
+        let word = &self.words[(context.seq - 1) % self.words.len()];
+        let round = (context.seq - 1) / self.words.len();
+        if round == 0 {
+            word.clone()
+        } else {
+            format!("{}-{}", word, round + 1)
+        }
+    }
+}
+
+// Fills in the placeholders {seq}, {group}, {role} and {date}.
+struct Template {
+    template: String,
+    date: String,
+}
+
+impl NamingScheme for Template {
+    fn name(&mut self, context: &NameContext) -> String {
+        self.template
+            .replace("{seq}", &format!("{:03}", context.seq))
+            .replace("{group}", &format!("{}", context.group))
+            .replace("{role}", context.role.as_str())
+            .replace("{date}", &self.date)
+    }
+}
+
+// Makes a naming scheme from its description on the command line:
+//
+//   fruit              Apple, Avocado, ...
+//   seq:PREFIX         PREFIX001, PREFIX002, ...
+//   date[:PREFIX]      PREFIX20261018-001, ...
+//   words:FILE         one name per line in FILE
+//   template:TEMPLATE  such as ARCH-{date}-G{group}-{role}
+pub fn scheme(spec: &str) -> Result<Box<dyn NamingScheme>> {
+    let (kind, arg) = match spec.find(':') {
+        Some(colon) => (&spec[..colon], Some(&spec[colon + 1..])),
+        None => (spec, None),
+    };
+
+    match (kind, arg) {
+        ("fruit", None) => Ok(Box::new(Words {
+            words: FRUITS.iter().map(|&word| word.into()).collect(),
+        })),
+        ("seq", Some(prefix)) => Ok(Box::new(Sequential {
+            prefix: prefix.into(),
+        })),
+        ("date", prefix) => Ok(Box::new(Template {
+            template: format!("{}{{date}}-{{seq}}", prefix.unwrap_or("")),
+            date: today()?,
+        })),
+        ("words", Some(path)) => {
+            let mut contents = String::new();
+            fs::File::open(path)
+                .and_then(|mut file| file.read_to_string(&mut contents))
+                .chain_err(|| format!("error reading names from {:?}", path))?;
+            let words: Vec<String> = contents
+                .lines()
+                .map(str::trim)
+                .filter(|word| !word.is_empty())
+                .map(String::from)
+                .collect();
+            if words.is_empty() {
+                bail!("no names in {:?}", path);
+            }
+            Ok(Box::new(Words { words }))
+        }
+        ("template", Some(template)) => Ok(Box::new(Template {
+            template: template.into(),
+            date: today()?,
+        })),
+        _ => bail!("unknown naming scheme: {}", spec),
+    }
+}
+
+fn today() -> Result<String> {
+    time::strftime("%Y%m%d", &time::now()).chain_err(|| "error formatting the date")
+}
+
+// Hands out names from a scheme, making them safe for file names and
+// volume labels, and unique regardless of case.
+pub struct Namer {
+    scheme: Box<dyn NamingScheme>,
+    used: HashSet<String>,
+    seq: usize,
+}
+
+impl Namer {
+    pub fn new(scheme: Box<dyn NamingScheme>) -> Self {
+        Namer {
+            scheme,
+            used: Default::default(),
+            seq: 0,
+        }
+    }
+
+    pub fn next(&mut self, group: usize, role: Role) -> Result<String> {
+        self.seq += 1;
+        let raw = self.scheme.name(&NameContext {
+            seq: self.seq,
+            group: group + 1,
+            role,
+        });
+        let name = sanitise(&raw);
+        if name.is_empty() {
+            bail!("the naming scheme made an empty name out of {:?}", raw);
+        }
+
+        let mut candidate = truncate(&name, MAX_NAME_LEN);
+        let mut count = 1;
+        while self.used.contains(&candidate.to_uppercase()) {
+            count += 1;
+            let suffix = format!("-{}", count);
+            candidate = truncate(&name, MAX_NAME_LEN - suffix.len()) + &suffix;
+        }
+        let _ = self.used.insert(candidate.to_uppercase());
+        Ok(candidate)
+    }
+}
+
+// Keeps letters, digits, '-' and '_', and replaces anything else.
+fn sanitise(name: &str) -> String {
+    name.chars()
+        .map(|ch| {
+            if ch.is_ascii_alphanumeric() || ch == '-' || ch == '_' {
+                ch
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+fn truncate(name: &str, len: usize) -> String {
+    name.chars().take(len).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use naming::{scheme, Namer, Role};
+
+    #[test]
+    fn test_words_run_out() {
+        let mut namer = Namer::new(scheme("fruit").expect("scheme"));
+        let names: Vec<String> = (0..8)
+            .map(|n| namer.next(n / 3, Role::Data).expect("next"))
+            .collect();
+        assert_eq!(names[0], "Apple");
+        assert_eq!(names[5], "Cranberry");
+        assert_eq!(names[6], "Apple-2");
+        assert_eq!(names[7], "Avocado-2");
+    }
+
+    #[test]
+    fn test_sequential() {
+        let mut namer = Namer::new(scheme("seq:ARCH-2026-").expect("scheme"));
+        assert_eq!(namer.next(0, Role::Data).expect("next"), "ARCH-2026-001");
+        assert_eq!(namer.next(0, Role::Data).expect("next"), "ARCH-2026-002");
+    }
+
+    #[test]
+    fn test_template_is_safe_and_unique() {
+        let mut namer = Namer::new(scheme("template:Backup {group}/{role}").expect("scheme"));
+        assert_eq!(namer.next(0, Role::Data).expect("next"), "Backup_1_data");
+        assert_eq!(namer.next(0, Role::Data).expect("next"), "Backup_1_data-2");
+        assert_eq!(namer.next(0, Role::Redundancy).expect("next"), "Backup_1_parity");
+
+        let mut namer = Namer::new(scheme(&format!("seq:{}", "X".repeat(40))).expect("scheme"));
+        let first = namer.next(0, Role::Data).expect("next");
+        let second = namer.next(0, Role::Data).expect("next");
+        assert_eq!(first.len(), 32);
+        assert_eq!(second.len(), 32);
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn test_unknown_scheme() {
+        assert!(scheme("seq").is_err());
+        assert!(scheme("animals").is_err());
+    }
+}
@@ -1,12 +1,17 @@
 use errors::*;
 use std::ops::Deref;
+use std::path::Component;
 use std::path::Path as StdPath;
 use std::path::PathBuf;
 use std::rc::Rc;
 
+// A path under a source directory.  Its logical path, the one
+// recorded in the index tables, is relative to the source directory
+// and placed under the namespace of the source, if it has one.
 #[derive(Clone, Debug, Default)]
 pub struct Path {
     prefix: Rc<PathBuf>,
+    namespace: Rc<PathBuf>,
     path: PathBuf,
 }
 
@@ -14,9 +19,18 @@ impl Path {
     pub fn with_prefix<P>(prefix: P) -> Self
     where
         P: AsRef<StdPath>,
+    {
+        Self::with_namespace(prefix, "")
+    }
+
+    pub fn with_namespace<P, Q>(prefix: P, namespace: Q) -> Self
+    where
+        P: AsRef<StdPath>,
+        Q: AsRef<StdPath>,
     {
         Self {
             prefix: Rc::new(prefix.as_ref().into()),
+            namespace: Rc::new(namespace.as_ref().into()),
             path: Default::default(),
         }
     }
@@ -24,6 +38,7 @@ impl Path {
     pub fn with_template(template: &Path) -> Self {
         Path {
             prefix: Rc::clone(&template.prefix),
+            namespace: Rc::clone(&template.namespace),
             path: Default::default(),
         }
     }
@@ -37,7 +52,12 @@ impl Path {
     }
 
     pub fn logical(&self) -> Result<PathBuf> {
-        make_logical_path(&*self.prefix, &self.path)
+        let relative = make_logical_path(&*self.prefix, &self.path)?;
+        if relative.as_os_str().is_empty() {
+            Ok((*self.namespace).clone())
+        } else {
+            Ok(self.namespace.join(relative))
+        }
     }
 
     pub fn canonical(&self) -> Result<PathBuf> {
@@ -70,6 +90,15 @@ impl Into<PathBuf> for Path {
     }
 }
 
+// Tells whether a relative path stays below the directory it is
+// joined to, that is, whether it has only plain names in it.
+pub fn stays_below<P: AsRef<StdPath>>(path: P) -> bool {
+    path.as_ref().components().all(|component| match component {
+        Component::Normal(_) => true,
+        _ => false,
+    })
+}
+
 fn make_logical_path<P, Q>(prefix: P, path: Q) -> Result<PathBuf>
 where
     P: AsRef<StdPath>,
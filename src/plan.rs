@@ -0,0 +1,346 @@
+use catalog::CatalogEntry;
+use compress::{Algorithm, Compression};
+use config::Settings;
+use errors::*;
+use hash;
+use medium::Medium;
+use path::Path;
+use profile::MediumProfile;
+use serde_json;
+use snapshot::Snapshot;
+use source::Source;
+use std::fs;
+use std::path::Path as StdPath;
+use std::path::PathBuf;
+use unit::{Duplicate, File};
+
+const PLAN_IDENTIFIER: &str = "Backup Plan";
+
+// What a backup is going to look like: which files go on which
+// medium, and the block size and the hash algorithm for redundancy.
+// Media come in groups of three, two data media followed by their
+// redundancy medium.
+#[derive(Debug)]
+pub struct Plan {
+    pub sources: Vec<Source>,
+    // Mount points under the sources left out in one-file-system mode.
+    pub mount_points: Vec<PathBuf>,
+    // The generation of the backup, and for an incremental backup the
+    // files kept on the media of earlier generations and those gone.
+    pub generation: u64,
+    pub unchanged: Vec<CatalogEntry>,
+    pub deleted: Vec<String>,
+    pub profile: MediumProfile,
+    pub block_size: u64,
+    pub hash_algorithm: hash::Algorithm,
+    pub planner: String,
+    // the settings the plan was made with
+    pub config: Settings,
+    pub media: Vec<Medium>,
+    pub fills: Vec<u64>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct PlanFile {
+    identifier: String,
+    sources: Vec<Source>,
+    #[serde(default)]
+    mount_points: Vec<PathBuf>,
+    #[serde(default)]
+    generation: u64,
+    #[serde(default)]
+    unchanged: Vec<CatalogEntry>,
+    #[serde(default)]
+    deleted: Vec<String>,
+    profile: MediumProfile,
+    block_size: u64,
+    #[serde(default)]
+    hash_algorithm: hash::Algorithm,
+    planner: String,
+    #[serde(default)]
+    config: Settings,
+    media: Vec<PlannedMedium>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct PlannedMedium {
+    name: String,
+    group: usize,
+    redundancy: bool,
+    fill: u64,
+    units: Vec<String>,
+    files: Vec<PlannedFile>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct PlannedFile {
+    path: String,
+    size: u64,
+    #[serde(default)]
+    snapshot: Option<Snapshot>,
+    // logical paths of the files with the same contents, and their
+    // snapshots in the same order
+    #[serde(default)]
+    duplicates: Vec<String>,
+    #[serde(default)]
+    duplicate_snapshots: Vec<Option<Snapshot>>,
+    // how the file is compressed, with the size above being its
+    // compressed size
+    #[serde(default)]
+    compression: Option<Algorithm>,
+    #[serde(default)]
+    original_size: Option<u64>,
+}
+
+impl Plan {
+    pub fn save<P: AsRef<StdPath>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let mut media = vec![];
+
+        for (index, medium) in self.media.iter().enumerate() {
+            let units = medium
+                .units()
+                .iter()
+                .map(|unit| logical_str(unit))
+                .collect::<Result<Vec<_>>>()?;
+            let files = medium
+                .files()
+                .iter()
+                .map(|file| {
+                    Ok(PlannedFile {
+                        path: logical_str(&file.path)?,
+                        size: file.len,
+                        snapshot: file.snapshot,
+                        duplicates: file.duplicates
+                            .iter()
+                            .map(|duplicate| logical_str(&duplicate.path))
+                            .collect::<Result<_>>()?,
+                        duplicate_snapshots: file.duplicates
+                            .iter()
+                            .map(|duplicate| duplicate.snapshot)
+                            .collect(),
+                        compression: file.compression
+                            .as_ref()
+                            .map(|compression| compression.algorithm),
+                        original_size: file.compression
+                            .as_ref()
+                            .map(|compression| compression.original_len),
+                    })
+                })
+                .collect::<Result<Vec<_>>>()?;
+            media.push(PlannedMedium {
+                name: medium.name.clone(),
+                group: index / 3,
+                redundancy: medium.is_redundancy(),
+                fill: self.fills[index],
+                units,
+                files,
+            });
+        }
+
+        let plan_file = PlanFile {
+            identifier: PLAN_IDENTIFIER.into(),
+            sources: self.sources.clone(),
+            mount_points: self.mount_points.clone(),
+            generation: self.generation,
+            unchanged: self.unchanged.clone(),
+            deleted: self.deleted.clone(),
+            profile: self.profile.clone(),
+            block_size: self.block_size,
+            hash_algorithm: self.hash_algorithm,
+            planner: self.planner.clone(),
+            config: self.config.clone(),
+            media,
+        };
+        let file = fs::File::create(path).chain_err(|| format!("error creating {:?}", path))?;
+        serde_json::to_writer_pretty(file, &plan_file)
+            .chain_err(|| format!("error writing plan to {:?}", path))
+    }
+
+    pub fn load<P: AsRef<StdPath>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let file = fs::File::open(path).chain_err(|| format!("error opening {:?}", path))?;
+        let plan_file: PlanFile = serde_json::from_reader(file)
+            .chain_err(|| format!("error reading plan from {:?}", path))?;
+
+        if plan_file.identifier != PLAN_IDENTIFIER {
+            bail!("{:?} is not a backup plan", path);
+        }
+        if plan_file.media.len() % 3 != 0 {
+            bail!("the plan in {:?} has incomplete groups of media", path);
+        }
+
+        let sources = plan_file.sources;
+        if sources.is_empty() {
+            bail!("the plan in {:?} has no source directories", path);
+        }
+        let mut media = vec![];
+        let mut fills = vec![];
+        for (index, planned) in plan_file.media.into_iter().enumerate() {
+            if planned.group != index / 3 || planned.redundancy != (index % 3 == 2) {
+                bail!(
+                    "Medium {} is out of place in the plan in {:?}",
+                    planned.name,
+                    path
+                );
+            }
+
+            let mut medium =
+                Medium::new(&planned.name, &plan_file.profile).redundancy(planned.redundancy);
+            for unit in planned.units {
+                medium.push_unit(Source::resolve(&sources, unit)?);
+            }
+            for file in planned.files {
+                let mut planned_file = File::new(Source::resolve(&sources, file.path)?, file.size);
+                planned_file.snapshot = file.snapshot;
+                if let Some(algorithm) = file.compression {
+                    let original_len = file.original_size.unwrap_or(file.size);
+                    planned_file.compression = Some(Compression::new(algorithm, original_len));
+                }
+                for (index, duplicate) in file.duplicates.into_iter().enumerate() {
+                    let snapshot = file.duplicate_snapshots
+                        .get(index)
+                        .and_then(|&snapshot| snapshot);
+                    planned_file.duplicates.push(Duplicate {
+                        path: Source::resolve(&sources, duplicate)?,
+                        snapshot,
+                    });
+                }
+                medium.push_file(planned_file);
+            }
+            media.push(medium);
+            fills.push(planned.fill);
+        }
+
+        Ok(Plan {
+            sources,
+            mount_points: plan_file.mount_points,
+            generation: plan_file.generation,
+            unchanged: plan_file.unchanged,
+            deleted: plan_file.deleted,
+            profile: plan_file.profile,
+            block_size: plan_file.block_size,
+            hash_algorithm: plan_file.hash_algorithm,
+            planner: plan_file.planner,
+            config: plan_file.config,
+            media,
+            fills,
+        })
+    }
+}
+
+fn logical_str(path: &Path) -> Result<String> {
+    Ok(path.logical()?
+        .to_str()
+        .ok_or_else(|| ErrorKind::from("utf8 error"))?
+        .into())
+}
+
+#[cfg(test)]
+mod test {
+    use config::Settings;
+    use hash;
+    use medium::Medium;
+    use path::Path;
+    use plan::Plan;
+    use profile::MediumProfile;
+    use source::Source;
+    use tempdir::TempDir;
+    use unit::File;
+
+    #[test]
+    fn test_save_load() {
+        let temp_dir = TempDir::new("test_plan").expect("TempDir::new");
+        let profile = MediumProfile::file(1024 * 1024);
+        let source = Path::with_prefix("/src");
+
+        let mut media = vec![];
+        for (name, redundancy) in &[("a", false), ("b", false), ("c", true)] {
+            let mut medium = Medium::new(name, &profile).redundancy(*redundancy);
+            if !redundancy {
+                medium.push_unit(Path::with_template(&source).path("/src/dir"));
+                medium.push_file(File::new(
+                    Path::with_template(&source).path(format!("/src/dir/{}", name)),
+                    100,
+                ));
+            }
+            media.push(medium);
+        }
+        let plan = Plan {
+            sources: vec![Source {
+                path: "/src".into(),
+                namespace: "".into(),
+            }],
+            mount_points: vec!["/src/mnt".into()],
+            generation: 1,
+            unchanged: vec![],
+            deleted: vec!["dir/gone".into()],
+            profile,
+            block_size: 0x1000,
+            hash_algorithm: hash::Algorithm::Blake3,
+            planner: "pack".into(),
+            config: Settings {
+                block_size: Some(0x1000),
+                ..Settings::default()
+            },
+            media,
+            fills: vec![1, 2, 3],
+        };
+
+        let path = temp_dir.path().join("plan.json");
+        plan.save(&path).expect("save");
+        let loaded = Plan::load(&path).expect("load");
+
+        assert_eq!(loaded.block_size, 0x1000);
+        assert_eq!(loaded.hash_algorithm, hash::Algorithm::Blake3);
+        assert_eq!(loaded.mount_points, plan.mount_points);
+        assert_eq!(loaded.generation, 1);
+        assert_eq!(loaded.deleted, plan.deleted);
+        assert_eq!(loaded.fills, vec![1, 2, 3]);
+        assert_eq!(loaded.config, plan.config);
+        assert_eq!(loaded.media.len(), 3);
+        assert!(loaded.media[2].is_redundancy());
+        let file = &loaded.media[1].files()[0];
+        assert_eq!(file.len, 100);
+        assert_eq!(file.path.logical().expect("logical").to_str(), Some("dir/b"));
+        assert_eq!(
+            loaded.media[0].units()[0].logical().expect("logical").to_str(),
+            Some("dir")
+        );
+    }
+
+    #[test]
+    fn test_load_escaping() {
+        let temp_dir = TempDir::new("test_plan").expect("TempDir::new");
+        let profile = MediumProfile::file(1024 * 1024);
+        let source = Path::with_prefix("/src");
+        let mut medium = Medium::new("a", &profile);
+        medium.push_file(File::new(
+            Path::with_template(&source).path("/src/../x"),
+            100,
+        ));
+        let plan = Plan {
+            sources: vec![Source {
+                path: "/src".into(),
+                namespace: "".into(),
+            }],
+            mount_points: vec![],
+            generation: 1,
+            unchanged: vec![],
+            deleted: vec![],
+            profile,
+            block_size: 0x1000,
+            hash_algorithm: hash::Algorithm::Blake3,
+            planner: "pack".into(),
+            config: Settings::default(),
+            media: vec![medium],
+            fills: vec![100],
+        };
+
+        let path = temp_dir.path().join("plan.json");
+        plan.save(&path).expect("save");
+        let json = ::std::fs::read_to_string(&path).expect("read_to_string");
+        assert!(json.contains("../x"));
+        assert!(Plan::load(&path).is_err());
+    }
+}
@@ -0,0 +1,560 @@
+use autofill::AutoFill;
+use disperse::Disperse;
+use errors::*;
+use itertools::Itertools;
+use medium::Medium;
+use slog::Logger;
+use std::cmp;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::mem;
+use std::path::PathBuf;
+use unitset::UnitSet;
+
+// Exact search is only attempted for up to this many units.
+const EXACT_UNIT_LIMIT: usize = 12;
+
+// Decides which units go on which data medium.  Every planner returns
+// an even number of media because media are grouped in pairs for
+// redundancy.
+pub trait Planner {
+    fn name(&self) -> &'static str;
+    fn plan(&self, units: UnitSet, medium_size: u64, log: &Logger) -> Result<Vec<UnitSet>>;
+}
+
+pub fn planner(name: &str, locality_weight: f64) -> Result<Box<dyn Planner>> {
+    match name {
+        "path-order" => Ok(Box::new(PathOrder)),
+        "pack" => Ok(Box::new(Packing)),
+        "locality" => Ok(Box::new(Locality::new(locality_weight))),
+        _ => bail!("unknown planner: {}", name),
+    }
+}
+
+// Keeps the units in path order and shifts units between adjacent
+// media until the used space is evenly dispersed, adding two media at
+// a time until everything fits.
+pub struct PathOrder;
+
+impl Planner for PathOrder {
+    fn name(&self) -> &'static str {
+        "path-order"
+    }
+
+    fn plan(&self, unit_set: UnitSet, medium_size: u64, log: &Logger) -> Result<Vec<UnitSet>> {
+        let mut est_media_count = ((unit_set.len() + (medium_size - 1)) / medium_size) as usize;
+        slog_info!(log, "estimated media count: {}", est_media_count);
+        if est_media_count & 0x1 == 0x1 {
+            // make even number
+            est_media_count += 1;
+        }
+        est_media_count = cmp::max(est_media_count, 2);
+
+        let mut sets = Vec::with_capacity(est_media_count);
+        sets.push(unit_set);
+        loop {
+            disperse_over(est_media_count, &mut sets, log)?;
+            sets.iter().for_each(|set| slog_info!(log, "{}", set));
+
+            if sets.iter().all(|set| set.len() <= medium_size) {
+                break;
+            } else {
+                slog_info!(log, "{} media weren't enough.", est_media_count);
+                est_media_count += 2;
+            }
+        }
+
+        Ok(sets)
+    }
+}
+
+fn disperse_over(est_media_count: usize, sets: &mut Vec<UnitSet>, log: &Logger) -> Result<()> {
+    assert!(sets.len() <= est_media_count);
+    while sets.len() < est_media_count {
+        sets.push(Default::default());
+    }
+
+    let mut disperse = Disperse::new(sets, 5., log);
+    disperse.disperse();
+
+    let autofill = AutoFill::new(&format!(
+        concat!(
+            "After dispersing, standard deviation of the used space is {:.2}M.",
+            "  This is {:.2} percents of the mean size of {:.2}MB."
+        ),
+        disperse.measure() / 1024. / 1024.,
+        disperse.measure() * 100.0 / disperse.mean(),
+        disperse.mean() / 1024. / 1024.
+    ));
+    autofill.foreach(|line| slog_info!(log, "{}", line));
+
+    Ok(())
+}
+
+// Packs the units with first-fit-decreasing, followed by a local
+// search that tries to empty the least used medium and then evens out
+// the used space.  Small inputs are solved exactly instead.
+pub struct Packing;
+
+impl Planner for Packing {
+    fn name(&self) -> &'static str {
+        "pack"
+    }
+
+    fn plan(&self, unit_set: UnitSet, medium_size: u64, log: &Logger) -> Result<Vec<UnitSet>> {
+        let lens: Vec<u64> = unit_set.0.iter().map(|unit| unit.len).collect();
+        let mut bins = pack(&lens, medium_size, log)?;
+        make_even(&mut bins);
+        balance(&mut bins, &lens);
+
+        let sets = distribute(unit_set, &bins);
+        sets.iter().for_each(|set| slog_info!(log, "{}", set));
+        Ok(sets)
+    }
+}
+
+// Places directory trees together, using the parent of each unit to
+// find its ancestors.  The weight trades filling media evenly (0)
+// against keeping trees on as few media as possible (1).  It starts
+// with as many media as packing needs, and adds more only when a unit
+// fits nowhere.
+pub struct Locality {
+    weight: f64,
+}
+
+impl Locality {
+    pub fn new(weight: f64) -> Self {
+        assert!(weight >= 0. && weight <= 1.);
+        Locality { weight }
+    }
+}
+
+impl Planner for Locality {
+    fn name(&self) -> &'static str {
+        "locality"
+    }
+
+    fn plan(&self, unit_set: UnitSet, medium_size: u64, log: &Logger) -> Result<Vec<UnitSet>> {
+        let lens: Vec<u64> = unit_set.0.iter().map(|unit| unit.len).collect();
+        let mut count = pack(&lens, medium_size, log)?.len();
+        if count & 0x1 == 0x1 {
+            count += 1;
+        }
+
+        // The ancestors of each unit, nearest first, excluding the
+        // root which every unit shares.
+        let ancestors: Vec<Vec<usize>> = (0..unit_set.0.len())
+            .map(|index| {
+                let mut chain = vec![];
+                let mut finger = index;
+                while finger != 0 {
+                    chain.push(finger);
+                    finger = unit_set.0[finger].parent;
+                }
+                chain
+            })
+            .collect();
+
+        // Place the largest trees first, each tree in path order.
+        let mut trees: HashMap<usize, Vec<usize>> = HashMap::new();
+        for (index, chain) in ancestors.iter().enumerate() {
+            trees
+                .entry(chain.last().cloned().unwrap_or(0))
+                .or_insert_with(Vec::new)
+                .push(index);
+        }
+        let mut trees: Vec<Vec<usize>> = trees.into_iter().map(|(_, tree)| tree).collect();
+        trees.sort_by_key(|tree| {
+            (
+                cmp::Reverse(tree.iter().map(|&index| lens[index]).sum::<u64>()),
+                tree[0],
+            )
+        });
+
+        let mut bins = vec![Bin::default(); count];
+        let mut members: Vec<HashSet<usize>> = vec![HashSet::new(); count];
+        for index in trees.into_iter().flat_map(|tree| tree) {
+            let chain = &ancestors[index];
+            let score = |bin: usize| {
+                let shared = chain
+                    .iter()
+                    .position(|ancestor| members[bin].contains(ancestor))
+                    .map(|position| (chain.len() - position) as f64 / chain.len() as f64)
+                    .unwrap_or(0.);
+                let free = 1. - bins[bin].load as f64 / medium_size as f64;
+                self.weight * shared + (1. - self.weight) * free
+            };
+            let best = (0..bins.len())
+                .filter(|&bin| bins[bin].load + lens[index] <= medium_size)
+                .map(|bin| (bin, score(bin)))
+                .fold(None, |best: Option<(usize, f64)>, (bin, score)| match best {
+                    Some((_, best_score)) if best_score >= score => best,
+                    _ => Some((bin, score)),
+                })
+                .map(|(bin, _)| bin);
+
+            let bin = match best {
+                Some(bin) => bin,
+                None => {
+                    slog_info!(log, "unit {} fits nowhere, adding a medium", index);
+                    bins.push(Bin::default());
+                    members.push(HashSet::new());
+                    bins.len() - 1
+                }
+            };
+            bins[bin].add(index, &lens);
+            members[bin].extend(chain.iter().cloned());
+        }
+        make_even(&mut bins);
+
+        let sets = distribute(unit_set, &bins);
+        sets.iter().for_each(|set| slog_info!(log, "{}", set));
+        Ok(sets)
+    }
+}
+
+fn pack(lens: &[u64], medium_size: u64, log: &Logger) -> Result<Vec<Bin>> {
+    if let Some(len) = lens.iter().find(|&&len| len > medium_size) {
+        bail!(
+            "unit of {} bytes does not fit in a medium of {} bytes",
+            len,
+            medium_size
+        );
+    }
+
+    let mut bins = first_fit_decreasing(lens, medium_size);
+    slog_info!(log, "first-fit-decreasing uses {} media", bins.len());
+    eliminate_bins(&mut bins, lens, medium_size);
+    slog_info!(log, "local search reduced it to {} media", bins.len());
+
+    if lens.len() <= EXACT_UNIT_LIMIT {
+        if let Some(exact) = exact(lens, medium_size, bins.len()) {
+            slog_info!(log, "exact search found {} media", exact.len());
+            bins = exact;
+        }
+    }
+
+    Ok(bins)
+}
+
+fn make_even(bins: &mut Vec<Bin>) {
+    while bins.len() < 2 || bins.len() & 0x1 == 0x1 {
+        bins.push(Bin::default());
+    }
+}
+
+// Lists the data media each top-level directory landed on.  Files
+// directly in the source directory are listed under ".".
+pub fn top_level_spans(media: &[Medium]) -> Result<BTreeMap<PathBuf, Vec<String>>> {
+    let mut spans: BTreeMap<PathBuf, Vec<String>> = BTreeMap::new();
+
+    for medium in media.iter().filter(|medium| !medium.is_redundancy()) {
+        for file in medium.files() {
+            let logical = file.path.logical()?;
+            let mut components = logical.components();
+            let top = match (components.next(), components.next()) {
+                (Some(top), Some(_)) => PathBuf::from(top.as_os_str()),
+                _ => PathBuf::from("."),
+            };
+            let names = spans.entry(top).or_insert_with(Vec::new);
+            if !names.contains(&medium.name) {
+                names.push(medium.name.clone());
+            }
+        }
+    }
+
+    Ok(spans)
+}
+
+#[derive(Clone, Debug, Default)]
+struct Bin {
+    load: u64,
+    units: Vec<usize>,
+}
+
+impl Bin {
+    fn add(&mut self, unit: usize, lens: &[u64]) {
+        self.load += lens[unit];
+        self.units.push(unit);
+    }
+
+    fn remove(&mut self, position: usize, lens: &[u64]) -> usize {
+        let unit = self.units.swap_remove(position);
+        self.load -= lens[unit];
+        unit
+    }
+}
+
+fn decreasing(lens: &[u64]) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..lens.len()).collect();
+    order.sort_by(|&a, &b| lens[b].cmp(&lens[a]));
+    order
+}
+
+fn first_fit_decreasing(lens: &[u64], capacity: u64) -> Vec<Bin> {
+    let mut bins: Vec<Bin> = vec![];
+
+    for unit in decreasing(lens) {
+        let len = lens[unit];
+        if let Some(bin) = bins.iter_mut().find(|bin| bin.load + len <= capacity) {
+            bin.add(unit, lens);
+            continue;
+        }
+        let mut bin = Bin::default();
+        bin.add(unit, lens);
+        bins.push(bin);
+    }
+
+    bins
+}
+
+// Repeatedly tries to move every unit of the least used bin into the
+// other bins, swapping a smaller unit out of the way where a unit
+// does not fit as is.
+fn eliminate_bins(bins: &mut Vec<Bin>, lens: &[u64], capacity: u64) {
+    while bins.len() > 1 {
+        let emptiest = (0..bins.len())
+            .min_by_key(|&i| bins[i].load)
+            .expect("unexpectedly no bins");
+        let mut trial = bins.clone();
+        let mut homeless = trial.remove(emptiest).units;
+        homeless.sort_by(|&a, &b| lens[b].cmp(&lens[a]));
+
+        if homeless.into_iter().all(|unit| reinsert(&mut trial, unit, lens, capacity)) {
+            *bins = trial;
+        } else {
+            break;
+        }
+    }
+}
+
+fn reinsert(bins: &mut [Bin], unit: usize, lens: &[u64], capacity: u64) -> bool {
+    let len = lens[unit];
+
+    // best fit
+    if let Some(i) = (0..bins.len())
+        .filter(|&i| bins[i].load + len <= capacity)
+        .min_by_key(|&i| capacity - bins[i].load - len)
+    {
+        bins[i].add(unit, lens);
+        return true;
+    }
+
+    // swap out a smaller unit that fits in yet another bin
+    for i in 0..bins.len() {
+        for position in 0..bins[i].units.len() {
+            let other = bins[i].units[position];
+            if lens[other] >= len || bins[i].load - lens[other] + len > capacity {
+                continue;
+            }
+            if let Some(j) = (0..bins.len())
+                .filter(|&j| j != i && bins[j].load + lens[other] <= capacity)
+                .min_by_key(|&j| capacity - bins[j].load - lens[other])
+            {
+                let other = bins[i].remove(position, lens);
+                bins[i].add(unit, lens);
+                bins[j].add(other, lens);
+                return true;
+            }
+        }
+    }
+
+    false
+}
+
+// Moves units from the most used bin to the least used one as long as
+// that narrows the gap between the two.
+fn balance(bins: &mut [Bin], lens: &[u64]) {
+    loop {
+        let fullest = (0..bins.len())
+            .max_by_key(|&i| bins[i].load)
+            .expect("unexpectedly no bins");
+        let emptiest = (0..bins.len())
+            .min_by_key(|&i| bins[i].load)
+            .expect("unexpectedly no bins");
+        let gap = bins[fullest].load - bins[emptiest].load;
+
+        // The best unit to move is the one closest to half the gap.
+        // Moving a unit of length len narrows the gap if len < gap.
+        let candidate = (0..bins[fullest].units.len())
+            .filter(|&position| lens[bins[fullest].units[position]] < gap)
+            .filter(|&position| lens[bins[fullest].units[position]] > 0)
+            .min_by_key(|&position| {
+                let len = lens[bins[fullest].units[position]];
+                cmp::max(len * 2, gap) - cmp::min(len * 2, gap)
+            });
+
+        if let Some(position) = candidate {
+            let unit = bins[fullest].remove(position, lens);
+            bins[emptiest].add(unit, lens);
+        } else {
+            break;
+        }
+    }
+}
+
+// Finds a packing into fewer than `upper` bins if there is one, trying
+// the smallest number of bins first.
+fn exact(lens: &[u64], capacity: u64, upper: usize) -> Option<Vec<Bin>> {
+    let sum: u64 = lens.iter().sum();
+    let lower = cmp::max(((sum + capacity - 1) / capacity) as usize, 1);
+    let order = decreasing(lens);
+
+    for count in lower..upper {
+        let mut bins = vec![Bin::default(); count];
+        if place(0, &order, lens, capacity, &mut bins) {
+            return Some(bins);
+        }
+    }
+    None
+}
+
+fn place(next: usize, order: &[usize], lens: &[u64], capacity: u64, bins: &mut [Bin]) -> bool {
+    if next == order.len() {
+        return true;
+    }
+
+    let unit = order[next];
+    for i in 0..bins.len() {
+        // Bins with the same load are interchangeable.
+        if bins[i].load + lens[unit] > capacity
+            || (0..i).any(|j| bins[j].load == bins[i].load)
+        {
+            continue;
+        }
+
+        bins[i].add(unit, lens);
+        if place(next + 1, order, lens, capacity, bins) {
+            return true;
+        }
+        let position = bins[i].units.len() - 1;
+        let _ = bins[i].remove(position, lens);
+    }
+    false
+}
+
+// Hands out the units to media according to the bins, keeping each
+// medium in path order.
+fn distribute(unit_set: UnitSet, bins: &[Bin]) -> Vec<UnitSet> {
+    let mut units: Vec<_> = unit_set.0.into_iter().map(Some).collect();
+
+    bins.iter()
+        .map(|bin| {
+            let mut indices = bin.units.clone();
+            indices.sort();
+            let mut set = UnitSet::default();
+            for index in indices {
+                set.push(mem::replace(&mut units[index], None).expect("unit placed twice"));
+            }
+            set
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use path::Path;
+    use planner::{balance, eliminate_bins, exact, first_fit_decreasing, Locality, Planner};
+    use slog::{self, Logger};
+    use unit::{Files, Unit};
+    use unitset::UnitSet;
+
+    fn loads_fit(bins: &[super::Bin], capacity: u64) -> bool {
+        bins.iter().all(|bin| bin.load <= capacity)
+    }
+
+    #[test]
+    fn test_first_fit_decreasing() {
+        let lens = [5, 7, 5, 2, 4, 2, 5];
+        let bins = first_fit_decreasing(&lens, 10);
+        assert_eq!(bins.len(), 4);
+        assert!(loads_fit(&bins, 10));
+        assert_eq!(bins.iter().map(|bin| bin.units.len()).sum::<usize>(), 7);
+    }
+
+    #[test]
+    fn test_exact_beats_ffd() {
+        // FFD needs 3 bins here, but {5, 3, 2}, {4, 4, 2} is a packing
+        // into 2.
+        let lens = [5, 4, 4, 3, 2, 2];
+        let mut bins = first_fit_decreasing(&lens, 10);
+        assert_eq!(bins.len(), 3);
+        eliminate_bins(&mut bins, &lens, 10);
+        let bins = exact(&lens, 10, bins.len()).expect("exact");
+        assert_eq!(bins.len(), 2);
+        assert!(loads_fit(&bins, 10));
+    }
+
+    #[test]
+    fn test_eliminate_and_balance() {
+        let lens = [5, 4, 1];
+        let mut bins = vec![super::Bin::default(); 3];
+        for unit in 0..lens.len() {
+            bins[unit].add(unit, &lens);
+        }
+        eliminate_bins(&mut bins, &lens, 10);
+        assert_eq!(bins.len(), 1);
+        assert_eq!(bins[0].load, 10);
+
+        let lens = [6, 5, 5, 4, 4, 3, 3];
+        let mut bins = vec![super::Bin::default(), super::Bin::default()];
+        for unit in 0..lens.len() {
+            bins[0].add(unit, &lens);
+        }
+        balance(&mut bins, &lens);
+        assert!(bins[0].load.max(bins[1].load) - bins[0].load.min(bins[1].load) <= 2);
+    }
+
+    #[test]
+    fn test_locality() {
+        // root, a, a/x, b, b/y in path order
+        let tree = [(0, 0), (0, 4), (1, 4), (0, 4), (3, 4)];
+        let mut unit_set = UnitSet::default();
+        for (index, &(parent, len)) in tree.iter().enumerate() {
+            unit_set.push(Unit {
+                parent,
+                len,
+                path: Path::with_prefix("/").path(format!("/{}", index)),
+                files: Files::default(),
+            });
+        }
+
+        let log = Logger::root(slog::Discard, o!());
+        let sets = Locality::new(1.)
+            .plan(unit_set, 8, &log)
+            .expect("plan");
+        assert_eq!(sets.len(), 2);
+        let parents: Vec<Vec<usize>> = sets.iter()
+            .map(|set| set.0.iter().map(|unit| unit.parent).collect())
+            .collect();
+        assert!(parents.contains(&vec![0, 1]) || parents.contains(&vec![0, 0, 1]));
+        assert!(parents.contains(&vec![0, 3]) || parents.contains(&vec![0, 0, 3]));
+    }
+
+    #[test]
+    fn test_locality_of_sources() {
+        // Two sources, each a root with a child, as planned together.
+        let source = |prefix: &str| {
+            let mut unit_set = UnitSet::default();
+            for (index, &(parent, len)) in [(0, 1), (0, 4)].iter().enumerate() {
+                unit_set.push(Unit {
+                    parent,
+                    len,
+                    path: Path::with_prefix(prefix).path(format!("{}/{}", prefix, index)),
+                    files: Files::default(),
+                });
+            }
+            unit_set
+        };
+        let mut unit_set = source("/a");
+        unit_set.append(source("/b"));
+        let parents: Vec<usize> = unit_set.0.iter().map(|unit| unit.parent).collect();
+        assert_eq!(parents, vec![0, 0, 0, 2]);
+        assert_eq!(unit_set.plan_merges(2), vec![(1, 0), (2, 0), (3, 0)]);
+
+        let log = Logger::root(slog::Discard, o!());
+        let sets = Locality::new(1.)
+            .plan(unit_set, 5, &log)
+            .expect("plan");
+        assert_eq!(sets.iter().map(|set| set.0.len()).sum::<usize>(), 4);
+    }
+}
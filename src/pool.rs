@@ -0,0 +1,134 @@
+use std::panic;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+// Does the work on every job using at most the given number of
+// threads, and hands every result with the index of its job to done on
+// the calling thread as soon as it is ready.  Once done fails, no more
+// jobs are started, and its error is returned when the jobs under way
+// are finished.  A panic in a worker is passed on once the other
+// workers are done.
+pub fn each<T, R, F, D, E>(jobs: Vec<T>, workers: usize, work: F, mut done: D) -> Result<(), E>
+where
+    T: Send + 'static,
+    R: Send + 'static,
+    F: Fn(T) -> R + Send + Sync + 'static,
+    D: FnMut(usize, R) -> Result<(), E>,
+{
+    let count = jobs.len();
+    let jobs = Arc::new(Mutex::new(jobs.into_iter().enumerate()));
+    let (sender, receiver) = mpsc::channel();
+    let work = Arc::new(work);
+
+    let workers: Vec<_> = (0..workers.max(1).min(count))
+        .map(|_| {
+            let jobs = Arc::clone(&jobs);
+            let sender = sender.clone();
+            let work = Arc::clone(&work);
+            thread::spawn(move || loop {
+                let job = jobs.lock().expect("pool jobs").next();
+                match job {
+                    Some((index, job)) => {
+                        let result = work(job);
+                        if sender.send((index, result)).is_err() {
+                            break;
+                        }
+                    }
+                    None => break,
+                }
+            })
+        })
+        .collect();
+    drop(sender);
+
+    // The results stop coming when every worker is gone, panicked or
+    // not.
+    let mut outcome = Ok(());
+    for (index, result) in receiver {
+        if outcome.is_ok() {
+            outcome = done(index, result);
+            if outcome.is_err() {
+                jobs.lock().expect("pool jobs").by_ref().for_each(drop);
+            }
+        }
+    }
+
+    let mut panicked = None;
+    for worker in workers {
+        if let Err(payload) = worker.join() {
+            panicked = Some(payload);
+        }
+    }
+    if let Some(payload) = panicked {
+        panic::resume_unwind(payload);
+    }
+    outcome
+}
+
+#[cfg(test)]
+mod test {
+    use pool;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_each() {
+        // the number of jobs running, and the most that ever ran at once
+        let running = Arc::new(Mutex::new((0, 0)));
+        let running_in_work = Arc::clone(&running);
+
+        let mut results = vec![];
+        let outcome = pool::each(
+            (0..10).collect(),
+            3,
+            move |n: usize| {
+                {
+                    let mut running = running_in_work.lock().unwrap();
+                    running.0 += 1;
+                    running.1 = running.1.max(running.0);
+                }
+                thread::sleep(Duration::from_millis(10));
+                running_in_work.lock().unwrap().0 -= 1;
+                n * n
+            },
+            |index, result| -> Result<(), ()> {
+                results.push((index, result));
+                Ok(())
+            },
+        );
+
+        assert_eq!(outcome, Ok(()));
+        results.sort();
+        assert_eq!(results, (0..10).map(|n| (n, n * n)).collect::<Vec<_>>());
+        assert!(running.lock().unwrap().1 <= 3);
+    }
+
+    #[test]
+    fn test_each_failing() {
+        let started = Arc::new(Mutex::new(0));
+        let started_in_work = Arc::clone(&started);
+        let mut done = vec![];
+
+        let outcome = pool::each(
+            (0..10).collect(),
+            1,
+            move |n: usize| {
+                *started_in_work.lock().unwrap() += 1;
+                thread::sleep(Duration::from_millis(10));
+                n * n
+            },
+            |index, result| {
+                done.push((index, result));
+                Err(index)
+            },
+        );
+
+        // Nothing is handed on after the failure, and the jobs not yet
+        // started are left.
+        assert_eq!(outcome, Err(0));
+        assert_eq!(done, vec![(0, 0)]);
+        assert!(*started.lock().unwrap() < 10);
+    }
+}
@@ -0,0 +1,296 @@
+use block_size::BLOCK_SIZES;
+use errors::*;
+use serde_json;
+use std::fs;
+use std::path::Path as StdPath;
+
+const MIB: u64 = 1024 * 1024;
+const OPTICAL_SECTOR_SIZE: u64 = 2048;
+const FILE_SECTOR_SIZE: u64 = 512;
+const TAPE_RECORD_SIZE: u64 = 0x80_000;
+const TAPE_BLOCK_SIZE: u64 = 0x100_000;
+
+// Describes a kind of backup medium.  The capacity is what is left
+// for our files after the file system and the burner or tape drive
+// have taken their share, not what is printed on the label.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MediumProfile {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    pub capacity: u64,
+    pub sector_size: u64,
+    #[serde(default)]
+    pub block_size: Option<u64>,
+}
+
+impl MediumProfile {
+    // Makes a profile for a medium of arbitrary size, such as a
+    // file on a disk or a network share.
+    pub fn file(size: u64) -> Self {
+        MediumProfile {
+            name: "file".into(),
+            description: format!("{} MiB file", size / MIB),
+            capacity: size,
+            sector_size: FILE_SECTOR_SIZE,
+            block_size: None,
+        }
+    }
+
+    fn optical(name: &str, description: &str, sectors: u64) -> Self {
+        let raw = sectors * OPTICAL_SECTOR_SIZE;
+
+        // Reserve about 0.2% for the ISO 9660 and UDF structures and
+        // the run-out blocks some burners insist on, but never less
+        // than 2 MiB.
+        let reserved = round_up(::std::cmp::max(raw / 512, 2 * MIB), OPTICAL_SECTOR_SIZE);
+
+        MediumProfile {
+            name: name.into(),
+            description: description.into(),
+            capacity: raw - reserved,
+            sector_size: OPTICAL_SECTOR_SIZE,
+            block_size: None,
+        }
+    }
+
+    fn tape(name: &str, description: &str, native: u64) -> Self {
+        // LTFS keeps its index in a separate partition and writes
+        // index copies into the data partition as well.  Allow 5%.
+        let reserved = round_up(native / 20, TAPE_RECORD_SIZE);
+
+        MediumProfile {
+            name: name.into(),
+            description: description.into(),
+            capacity: native - reserved,
+            sector_size: TAPE_RECORD_SIZE,
+            block_size: Some(TAPE_BLOCK_SIZE),
+        }
+    }
+}
+
+pub struct Profiles(Vec<MediumProfile>);
+
+impl Profiles {
+    pub fn builtin() -> Self {
+        Profiles(vec![
+            MediumProfile::optical("cd-r-700", "CD-R 80 minutes, 700 MB", 359_846),
+            MediumProfile::optical("dvd+r", "DVD+R single layer, 4.7 GB", 2_295_104),
+            MediumProfile::optical("dvd-r", "DVD-R single layer, 4.7 GB", 2_298_496),
+            MediumProfile::optical("dvd+r-dl", "DVD+R dual layer, 8.5 GB", 4_173_824),
+            MediumProfile::optical("dvd-r-dl", "DVD-R dual layer, 8.5 GB", 4_171_712),
+            MediumProfile::optical("bd-r-25", "BD-R single layer, 25 GB", 12_219_392),
+            MediumProfile::optical("bd-r-50", "BD-R dual layer, 50 GB", 24_438_784),
+            MediumProfile::optical("bd-r-100", "BD-R XL triple layer, 100 GB", 48_878_592),
+            MediumProfile::optical("bd-r-128", "BD-R XL quadruple layer, 128 GB", 62_500_864),
+            MediumProfile::optical("m-disc-dvd", "M-DISC DVD, 4.7 GB", 2_295_104),
+            MediumProfile::optical("m-disc-bd-25", "M-DISC BD-R, 25 GB", 12_219_392),
+            MediumProfile::optical("m-disc-bd-50", "M-DISC BD-R, 50 GB", 24_438_784),
+            MediumProfile::optical("m-disc-bd-100", "M-DISC BD-R XL, 100 GB", 48_878_592),
+            MediumProfile::tape("lto-5", "LTO-5, 1.5 TB native", 1_500_000_000_000),
+            MediumProfile::tape("lto-6", "LTO-6, 2.5 TB native", 2_500_000_000_000),
+            MediumProfile::tape("lto-7", "LTO-7, 6 TB native", 6_000_000_000_000),
+            MediumProfile::tape("lto-8", "LTO-8, 12 TB native", 12_000_000_000_000),
+            MediumProfile::tape("lto-9", "LTO-9, 18 TB native", 18_000_000_000_000),
+        ])
+    }
+
+    // Loads the profiles defined in a JSON file, which holds an array
+    // of profiles.  A profile named the same as a built-in replaces
+    // it.
+    pub fn load<P: AsRef<StdPath>>(mut self, path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let file = fs::File::open(path).chain_err(|| format!("error opening {:?}", path))?;
+        let profiles: Vec<MediumProfile> = serde_json::from_reader(file)
+            .chain_err(|| format!("error reading medium profiles from {:?}", path))?;
+
+        for profile in profiles {
+            self.add(profile)?;
+        }
+        Ok(self)
+    }
+
+    pub fn add(&mut self, profile: MediumProfile) -> Result<()> {
+        if profile.capacity == 0 || profile.sector_size == 0 {
+            bail!("medium profile {} has no capacity", profile.name);
+        }
+        let largest = BLOCK_SIZES[BLOCK_SIZES.len() - 1];
+        if profile.sector_size > largest {
+            bail!(
+                "sector size {} of medium profile {} is larger than the largest block size {}",
+                profile.sector_size,
+                profile.name,
+                largest
+            );
+        }
+        if let Some(block_size) = profile.block_size {
+            if block_size == 0 {
+                bail!("medium profile {} has a block size of 0", profile.name);
+            }
+            if block_size > u64::from(u32::MAX) {
+                bail!(
+                    "block size {} of medium profile {} is larger than {}",
+                    block_size,
+                    profile.name,
+                    u32::MAX
+                );
+            }
+            if block_size % profile.sector_size != 0 {
+                bail!(
+                    "block size {} of medium profile {} is not a multiple of its sector size {}",
+                    block_size,
+                    profile.name,
+                    profile.sector_size
+                );
+            }
+        }
+
+        self.0.retain(|other| !other.name.eq_ignore_ascii_case(&profile.name));
+        self.0.push(profile);
+        Ok(())
+    }
+
+    // Looks up a profile by name.  A plain number, or `file:` followed
+    // by a number, makes a file profile of that many MiB.
+    pub fn resolve(&self, spec: &str) -> Result<MediumProfile> {
+        let size = if spec.starts_with("file:") {
+            Some(&spec["file:".len()..])
+        } else if spec.chars().all(|ch| ch.is_digit(10)) {
+            Some(spec)
+        } else {
+            None
+        };
+
+        if let Some(size) = size {
+            let size = size.parse::<u64>()
+                .chain_err(|| format!("expecting the size of the backup media in MiB: {}", spec))?;
+            if size == 0 {
+                bail!("the size of the backup media must not be zero");
+            }
+            let size = size.checked_mul(MIB)
+                .chain_err(|| format!("the size of the backup media is too large: {}", spec))?;
+            Ok(MediumProfile::file(size))
+        } else {
+            self.0
+                .iter()
+                .find(|profile| profile.name.eq_ignore_ascii_case(spec))
+                .cloned()
+                .ok_or_else(|| ErrorKind::UnknownMediumProfile(spec.into()).into())
+        }
+    }
+
+    #[allow(unused)]
+    pub fn iter(&self) -> ::std::slice::Iter<MediumProfile> {
+        self.0.iter()
+    }
+}
+
+fn round_up(n: u64, multiple: u64) -> u64 {
+    (n + multiple - 1) / multiple * multiple
+}
+
+#[cfg(test)]
+mod test {
+    use profile::{MediumProfile, Profiles, MIB};
+    use std::fs;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_builtin_capacity() {
+        let profiles = Profiles::builtin();
+        let cd = profiles.resolve("CD-R-700").expect("resolve");
+        assert_eq!(cd.sector_size, 2048);
+        assert!(cd.capacity < 359_846 * 2048);
+        assert!(cd.capacity > 700 * MIB);
+        assert_eq!(cd.capacity % cd.sector_size, 0);
+
+        for profile in profiles.iter() {
+            assert!(profile.capacity > 0);
+            if let Some(block_size) = profile.block_size {
+                assert_eq!(block_size % profile.sector_size, 0);
+            }
+        }
+    }
+
+    #[test]
+    fn test_file_profile() {
+        let profiles = Profiles::builtin();
+        assert_eq!(profiles.resolve("700").expect("resolve").capacity, 700 * MIB);
+        assert_eq!(
+            profiles.resolve("file:4096").expect("resolve").capacity,
+            4096 * MIB
+        );
+        assert!(profiles.resolve("file:").is_err());
+        assert!(profiles.resolve("0").is_err());
+        assert!(profiles.resolve("floppy").is_err());
+        assert!(profiles.resolve("file:18446744073709551615").is_err());
+    }
+
+    #[test]
+    fn test_override() {
+        let mut profiles = Profiles::builtin();
+        profiles
+            .add(MediumProfile {
+                name: "bd-r-25".into(),
+                description: Default::default(),
+                capacity: 24_000 * MIB,
+                sector_size: 2048,
+                block_size: Some(0x10_000),
+            })
+            .expect("add");
+        let bd = profiles.resolve("bd-r-25").expect("resolve");
+        assert_eq!(bd.capacity, 24_000 * MIB);
+        assert_eq!(bd.block_size, Some(0x10_000));
+        assert_eq!(
+            profiles
+                .iter()
+                .filter(|profile| profile.name == "bd-r-25")
+                .count(),
+            1
+        );
+
+        // No block size considered would fit in a sector.
+        let huge = MediumProfile {
+            name: "huge".into(),
+            description: Default::default(),
+            capacity: 24_000 * MIB,
+            sector_size: 0x10_000_000,
+            block_size: None,
+        };
+        assert!(profiles.add(huge).is_err());
+        let huge = MediumProfile {
+            name: "huge".into(),
+            description: Default::default(),
+            capacity: 24_000 * MIB,
+            sector_size: 0x800,
+            block_size: Some(0x1_0000_0000),
+        };
+        assert!(profiles.add(huge).is_err());
+        let empty = MediumProfile {
+            name: "empty".into(),
+            description: Default::default(),
+            capacity: 24_000 * MIB,
+            sector_size: 0x800,
+            block_size: Some(0),
+        };
+        assert!(profiles.add(empty).is_err());
+    }
+
+    #[test]
+    fn test_load() {
+        let temp_dir = TempDir::new("test_profiles").expect("TempDir::new");
+        let path = temp_dir.path().join("profiles.json");
+        fs::write(
+            &path,
+            r#"[{"name": "tape", "capacity": 1073741824, "sector_size": 512}]"#,
+        ).expect("write");
+        let profiles = Profiles::builtin().load(&path).expect("load");
+        assert_eq!(profiles.resolve("tape").expect("resolve").capacity, 1 << 30);
+
+        fs::write(
+            &path,
+            r#"[{"name": "tape", "capacity": 1073741824, "sector_size": 512, "block_size": 0}]"#,
+        ).expect("write");
+        assert!(Profiles::builtin().load(&path).is_err());
+    }
+}
@@ -0,0 +1,286 @@
+use libc;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+// How often the progress of a phase is drawn on the terminal, and how
+// often a line of it is written when there is no terminal to draw it
+// on.
+const TERMINAL_INTERVAL: Duration = Duration::from_millis(200);
+const LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+const BAR_WIDTH: usize = 30;
+
+// Where progress goes: a bar redrawn in place on the terminal, on
+// standard error so as not to mix with the output of commands, or
+// records in the log for whoever watches it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Output {
+    Terminal,
+    Log,
+}
+
+impl Output {
+    #[cfg(target_family = "unix")]
+    fn detect() -> Self {
+        if unsafe { libc::isatty(libc::STDERR_FILENO) } == 1 {
+            Output::Terminal
+        } else {
+            Output::Log
+        }
+    }
+
+    #[cfg(not(target_family = "unix"))]
+    fn detect() -> Self {
+        Output::Log
+    }
+}
+
+#[derive(Debug)]
+struct State {
+    phase: &'static str,
+    output: Output,
+    started: Instant,
+    reported: Instant,
+    bytes: u64,
+    files: u64,
+    total_bytes: u64,
+    total_files: u64,
+    finished: bool,
+}
+
+impl State {
+    // Bytes processed per second since the phase started.
+    fn throughput(&self, now: Instant) -> f64 {
+        let elapsed = now.duration_since(self.started);
+        let secs = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+        if secs > 0. {
+            self.bytes as f64 / secs
+        } else {
+            0.
+        }
+    }
+
+    // The time left at the throughput so far, if the total is known.
+    fn eta(&self, now: Instant) -> Option<Duration> {
+        let throughput = self.throughput(now);
+        if self.total_bytes == 0 || throughput <= 0. {
+            return None;
+        }
+        let left = self.total_bytes.saturating_sub(self.bytes) as f64;
+        Some(Duration::from_secs((left / throughput).ceil() as u64))
+    }
+
+    fn line(&self, now: Instant) -> String {
+        let throughput = format!("{}/s", human_bytes(self.throughput(now) as u64));
+        if self.total_bytes == 0 {
+            return format!(
+                "{}: {}, {} files, {}",
+                self.phase,
+                human_bytes(self.bytes),
+                self.files,
+                throughput
+            );
+        }
+
+        let ratio = (self.bytes as f64 / self.total_bytes as f64).min(1.);
+        let filled = (ratio * BAR_WIDTH as f64) as usize;
+        let eta = match self.eta(now) {
+            Some(eta) if !self.finished => format!(", ETA {}", human_duration(eta)),
+            _ => String::new(),
+        };
+        format!(
+            "{} [{}{}] {:3.0}% {}/{}, {}/{} files, {}{}",
+            self.phase,
+            "#".repeat(filled),
+            ".".repeat(BAR_WIDTH - filled),
+            ratio * 100.,
+            human_bytes(self.bytes),
+            human_bytes(self.total_bytes),
+            self.files,
+            self.total_files,
+            throughput,
+            eta
+        )
+    }
+
+    fn finish(&mut self) {
+        if !self.finished {
+            self.finished = true;
+            self.report(Instant::now());
+        }
+    }
+
+    fn report(&mut self, now: Instant) {
+        self.reported = now;
+        match self.output {
+            Output::Terminal => {
+                // Clears what is left of a longer line drawn before.
+                eprint!("\r{}\x1b[K", self.line(now));
+                if self.finished {
+                    eprintln!();
+                }
+                let _ = io::stderr().flush();
+            }
+            Output::Log => info!(
+                "progress";
+                "phase" => self.phase,
+                "bytes" => self.bytes,
+                "files" => self.files,
+                "throughput" => self.throughput(now),
+                "eta" => self.eta(now).map(|eta| eta.as_secs())
+            ),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Shared(Mutex<State>);
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.0.lock() {
+            state.finish();
+        }
+    }
+}
+
+// The bytes and files a long phase of the backup has processed, of
+// how many if that is known.  Clones report to the same phase, so it
+// can be shared by the threads doing the work.  The phase is reported
+// as finished at the latest when the last clone is gone.
+#[derive(Clone, Debug)]
+pub struct Progress(Arc<Shared>);
+
+impl Progress {
+    pub fn new(phase: &'static str) -> Self {
+        Self::with_output(phase, Output::detect())
+    }
+
+    fn with_output(phase: &'static str, output: Output) -> Self {
+        let now = Instant::now();
+        Progress(Arc::new(Shared(Mutex::new(State {
+            phase,
+            output,
+            started: now,
+            reported: now,
+            bytes: 0,
+            files: 0,
+            total_bytes: 0,
+            total_files: 0,
+            finished: false,
+        }))))
+    }
+
+    // Counts more work in the total of the phase.
+    pub fn add_total(&self, bytes: u64, files: u64) {
+        let mut state = self.state();
+        state.total_bytes += bytes;
+        state.total_files += files;
+    }
+
+    // Counts the bytes and files processed, reporting them once in a
+    // while.
+    pub fn advance(&self, bytes: u64, files: u64) {
+        let mut state = self.state();
+        state.bytes += bytes;
+        state.files += files;
+
+        let now = Instant::now();
+        let interval = match state.output {
+            Output::Terminal => TERMINAL_INTERVAL,
+            Output::Log => LOG_INTERVAL,
+        };
+        if !state.finished && now.duration_since(state.reported) >= interval {
+            state.report(now);
+        }
+    }
+
+    // Reports the phase as finished, unless it was already.
+    pub fn finish(&self) {
+        self.state().finish();
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        (self.0).0.lock().expect("progress state")
+    }
+}
+
+// Formats a number of bytes in the largest binary unit it makes at
+// least one of.
+fn human_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["KiB", "MiB", "GiB", "TiB", "PiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut value = bytes as f64 / 1024.;
+    let mut unit = 0;
+    while value >= 1024. && unit < UNITS.len() - 1 {
+        value /= 1024.;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+fn human_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs >= 60 * 60 {
+        format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+    } else {
+        format!("{}:{:02}", secs / 60, secs % 60)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use progress::{human_bytes, human_duration, Output, Progress};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_human() {
+        assert_eq!(human_bytes(0), "0 B");
+        assert_eq!(human_bytes(1023), "1023 B");
+        assert_eq!(human_bytes(1536), "1.5 KiB");
+        assert_eq!(human_bytes(5 * 1024 * 1024 * 1024), "5.0 GiB");
+        assert_eq!(human_duration(Duration::from_secs(75)), "1:15");
+        assert_eq!(human_duration(Duration::from_secs(3 * 3600 + 5)), "3:00:05");
+    }
+
+    #[test]
+    fn test_progress() {
+        let progress = Progress::with_output("test", Output::Log);
+        progress.add_total(1000, 4);
+
+        let workers: Vec<_> = (0..4)
+            .map(|_| {
+                let progress = progress.clone();
+                thread::spawn(move || progress.advance(250, 1))
+            })
+            .collect();
+        for worker in workers {
+            worker.join().expect("worker");
+        }
+
+        let state = progress.state();
+        assert_eq!((state.bytes, state.files), (1000, 4));
+        let now = state.started + Duration::from_secs(10);
+        assert_eq!(state.throughput(now), 100.);
+        assert_eq!(state.eta(now), Some(Duration::from_secs(0)));
+        assert!(state.line(Instant::now()).contains("1000 B/1000 B, 4/4 files"));
+    }
+
+    #[test]
+    fn test_eta() {
+        let progress = Progress::with_output("test", Output::Log);
+        assert_eq!(progress.state().eta(Instant::now()), None);
+
+        progress.add_total(1000, 1);
+        progress.advance(250, 0);
+        let state = progress.state();
+        let now = state.started + Duration::from_secs(5);
+        assert_eq!(state.eta(now), Some(Duration::from_secs(15)));
+        assert!(state.line(now).contains(" 25% "));
+        assert!(state.line(now).contains("ETA 0:15"));
+    }
+}
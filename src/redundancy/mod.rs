@@ -1,15 +1,11 @@
 mod redun;
+mod xor;
 
-pub use self::redun::{generate_key, EncKey, Nonce, PartialIndex, PartialIndexKind, Redundancy};
-
-pub type Hash = [u8; 20];
+pub use self::redun::{blocks_per_file, generate_key, min_memory, read_block, EncKey, Nonce,
+                      PartialIndex, PartialIndexKind, Redundancy};
 
 pub fn redundancy(data1: &[u8], data2: &[u8], out: &mut [u8]) {
-    assert_eq!(data1.len(), data2.len());
-    assert_eq!(data1.len(), out.len());
-    for i in 0..data1.len() {
-        out[i] = data1[i] ^ data2[i];
-    }
+    xor::xor(data1, data2, out);
 }
 
 pub fn redundancy_copy(data1: &[u8], data2: &[u8], out: &mut [u8]) {
@@ -40,16 +36,78 @@ fn redundancy_copy_impl(
 
 #[cfg(test)]
 mod tests {
+    use rand::{self, Rng};
     use redundancy::redundancy;
     use redundancy::redundancy_copy;
+    use redundancy::xor;
+    use std::time::Instant;
+
+    const DATA1: [u8; 5] = [0u8, 30, 128, 10, 84];
+    const DATA2: [u8; 5] = [90u8, 1, 74, 121, 3];
+    const XORED: [u8; 5] = [90u8, 31, 202, 115, 87];
 
     #[test]
     fn test_redundancy() {
-        let data1 = [0u8, 30, 128, 10, 84];
-        let data2 = [90u8, 1, 74, 121, 3];
         let mut out = [0u8; 5];
-        redundancy(&data1, &data2, &mut out);
-        assert_eq!(out, [90u8, 31, 202, 115, 87]);
+        redundancy(&DATA1, &DATA2, &mut out);
+        assert_eq!(out, XORED);
+    }
+
+    // Checks every way to XOR against the vectors above, and against
+    // XORing a byte at a time at lengths and offsets that leave bytes
+    // over at either end.
+    #[test]
+    fn test_xor_implementations() {
+        let mut rng = rand::thread_rng();
+        let mut left = vec![0u8; 1000];
+        let mut right = vec![0u8; 1000];
+        rng.fill_bytes(&mut left);
+        rng.fill_bytes(&mut right);
+
+        for (name, implementation) in xor::implementations() {
+            let mut out = [0u8; 5];
+            implementation(&DATA1, &DATA2, &mut out);
+            assert_eq!(out, XORED, "{}", name);
+
+            for &(start, end) in &[(0, 1000), (1, 1000), (3, 997), (0, 31), (7, 40)] {
+                let mut expected = vec![0u8; end - start];
+                let mut out = vec![0u8; end - start];
+                xor::xor_bytes(&left[start..end], &right[start..end], &mut expected);
+                implementation(&left[start..end], &right[start..end], &mut out);
+                assert!(out == expected, "{} from {} to {}", name, start, end);
+            }
+        }
+    }
+
+    // Compares the ways to XOR on blocks of a MiB.  Run it with
+    // cargo test --release -- --ignored --nocapture bench_xor
+    #[test]
+    #[ignore]
+    fn bench_xor() {
+        const LEN: usize = 1024 * 1024;
+        const ROUNDS: usize = 256;
+        let left = vec![0x5au8; LEN];
+        let right = vec![0xa5u8; LEN];
+        let mut out = vec![0u8; LEN];
+
+        for (name, implementation) in xor::implementations() {
+            let mut check = [0u8; 5];
+            implementation(&DATA1, &DATA2, &mut check);
+            assert_eq!(check, XORED, "{}", name);
+
+            let start = Instant::now();
+            for _ in 0..ROUNDS {
+                implementation(&left, &right, &mut out);
+            }
+            let elapsed = start.elapsed();
+            assert!(out.iter().all(|&byte| byte == 0xff));
+            let seconds = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+            println!(
+                "{:>6}: {:>8.0} MiB/s",
+                name,
+                (LEN * ROUNDS) as f64 / seconds / (1024.0 * 1024.0)
+            );
+        }
     }
 
     #[test]
@@ -3,22 +3,31 @@ use consts::*;
 use crypto::aes;
 use crypto::symmetriccipher::SynchronousStreamCipher;
 use errors::*;
+use hash::{self, Hash};
 use index::{self, FileTable};
-use medium::Medium;
 use path::Path;
+use progress::Progress;
 use rand::{OsRng, Rng};
-use redundancy::{redundancy_copy, Hash};
-use sha1::Sha1;
+use redundancy::redundancy_copy;
 use std::cmp;
 use std::collections::HashMap;
-use std::io::Write;
+use std::fs;
+use std::io::{Read, Seek, SeekFrom, Write};
 use std::mem;
 use std::path::Path as StdPath;
 use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver};
+use std::thread::{self, JoinHandle};
 use time::get_time;
-use unit;
 use verifile::Verifile;
 
+// How much of a block is encrypted at a time as it is written.
+const ENCRYPTION_CHUNK: usize = 64 * 1024;
+
+// The blocks in memory while the redundancy of a group is built, on
+// top of those read ahead.
+const IN_FLIGHT_BLOCKS: u64 = 5;
+
 pub type EncKey = [u8; 16];
 pub type Nonce = [u8; 16];
 
@@ -62,21 +71,35 @@ impl RedunFile {
         self.file.path()
     }
 
-    pub fn write_blocks(&mut self, blocks: &[Block], block_size: usize) -> Result<()> {
+    // Writes the blocks as they come, encrypting them a piece at a time,
+    // and returns how many were written.
+    pub fn write_blocks<I>(&mut self, blocks: I, block_size: usize) -> Result<usize>
+    where
+        I: IntoIterator<Item = Result<Block>>,
+    {
         let mut write = self.file.write()?;
+        let mut buf = vec![0u8; cmp::min(block_size, ENCRYPTION_CHUNK)];
+        let mut count = 0;
         for block in blocks {
+            let block = block?;
             debug_assert_eq!(block.nonce.len(), 16);
             debug_assert_eq!(block.bytes.len(), block_size);
 
             write
                 .write_all(&block.nonce)
                 .chain_err(|| format!("error writing to {:?}", write))?;
-            write
-                .write_all(&encrypt(&block.bytes, &self.key, &block.nonce))
-                .chain_err(|| format!("error writing to {:?}", write))?;
+            let mut cipher = aes::ctr(aes::KeySize::KeySize128, &self.key, &block.nonce);
+            for chunk in block.bytes.chunks(buf.len()) {
+                let buf = &mut buf[..chunk.len()];
+                cipher.process(chunk, buf);
+                write
+                    .write_all(buf)
+                    .chain_err(|| format!("error writing to {:?}", write))?;
+            }
+            count += 1;
         }
         write.close()?;
-        Ok(())
+        Ok(count)
     }
 
     #[cfg(test)]
@@ -85,6 +108,23 @@ impl RedunFile {
     }
 }
 
+// Reads the block at the given position in a redundancy file and
+// decrypts it with the key of its group.
+pub fn read_block(path: &StdPath, key: &[u8], index: usize, block_size: usize) -> Result<Vec<u8>> {
+    let record = (mem::size_of::<Nonce>() + block_size) as u64;
+    let mut file = fs::File::open(path).chain_err(|| ErrorKind::UnreadableFile(path.into()))?;
+    let mut nonce = Nonce::default();
+    let mut encrypted = vec![0u8; block_size];
+    file.seek(SeekFrom::Start(index as u64 * record))
+        .and_then(|_| file.read_exact(&mut nonce))
+        .and_then(|_| file.read_exact(&mut encrypted))
+        .chain_err(|| ErrorKind::UnreadableFile(path.into()))?;
+
+    let mut block = vec![0u8; block_size];
+    aes::ctr(aes::KeySize::KeySize128, key, &nonce).process(&encrypted, &mut block);
+    Ok(block)
+}
+
 #[derive(Debug)]
 pub enum PartialIndexKind {
     Redundancy {
@@ -104,38 +144,71 @@ pub struct PartialIndex {
     pub hash: Hash,
 }
 
+// Builds the redundancy of a group of media.  It owns all it needs,
+// so groups can be built on threads of their own.
 #[derive(Debug)]
-pub struct Redundancy<'a> {
+pub struct Redundancy {
     block_size: usize,
-    left: &'a Medium,
-    right: &'a Medium,
-    redun: &'a mut Medium,
-    file_table: &'a FileTable,
+    left: Vec<block::File>,
+    right: Vec<block::File>,
     workdir: PathBuf,
-    queue: Vec<Block>,
+    len: u64,
+    file_size: u64,
+    read_ahead: usize,
+    progress: Option<Progress>,
+    hash_algorithm: hash::Algorithm,
+    redun_files: Vec<(PathBuf, u64)>,
     partial_indices: HashMap<PathBuf, Vec<PartialIndex>>,
     key: EncKey,
+    tolerate_changes: bool,
+    changed: Vec<usize>,
+    hashes: Vec<(usize, Hash)>,
 }
 
-impl<'a> Redundancy<'a> {
+impl Redundancy {
     pub fn new(
         block_size: usize,
         workdir: &StdPath,
-        left: &'a Medium,
-        right: &'a Medium,
-        redun: &'a mut Medium,
-        file_table: &'a FileTable,
+        file_table: &FileTable,
+        left_id: usize,
+        right_id: usize,
     ) -> Self {
+        // Duplicates are stored with the files they duplicate.
+        let entries = |medium_id: usize| {
+            file_table.entries().iter().filter(move |file_entry| {
+                file_entry.medium_id() == medium_id && file_entry.same_as().is_none()
+            })
+        };
+        let files = |medium_id: usize| -> Vec<block::File> {
+            entries(medium_id)
+                .map(|file_entry| {
+                    block::File::new(file_entry.id(), file_entry.actual_path())
+                        .snapshot(file_entry.snapshot())
+                })
+                .collect()
+        };
+
+        let len = entries(left_id)
+            .chain(entries(right_id))
+            .map(|file_entry| file_entry.stored_size().unwrap_or_else(|| file_entry.size()))
+            .sum();
+
         Redundancy {
             block_size,
-            left,
-            right,
-            redun,
-            file_table,
+            left: files(left_id),
+            right: files(right_id),
             workdir: workdir.into(),
-            queue: Default::default(),
+            len,
+            file_size: REDUNDANCY_FILE_SIZE,
+            read_ahead: READ_AHEAD_BLOCKS,
+            progress: None,
+            hash_algorithm: hash::Algorithm::default(),
+            redun_files: Default::default(),
             partial_indices: Default::default(),
             key: Default::default(),
+            tolerate_changes: false,
+            changed: Default::default(),
+            hashes: Default::default(),
         }
     }
 
@@ -144,6 +217,65 @@ impl<'a> Redundancy<'a> {
         self
     }
 
+    // Sizes the redundancy files, which hold as many blocks as fit.
+    pub fn file_size(mut self, file_size: u64) -> Self {
+        self.file_size = file_size;
+        self
+    }
+
+    // Hashes the files and the blocks with the algorithm, rather than
+    // with SHA-1.
+    pub fn hash_algorithm(mut self, hash_algorithm: hash::Algorithm) -> Self {
+        self.hash_algorithm = hash_algorithm;
+        self
+    }
+
+    // Reads fewer blocks ahead, if need be, to keep the blocks of the
+    // group in memory within the limit.  The limit is never less than
+    // min_memory.
+    pub fn memory_limit(mut self, memory_limit: u64) -> Self {
+        let spare = (memory_limit / self.block_size as u64).saturating_sub(IN_FLIGHT_BLOCKS);
+        // A block may wait to be taken on each side.
+        self.read_ahead = cmp::min(READ_AHEAD_BLOCKS as u64, spare / 2) as usize;
+        self
+    }
+
+    // Reports the files as they are read to the progress, counting
+    // them in its total first.
+    pub fn progress(mut self, progress: &Progress) -> Self {
+        progress.add_total(self.len, (self.left.len() + self.right.len()) as u64);
+        self.progress = Some(progress.clone());
+        self
+    }
+
+    // Carries on past files that changed since they were scanned,
+    // instead of failing.
+    pub fn tolerate_changes(mut self, tolerate_changes: bool) -> Self {
+        self.tolerate_changes = tolerate_changes;
+        self
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    // Returns the ids of the files that changed since they were
+    // scanned.
+    pub fn changed_files(&self) -> &[usize] {
+        &self.changed
+    }
+
+    // Returns the ids of the files read with the hashes of their
+    // contents.
+    pub fn file_hashes(&self) -> &[(usize, Hash)] {
+        &self.hashes
+    }
+
+    // Returns the redundancy files written, with their lengths.
+    pub fn redun_files(&self) -> &[(PathBuf, u64)] {
+        &self.redun_files
+    }
+
     pub fn partial_indices(&mut self) -> HashMap<PathBuf, Vec<PartialIndex>> {
         mem::replace(&mut self.partial_indices, Default::default())
     }
@@ -151,161 +283,248 @@ impl<'a> Redundancy<'a> {
     pub fn build(&mut self) -> Result<()> {
         assert!(self.workdir.is_dir());
 
-        // Used to sequentially name temporary files
-        let mut counter: usize = 0;
-
-        let left_id = self.left.id();
-        let right_id = self.right.id();
-        let mut left_iter = BlockIter::new(
+        let (left, left_reader) = read_ahead(
             self.block_size,
-            self.file_table
-                .entries()
-                .iter()
-                .filter(|file_entry| file_entry.medium_id() == left_id)
-                .map(|file_entry| {
-                    block::File::new(file_entry.id(), file_entry.actual_path())
-                }),
-        )?;
-        let mut right_iter = BlockIter::new(
+            mem::replace(&mut self.left, vec![]),
+            self.read_ahead,
+            self.hash_algorithm,
+            self.tolerate_changes,
+            self.progress.clone(),
+        );
+        let (right, right_reader) = read_ahead(
             self.block_size,
-            self.file_table
-                .entries()
-                .iter()
-                .filter(|file_entry| file_entry.medium_id() == right_id)
-                .map(|file_entry| {
-                    block::File::new(file_entry.id(), file_entry.actual_path())
-                }),
-        )?;
-
-        let mut partial_indices: Vec<PartialIndex> = Default::default();
-
-        loop {
-            let lblk = left_iter.next_block()?;
-            let rblk = right_iter.next_block()?;
-
-            if lblk.is_none() && rblk.is_none() {
-                break;
-            } else if lblk.is_none() || rblk.is_none() {
-                // replication
-                let block = lblk.unwrap_or_else(|| rblk.unwrap());
-                let mut sha1 = Sha1::new();
-                sha1.update(block.data());
-                let hash = sha1.digest().bytes();
-
-                assert!(block.data().len() <= u32::max_value() as usize);
-                let index = PartialIndex {
-                    kind: PartialIndexKind::Replication {
-                        original: index::Block::new(
-                            block.file_id(),
-                            block.block_id(),
-                            block.data().len() as u32,
-                            &hash,
-                        ),
-                    },
-                    id: self.queue.len(),
-                    len: block.data().len() as u32,
-                    hash,
-                };
-                partial_indices.push(index);
-
-                let mut buf = vec![0u8; self.block_size];
-                buf[..block.data().len()].copy_from_slice(block.data());
-
-                self.queue.push(Block::new(&generate_nonce()?, buf.into()));
-            } else {
-                // create redundancy
-                let lblk = lblk.unwrap();
-                let rblk = rblk.unwrap();
-
-                let mut sha1 = Sha1::new();
-                sha1.update(lblk.data());
-                let lhash = sha1.digest().bytes();
-                sha1.reset();
-                sha1.update(rblk.data());
-                let rhash = sha1.digest().bytes();
-
-                let mut buf = vec![0u8; cmp::max(lblk.data().len(), rblk.data().len())];
-                redundancy_copy(lblk.data(), rblk.data(), &mut buf);
-                sha1.reset();
-                sha1.update(&buf);
-                let redun_hash = sha1.digest().bytes();
-
-                assert!(lblk.data().len() <= u32::max_value() as usize);
-                assert!(rblk.data().len() <= u32::max_value() as usize);
-                let index = PartialIndex {
-                    kind: PartialIndexKind::Redundancy {
-                        left: index::Block::new(
-                            lblk.file_id(),
-                            lblk.block_id(),
-                            lblk.data().len() as u32,
-                            &lhash,
-                        ),
-                        right: index::Block::new(
-                            rblk.file_id(),
-                            rblk.block_id(),
-                            rblk.data().len() as u32,
-                            &rhash,
-                        ),
-                    },
-                    id: self.queue.len(),
-                    len: buf.len() as u32,
-                    hash: redun_hash,
-                };
-                partial_indices.push(index);
-
-                if buf.len() < self.block_size {
-                    let pad = self.block_size - buf.len();
-                    buf.append(&mut vec![0u8; pad]);
-                }
-
-                self.queue.push(Block::new(&generate_nonce()?, buf.into()));
-            }
+            mem::replace(&mut self.right, vec![]),
+            self.read_ahead,
+            self.hash_algorithm,
+            self.tolerate_changes,
+            self.progress.clone(),
+        );
+        let mut pairs = Pairs {
+            block_size: self.block_size,
+            hash_algorithm: self.hash_algorithm,
+            left,
+            right,
+        }.peekable();
 
-            if self.queue.len() >= MAX_REDUNDANCY_BLOCKS {
-                self.write_out_queue(
-                    &format!("{:010}", counter),
-                    mem::replace(&mut partial_indices, vec![]),
-                )?;
-                self.queue.clear();
-                counter += 1;
+        // Used to sequentially name the redundancy files
+        let mut counter: usize = 0;
+        let blocks_per_file = blocks_per_file(self.block_size as u64, self.file_size) as usize;
+
+        while pairs.peek().is_some() {
+            let mut partial_indices = vec![];
+            {
+                let blocks = pairs.by_ref().take(blocks_per_file).map(|pair| {
+                    pair.map(|(block, mut index)| {
+                        index.id = partial_indices.len();
+                        partial_indices.push(index);
+                        block
+                    })
+                });
+                self.write_file(&format!("{:010}", counter), blocks)?;
             }
+            let path = self.redun_files.last().expect("redundancy file").0.clone();
+            let _ = self.partial_indices.insert(path, partial_indices);
+            counter += 1;
         }
 
-        if !self.queue.is_empty() {
-            self.write_out_queue(
-                &format!("{:010}", counter),
-                mem::replace(&mut partial_indices, vec![]),
-            )?;
+        for reader in vec![left_reader, right_reader] {
+            let (changed, hashes) = reader
+                .join()
+                .map_err(|_| Error::from("a thread reading files panicked"))?;
+            self.changed.extend(changed);
+            self.hashes.extend(hashes);
         }
-
         Ok(())
     }
 
-    fn write_out_queue(&mut self, key: &str, partial_indices: Vec<PartialIndex>) -> Result<()> {
+    fn write_file<I>(&mut self, key: &str, blocks: I) -> Result<()>
+    where
+        I: Iterator<Item = Result<Block>>,
+    {
         let path = Path::with_prefix(&self.workdir).path(self.workdir.join(key));
         let mut redun_file =
             RedunFile::new(path.to_str()
                 .chain_err(|| format!("utf8 encoding error {:?}", path))?)?
                 .with_enc_key(&self.key);
-        redun_file.write_blocks(&self.queue, self.block_size)?;
-
-        let path = Path::with_template(&path).path(&redun_file.path());
-        self.partial_indices
-            .insert(path.to_path_buf(), partial_indices);
+        let _ = redun_file.write_blocks(blocks, self.block_size)?;
 
+        let path = redun_file.path().to_path_buf();
         let len = path.metadata()
             .chain_err(|| format!("error getting metadata of {:?}", path))?
             .len();
-        self.redun.push_file(unit::File::new(path, len));
+        self.redun_files.push((path, len));
         Ok(())
     }
 }
 
-fn encrypt(data: &[u8], key: &EncKey, nonce: &Nonce) -> Box<[u8]> {
-    let mut buf = vec![0u8; data.len()];
-    let mut cipher = aes::ctr(aes::KeySize::KeySize128, key, nonce);
-    cipher.process(data, &mut buf);
-    buf.into()
+// Pairs up the blocks of the left and the right media as they are
+// read, and makes a block of redundancy out of each pair, or a copy of
+// a block left without a partner.
+struct Pairs {
+    block_size: usize,
+    hash_algorithm: hash::Algorithm,
+    left: Receiver<Result<block::Block>>,
+    right: Receiver<Result<block::Block>>,
+}
+
+impl Pairs {
+    fn next_pair(&mut self) -> Result<Option<(Block, PartialIndex)>> {
+        let lblk = next_block(&self.left)?;
+        let rblk = next_block(&self.right)?;
+
+        if lblk.is_none() && rblk.is_none() {
+            Ok(None)
+        } else if lblk.is_none() || rblk.is_none() {
+            // replication
+            let block = lblk.unwrap_or_else(|| rblk.unwrap());
+            let hash = block.hash().to_vec();
+
+            assert!(block.data().len() <= u32::max_value() as usize);
+            let index = PartialIndex {
+                kind: PartialIndexKind::Replication {
+                    original: index::Block::new(
+                        block.file_id(),
+                        block.block_id(),
+                        block.data().len() as u32,
+                        block.hash(),
+                    ),
+                },
+                id: 0,
+                len: block.data().len() as u32,
+                hash,
+            };
+
+            let mut buf = vec![0u8; self.block_size];
+            buf[..block.data().len()].copy_from_slice(block.data());
+
+            Ok(Some((Block::new(&generate_nonce()?, buf.into()), index)))
+        } else {
+            // create redundancy
+            let lblk = lblk.unwrap();
+            let rblk = rblk.unwrap();
+
+            // The blocks read were hashed as they were read.
+            let mut buf = vec![0u8; cmp::max(lblk.data().len(), rblk.data().len())];
+            redundancy_copy(lblk.data(), rblk.data(), &mut buf);
+            let redun_hash = self.hash_algorithm.digest(&buf);
+
+            assert!(lblk.data().len() <= u32::max_value() as usize);
+            assert!(rblk.data().len() <= u32::max_value() as usize);
+            let index = PartialIndex {
+                kind: PartialIndexKind::Redundancy {
+                    left: index::Block::new(
+                        lblk.file_id(),
+                        lblk.block_id(),
+                        lblk.data().len() as u32,
+                        lblk.hash(),
+                    ),
+                    right: index::Block::new(
+                        rblk.file_id(),
+                        rblk.block_id(),
+                        rblk.data().len() as u32,
+                        rblk.hash(),
+                    ),
+                },
+                id: 0,
+                len: buf.len() as u32,
+                hash: redun_hash,
+            };
+
+            buf.resize(self.block_size, 0);
+
+            Ok(Some((Block::new(&generate_nonce()?, buf.into()), index)))
+        }
+    }
+}
+
+impl Iterator for Pairs {
+    type Item = Result<(Block, PartialIndex)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next_pair() {
+            Ok(Some(pair)) => Some(Ok(pair)),
+            Ok(None) => None,
+            Err(err) => Some(Err(err)),
+        }
+    }
+}
+
+// Returns how many blocks of redundancy go in one redundancy file, so
+// that each file stays within the given size unless a single block is
+// larger.
+pub fn blocks_per_file(block_size: u64, file_size: u64) -> u64 {
+    let record = block_size + mem::size_of::<Nonce>() as u64;
+    cmp::max(file_size / record, 1)
+}
+
+// Returns the least memory the redundancy of a group takes to build:
+// a block being read for each side, the pair of blocks read and the
+// block of redundancy made out of them.
+pub fn min_memory(block_size: u64) -> u64 {
+    IN_FLIGHT_BLOCKS * block_size
+}
+
+type Reader = JoinHandle<(Vec<usize>, Vec<(usize, Hash)>)>;
+
+// Reads the blocks of the files on a thread of its own, the given
+// number of blocks ahead of the hashing and the encryption.  The thread
+// returns the ids of the files that changed and the hashes of the files
+// it read.  Files count as processed once they are read in full.
+fn read_ahead(
+    block_size: usize,
+    files: Vec<block::File>,
+    blocks: usize,
+    hash_algorithm: hash::Algorithm,
+    tolerate_changes: bool,
+    progress: Option<Progress>,
+) -> (Receiver<Result<block::Block>>, Reader) {
+    let (sender, receiver) = mpsc::sync_channel(blocks);
+    let reader = thread::spawn(move || {
+        let mut iter = match BlockIter::new(block_size, files.into_iter()) {
+            Ok(iter) => iter.hash_algorithm(hash_algorithm)
+                .tolerate_changes(tolerate_changes),
+            Err(err) => {
+                let _ = sender.send(Err(err));
+                return Default::default();
+            }
+        };
+        let mut files_read = 0;
+        let mut advance = |bytes: usize, files: usize| {
+            if let Some(ref progress) = progress {
+                progress.advance(bytes as u64, (files - files_read) as u64);
+            }
+            files_read = files;
+        };
+        loop {
+            match iter.next_block() {
+                Ok(Some(block)) => {
+                    advance(block.data().len(), iter.hashes().len());
+                    if sender.send(Ok(block)).is_err() {
+                        // the blocks are no longer wanted
+                        break;
+                    }
+                }
+                Ok(None) => {
+                    advance(0, iter.hashes().len());
+                    break;
+                }
+                Err(err) => {
+                    let _ = sender.send(Err(err));
+                    break;
+                }
+            }
+        }
+        (iter.changed().to_vec(), iter.hashes().to_vec())
+    });
+    (receiver, reader)
+}
+
+// Returns the next block read ahead, or None once all are read.
+fn next_block(blocks: &Receiver<Result<block::Block>>) -> Result<Option<block::Block>> {
+    match blocks.recv() {
+        Ok(block) => block.map(Some),
+        Err(_) => Ok(None),
+    }
 }
 
 // Generates random sequence of bytes.
@@ -335,15 +554,21 @@ pub fn generate_nonce() -> Result<Box<[u8]>> {
 
 #[cfg(test)]
 mod test {
-    use redundancy::redun::{generate_key, generate_nonce, Block, RedunFile};
+    use crypto::aes;
+    use crypto::symmetriccipher::SynchronousStreamCipher;
+    use redundancy::redun::{generate_key, generate_nonce, read_block, Block, RedunFile,
+                            ENCRYPTION_CHUNK};
+    use std::fs;
+    use std::io::Read;
 
     const BLOCK_SIZE: usize = 4096;
 
     #[test]
     fn test_redun_file() {
+        let key = generate_key().expect("generate_key");
         let mut rfile = RedunFile::new("test_redun_file")
             .expect("RedunFile::new")
-            .with_enc_key(&generate_key().expect("generate_key"));
+            .with_enc_key(&key);
 
         let mut blocks = vec![];
 
@@ -359,13 +584,43 @@ mod test {
         let block = Block::new(&generate_nonce().expect("generate_nonce"), buf.into());
         blocks.push(block);
 
-        rfile
-            .write_blocks(&blocks, BLOCK_SIZE)
+        let count = rfile
+            .write_blocks(blocks.into_iter().map(Ok), BLOCK_SIZE)
             .expect("write_blocks");
+        assert_eq!(count, 2);
         assert_eq!(
             rfile.file.path().metadata().expect("metadata").len(),
             2 * (BLOCK_SIZE + 16) as u64
         );
+
+        let block = read_block(rfile.path(), &key, 1, BLOCK_SIZE).expect("read_block");
+        assert_eq!(block.len(), BLOCK_SIZE);
+        assert!(block.starts_with(b"What it will be like\0"));
+        rfile.remove().expect("rfile.remove()");
+    }
+
+    #[test]
+    fn test_encrypt_in_chunks() {
+        let block_size = ENCRYPTION_CHUNK * 5 / 2;
+        let key = generate_key().expect("generate_key");
+        let nonce = generate_nonce().expect("generate_nonce");
+        let data: Vec<u8> = (0..block_size).map(|i| (i % 251) as u8).collect();
+        let mut rfile = RedunFile::new("test_encrypt_in_chunks")
+            .expect("RedunFile::new")
+            .with_enc_key(&key);
+        let block = Block::new(&nonce, data.clone().into());
+        let _ = rfile
+            .write_blocks(vec![Ok(block)], block_size)
+            .expect("write_blocks");
+
+        let mut written = vec![];
+        let _ = fs::File::open(rfile.path())
+            .and_then(|mut file| file.read_to_end(&mut written))
+            .expect("read");
+        assert_eq!(&written[..16], &nonce[..]);
+        let mut decrypted = vec![0u8; block_size];
+        aes::ctr(aes::KeySize::KeySize128, &key, &nonce).process(&written[16..], &mut decrypted);
+        assert!(decrypted == data);
         rfile.remove().expect("rfile.remove()");
     }
 }
@@ -0,0 +1,106 @@
+#[cfg(target_arch = "x86")]
+use std::arch::x86::*;
+#[cfg(target_arch = "x86_64")]
+use std::arch::x86_64::*;
+use std::mem;
+
+const WORD: usize = mem::size_of::<u64>();
+
+#[cfg(test)]
+pub type Xor = fn(&[u8], &[u8], &mut [u8]);
+
+// XORs two slices of the same length into a third, as many bytes at a
+// time as the CPU allows.  The CPU is asked which vector instructions
+// it has on every call; the standard library caches the answer.
+pub fn xor(left: &[u8], right: &[u8], out: &mut [u8]) {
+    assert_eq!(left.len(), right.len());
+    assert_eq!(left.len(), out.len());
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        if is_x86_feature_detected!("avx2") {
+            return unsafe { xor_avx2(left, right, out) };
+        }
+        if is_x86_feature_detected!("sse2") {
+            return unsafe { xor_sse2(left, right, out) };
+        }
+    }
+    xor_words(left, right, out)
+}
+
+// The ways to XOR that this CPU supports, by name, from the plainest.
+#[cfg(test)]
+pub fn implementations() -> Vec<(&'static str, Xor)> {
+    let mut implementations: Vec<(&'static str, Xor)> =
+        vec![("bytes", xor_bytes), ("words", xor_words)];
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        if is_x86_feature_detected!("sse2") {
+            implementations.push(("sse2", |left, right, out| unsafe {
+                xor_sse2(left, right, out)
+            }));
+        }
+        if is_x86_feature_detected!("avx2") {
+            implementations.push(("avx2", |left, right, out| unsafe {
+                xor_avx2(left, right, out)
+            }));
+        }
+    }
+    implementations
+}
+
+// XORs a byte at a time, checking the bounds of every byte.
+#[allow(clippy::needless_range_loop)]
+pub fn xor_bytes(left: &[u8], right: &[u8], out: &mut [u8]) {
+    for i in 0..out.len() {
+        out[i] = left[i] ^ right[i];
+    }
+}
+
+// XORs a machine word at a time, on any CPU.
+pub fn xor_words(left: &[u8], right: &[u8], out: &mut [u8]) {
+    let len = out.len() / WORD * WORD;
+    for ((out, left), right) in out[..len]
+        .chunks_exact_mut(WORD)
+        .zip(left[..len].chunks_exact(WORD))
+        .zip(right[..len].chunks_exact(WORD))
+    {
+        out.copy_from_slice(&(word(left) ^ word(right)).to_ne_bytes());
+    }
+    xor_bytes(&left[len..], &right[len..], &mut out[len..]);
+}
+
+fn word(bytes: &[u8]) -> u64 {
+    let mut word = [0u8; WORD];
+    word.copy_from_slice(bytes);
+    u64::from_ne_bytes(word)
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[target_feature(enable = "sse2")]
+unsafe fn xor_sse2(left: &[u8], right: &[u8], out: &mut [u8]) {
+    const LANES: usize = mem::size_of::<__m128i>();
+    let len = out.len() / LANES * LANES;
+    for offset in (0..len).step_by(LANES) {
+        let l = _mm_loadu_si128(left.as_ptr().add(offset) as *const __m128i);
+        let r = _mm_loadu_si128(right.as_ptr().add(offset) as *const __m128i);
+        _mm_storeu_si128(out.as_mut_ptr().add(offset) as *mut __m128i, _mm_xor_si128(l, r));
+    }
+    xor_words(&left[len..], &right[len..], &mut out[len..]);
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[target_feature(enable = "avx2")]
+unsafe fn xor_avx2(left: &[u8], right: &[u8], out: &mut [u8]) {
+    const LANES: usize = mem::size_of::<__m256i>();
+    let len = out.len() / LANES * LANES;
+    for offset in (0..len).step_by(LANES) {
+        let l = _mm256_loadu_si256(left.as_ptr().add(offset) as *const __m256i);
+        let r = _mm256_loadu_si256(right.as_ptr().add(offset) as *const __m256i);
+        _mm256_storeu_si256(
+            out.as_mut_ptr().add(offset) as *mut __m256i,
+            _mm256_xor_si256(l, r),
+        );
+    }
+    xor_words(&left[len..], &right[len..], &mut out[len..]);
+}
@@ -0,0 +1,114 @@
+use error_chain::ChainedError;
+use errors::*;
+use serde_json;
+use std::cell::RefCell;
+use std::fs;
+use std::path::Path as StdPath;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+const REPORT_IDENTIFIER: &str = "Skipped Files";
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SkippedFile {
+    pub path: PathBuf,
+    pub error: String,
+}
+
+#[derive(Debug, Serialize)]
+struct ReportFile<'a> {
+    identifier: String,
+    skipped: &'a [SkippedFile],
+}
+
+// Files and directories left out of the backup because they could not
+// be read.  Clones share the same list, so it can be handed to the
+// scan and to the build alike.
+#[derive(Clone, Debug, Default)]
+pub struct Skipped(Rc<RefCell<Vec<SkippedFile>>>);
+
+impl Skipped {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&self, path: &StdPath, err: &Error) {
+        warn!("skip {:?}: {}", path, err.display_chain().to_string().trim());
+        self.0.borrow_mut().push(SkippedFile {
+            path: path.into(),
+            error: err.iter()
+                .map(|err| err.to_string())
+                .collect::<Vec<_>>()
+                .join(": "),
+        });
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.borrow().is_empty()
+    }
+
+    // Writes the list as JSON, for whoever has to deal with the files.
+    pub fn save<P: AsRef<StdPath>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let skipped = self.0.borrow();
+        let report = ReportFile {
+            identifier: REPORT_IDENTIFIER.into(),
+            skipped: &skipped,
+        };
+        let file = fs::File::create(path).chain_err(|| format!("error creating {:?}", path))?;
+        serde_json::to_writer_pretty(file, &report)
+            .chain_err(|| format!("error writing report to {:?}", path))
+    }
+
+    pub fn summary(&self) -> String {
+        let skipped = self.0.borrow();
+        let mut summary = format!("{} files or directories were skipped:\n", skipped.len());
+        for file in skipped.iter() {
+            summary.push_str(&format!("  {:?}: {}\n", file.path, file.error));
+        }
+        summary
+    }
+}
+
+// Passes on a result, except that an error is recorded and turned into
+// None when errors are tolerated.
+pub fn tolerate<T>(
+    skipped: Option<&Skipped>,
+    path: &StdPath,
+    result: Result<T>,
+) -> Result<Option<T>> {
+    match (result, skipped) {
+        (Ok(value), _) => Ok(Some(value)),
+        (Err(err), Some(skipped)) => {
+            skipped.record(path, &err);
+            Ok(None)
+        }
+        (Err(err), None) => Err(err),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use errors::*;
+    use report::{tolerate, Skipped};
+    use std::path::Path;
+
+    #[test]
+    fn test_tolerate() {
+        let path = Path::new("/unreadable");
+        let failure = || -> Result<u32> { Err("permission denied".into()) };
+
+        assert!(tolerate(None, path, failure()).is_err());
+        assert_eq!(tolerate(None, path, Ok(1)).expect("tolerate"), Some(1));
+
+        let skipped = Skipped::new();
+        let shared = skipped.clone();
+        assert_eq!(tolerate(Some(&shared), path, failure()).expect("tolerate"), None);
+        assert_eq!(skipped.len(), 1);
+        assert!(skipped.summary().contains("permission denied"));
+    }
+}
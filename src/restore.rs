@@ -0,0 +1,400 @@
+use catalog::Catalog;
+use compress;
+use errors::*;
+use filter;
+use hash::{self, Hash};
+use index::{FileEntry, RedundancyIndex};
+use mounted::Mounted;
+use path;
+use itertools::Itertools;
+use redundancy::{self, read_block};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fs;
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::Path as StdPath;
+use std::path::PathBuf;
+use std::time::{Duration, UNIX_EPOCH};
+
+// What checking, restoring or recovering files came to.
+#[derive(Debug, Default)]
+pub struct Outcome {
+    pub files: u64,
+    pub bytes: u64,
+    // The paths of the files that do not match their tables, with why.
+    pub damaged: Vec<(String, String)>,
+}
+
+impl Outcome {
+    fn check(&mut self, entry: &FileEntry, result: Result<()>) {
+        match result {
+            Ok(()) => {
+                self.files += 1;
+                self.bytes += entry.size();
+            }
+            Err(err) => {
+                warn!("{}: {}", entry.path(), err);
+                self.damaged.push((entry.path().into(), err.to_string()));
+            }
+        }
+    }
+}
+
+// Checks the files stored on a medium against the file table: their
+// sizes, and the hashes of their original contents where the table has
+// them.
+pub fn verify(medium: &Mounted) -> Result<Outcome> {
+    let hash_algorithm = medium.file_table().hash_algorithm();
+    let mut outcome = Outcome::default();
+    for entry in medium.stored_entries() {
+        let path = medium.stored_path(entry);
+        let result = stored_len(&path).and_then(|len| {
+            let expected = entry.stored_size().unwrap_or_else(|| entry.size());
+            if len != expected {
+                bail!("{} bytes long instead of {}", len, expected);
+            }
+            if entry.is_inconsistent() {
+                warn!("{} changed while it was backed up", entry.path());
+                return Ok(());
+            }
+            match entry.hash() {
+                Some(hash) => check_hash(hash, &original_hash(entry, &path, hash_algorithm)?),
+                None => Ok(()),
+            }
+        });
+        outcome.check(entry, result);
+    }
+    Ok(outcome)
+}
+
+// Restores the files on a medium into target, each under its path in
+// the backup, or only those matching one of the gitignore-style
+// patterns if there are any.  Files are decompressed, and checked
+// against the file table as they are written.
+pub fn restore(medium: &Mounted, target: &StdPath, patterns: &[&str]) -> Result<Outcome> {
+    if medium.is_redundancy() {
+        bail!("{:?} holds redundancy rather than files", medium.root());
+    }
+    let mut outcome = Outcome::default();
+    let entries = medium
+        .file_table()
+        .entries()
+        .iter()
+        .filter(|entry| entry.medium_id() == medium.id())
+        .filter(|entry| is_selected(entry.path(), patterns));
+    for entry in entries {
+        outcome.check(entry, restore_entry(medium, entry, target));
+    }
+    Ok(outcome)
+}
+
+// Restores the tree as it was in a generation of a backup into target,
+// or only the files matching one of the patterns if there are any.  The
+// catalog of the generation tells which medium of that or an earlier
+// generation holds each file; files deleted by then are left out.  The
+// media holding the files have to be among those given.
+pub fn restore_generation(
+    media: &[Mounted],
+    generation: u64,
+    target: &StdPath,
+    patterns: &[&str],
+) -> Result<Outcome> {
+    let mut mounted = BTreeMap::new();
+    let mut catalogs = vec![];
+    for medium in media.iter().filter(|medium| !medium.is_redundancy()) {
+        let catalog = Catalog::load(medium.root())?;
+        let _ = mounted.insert((catalog.generation(), medium.name().to_string()), medium);
+        if catalog.generation() == generation {
+            catalogs.push(catalog);
+        }
+    }
+    let deleted: HashSet<_> = catalogs
+        .iter()
+        .flat_map(|catalog| catalog.deleted().iter().cloned())
+        .collect();
+    let catalog = match Catalog::latest(catalogs) {
+        Some(catalog) => catalog,
+        None => bail!("none of the data media is of generation {}", generation),
+    };
+
+    let entries: Vec<_> = catalog
+        .entries()
+        .iter()
+        .filter(|entry| !deleted.contains(&entry.path) && is_selected(&entry.path, patterns))
+        .collect();
+    let missing: BTreeSet<_> = entries
+        .iter()
+        .map(|entry| (entry.generation, entry.medium.clone()))
+        .filter(|medium| !mounted.contains_key(medium))
+        .collect();
+    if !missing.is_empty() {
+        bail!(
+            "the files of generation {} are also on {}; mount those media as well",
+            generation,
+            missing
+                .iter()
+                .map(|&(generation, ref name)| format!("{} of generation {}", name, generation))
+                .join(", ")
+        );
+    }
+
+    let mut outcome = Outcome::default();
+    for entry in entries {
+        let medium = mounted[&(entry.generation, entry.medium.clone())];
+        let stored = medium
+            .file_table()
+            .entries()
+            .iter()
+            .find(|stored| stored.medium_id() == medium.id() && stored.path() == entry.path);
+        match stored {
+            Some(stored) => outcome.check(stored, restore_entry(medium, stored, target)),
+            None => {
+                let err = format!("not in the file table of {}", entry.medium);
+                warn!("{}: {}", entry.path, err);
+                outcome.damaged.push((entry.path.clone(), err));
+            }
+        }
+    }
+    Ok(outcome)
+}
+
+// Tells whether a file is among those matching the patterns, if there
+// are any.
+fn is_selected(path: &str, patterns: &[&str]) -> bool {
+    patterns.is_empty()
+        || patterns
+            .iter()
+            .any(|pattern| filter::matches_file(pattern, StdPath::new(path)))
+}
+
+// Restores a file on a medium into target, under its path in the
+// backup, and checks it against the file table.
+fn restore_entry(medium: &Mounted, entry: &FileEntry, target: &StdPath) -> Result<()> {
+    let dest = target.join(relative_path(entry.path())?);
+    restore_file(medium, entry, &dest)?;
+    match entry.hash() {
+        Some(hash) if !entry.is_inconsistent() => check_hash(
+            hash,
+            &medium.file_table().hash_algorithm().file(&dest)?,
+        ),
+        _ => Ok(()),
+    }
+}
+
+fn restore_file(medium: &Mounted, entry: &FileEntry, dest: &StdPath) -> Result<()> {
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent).chain_err(|| format!("error making directory {:?}", parent))?;
+    }
+    let source = medium.stored_path(entry);
+    let mut read =
+        fs::File::open(&source).chain_err(|| ErrorKind::UnreadableFile(source.clone()))?;
+    let mut write = fs::File::create(dest).chain_err(|| format!("error creating {:?}", dest))?;
+    match entry.compression() {
+        Some(algorithm) => compress::decompress(algorithm, read, &mut write)?,
+        None => {
+            let _ = io::copy(&mut read, &mut write)
+                .chain_err(|| format!("error copying {:?} to {:?}", source, dest))?;
+        }
+    }
+    if let Some((secs, nanos)) = entry.modified() {
+        write
+            .set_modified(UNIX_EPOCH + Duration::new(secs, nanos))
+            .chain_err(|| format!("error setting the modification time of {:?}", dest))?;
+    }
+    Ok(())
+}
+
+// Rebuilds the files of the lost data medium of a group into target,
+// as they were stored on it, from the other data medium and the
+// redundancy medium.  The tables and the key are copied along, so
+// target ends up as a copy of the lost medium.
+pub fn recover(data: &Mounted, redun: &Mounted, target: &StdPath) -> Result<Outcome> {
+    if data.is_redundancy() || !redun.is_redundancy() {
+        bail!("recovering takes a data medium and the redundancy medium of its group");
+    }
+    if data.media_table().entries() != redun.media_table().entries() {
+        bail!("{:?} and {:?} are not of the same group", data.root(), redun.root());
+    }
+    let lost = 1 - data.id();
+    let key = data.enc_key()?;
+    let file_table = data.file_table();
+    let entries = file_table.entries();
+    let hash_algorithm = redun.redun_table().hash_algorithm();
+    let block_size = redun
+        .block_size()
+        .chain_err(|| format!("{:?} holds no redundancy", redun.root()))?;
+
+    for entry in entries {
+        let _ = relative_path(entry.path())?;
+    }
+    let lost_file = |id: usize| -> PathBuf {
+        let stored = data.stored_path(&entries[id]);
+        let relative = stored.strip_prefix(data.root()).expect("stored under the root");
+        target.join(relative)
+    };
+    for entry in entries
+        .iter()
+        .filter(|entry| entry.medium_id() == lost && entry.same_as().is_none())
+    {
+        let path = lost_file(entry.id());
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .chain_err(|| format!("error making directory {:?}", parent))?;
+        }
+        let _ = fs::File::create(&path).chain_err(|| format!("error creating {:?}", path))?;
+    }
+
+    for index in redun.redun_table().entries() {
+        let (lost_block, contents) = match *index {
+            RedundancyIndex::Redundancy {
+                ref left,
+                ref right,
+                ref redundancy,
+            } => {
+                let (lost_block, kept) = if entries[left.file()].medium_id() == lost {
+                    (left, right)
+                } else {
+                    (right, left)
+                };
+                let xored = read_block(
+                    &redun.stored_path(&entries[redundancy.file()]),
+                    &key,
+                    redundancy.block(),
+                    block_size,
+                )?;
+                let kept_contents = read_at(
+                    &data.stored_path(&entries[kept.file()]),
+                    kept.block() * block_size,
+                    kept.size() as usize,
+                )?;
+                let mut contents = vec![0u8; redundancy.size() as usize];
+                redundancy::redundancy_copy(
+                    &xored[..redundancy.size() as usize],
+                    &kept_contents,
+                    &mut contents,
+                );
+                (lost_block, contents)
+            }
+            RedundancyIndex::Replication {
+                ref original,
+                ref replication,
+            } => {
+                if entries[original.file()].medium_id() != lost {
+                    continue;
+                }
+                let copy = read_block(
+                    &redun.stored_path(&entries[replication.file()]),
+                    &key,
+                    replication.block(),
+                    block_size,
+                )?;
+                (original, copy)
+            }
+        };
+
+        let contents = &contents[..lost_block.size() as usize];
+        check_hash(lost_block.hash(), &hash_algorithm.digest(contents))
+            .chain_err(|| format!("error recovering block {}", lost_block.block()))?;
+        let path = lost_file(lost_block.file());
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .chain_err(|| format!("error opening {:?}", path))?;
+        file.seek(SeekFrom::Start((lost_block.block() * block_size) as u64))
+            .and_then(|_| file.write_all(contents))
+            .chain_err(|| format!("error writing to {:?}", path))?;
+    }
+
+    let mut outcome = Outcome::default();
+    for entry in entries
+        .iter()
+        .filter(|entry| entry.medium_id() == lost && entry.same_as().is_none())
+    {
+        let path = lost_file(entry.id());
+        let result = match entry.hash() {
+            Some(hash) if !entry.is_inconsistent() => {
+                original_hash(entry, &path, file_table.hash_algorithm())
+                    .and_then(|recovered| check_hash(hash, &recovered))
+            }
+            _ => Ok(()),
+        };
+        outcome.check(entry, result);
+    }
+
+    let dir = fs::read_dir(data.root()).chain_err(|| format!("error reading {:?}", data.root()))?;
+    for entry in dir {
+        let entry = entry.chain_err(|| format!("error reading {:?}", data.root()))?;
+        if entry.path().is_file() {
+            let _ = fs::copy(entry.path(), target.join(entry.file_name()))
+                .chain_err(|| format!("error copying {:?}", entry.path()))?;
+        }
+    }
+    Ok(outcome)
+}
+
+// Takes a path from the tables as one to join to a directory, which it
+// must not lead out of.
+fn relative_path(path: &str) -> Result<&StdPath> {
+    let path = StdPath::new(path);
+    if !path::stays_below(path) || path.as_os_str().is_empty() {
+        bail!("{:?} leads out of the directory it belongs in", path);
+    }
+    Ok(path)
+}
+
+// Returns the length of a file as it is stored.
+fn stored_len(path: &StdPath) -> Result<u64> {
+    Ok(path.metadata()
+        .chain_err(|| ErrorKind::UnreadableFile(path.into()))?
+        .len())
+}
+
+// Returns the hash of the original contents of a stored file,
+// decompressing it if it is stored compressed.
+fn original_hash(
+    entry: &FileEntry,
+    path: &StdPath,
+    hash_algorithm: hash::Algorithm,
+) -> Result<Hash> {
+    match entry.compression() {
+        Some(algorithm) => {
+            let read = fs::File::open(path).chain_err(|| ErrorKind::UnreadableFile(path.into()))?;
+            let mut hasher = hash_algorithm.hasher();
+            compress::decompress(algorithm, read, &mut hasher)?;
+            Ok(hasher.digest())
+        }
+        None => hash_algorithm.file(path),
+    }
+}
+
+fn check_hash(expected: &[u8], actual: &[u8]) -> Result<()> {
+    if expected != actual {
+        bail!("the contents do not match the hash in the table");
+    }
+    Ok(())
+}
+
+// Reads up to len bytes at offset in a file.
+fn read_at(path: &StdPath, offset: usize, len: usize) -> Result<Vec<u8>> {
+    use std::io::Read;
+
+    let mut file = fs::File::open(path).chain_err(|| ErrorKind::UnreadableFile(path.into()))?;
+    let mut contents = vec![];
+    let _ = file.seek(SeekFrom::Start(offset as u64))
+        .and_then(|_| file.take(len as u64).read_to_end(&mut contents))
+        .chain_err(|| ErrorKind::UnreadableFile(path.into()))?;
+    Ok(contents)
+}
+
+#[cfg(test)]
+mod test {
+    use restore::relative_path;
+
+    #[test]
+    fn test_relative_path() {
+        assert!(relative_path("d1/f").is_ok());
+        assert!(relative_path("/etc/passwd").is_err());
+        assert!(relative_path("d1/../../f").is_err());
+        assert!(relative_path("./f").is_err());
+        assert!(relative_path("").is_err());
+    }
+}
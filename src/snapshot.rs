@@ -0,0 +1,67 @@
+use errors::*;
+use std::fs::Metadata;
+#[cfg(target_family = "unix")]
+use std::os::unix::fs::MetadataExt;
+use std::path::Path as StdPath;
+use std::time::UNIX_EPOCH;
+
+// What a file looked like when it was scanned.  A file that no longer
+// matches its snapshot has changed since, and its contents may not be
+// what the index tables say.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Snapshot {
+    pub len: u64,
+    pub modified: (u64, u32),
+    pub inode: u64,
+}
+
+impl Snapshot {
+    pub fn of(metadata: &Metadata) -> Result<Self> {
+        let modified = metadata
+            .modified()
+            .chain_err(|| "error getting modification time")?
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let inode = if cfg!(target_family = "unix") {
+            metadata.ino()
+        } else {
+            // Not implemented on other platforms yet; the length and
+            // the modification time have to do.
+            0
+        };
+
+        Ok(Snapshot {
+            len: metadata.len(),
+            modified: (modified.as_secs(), modified.subsec_nanos()),
+            inode,
+        })
+    }
+
+    pub fn take(path: &StdPath) -> Result<Self> {
+        let metadata = path.metadata()
+            .chain_err(|| format!("error getting metadata of {:?}", path))?;
+        Self::of(&metadata).chain_err(|| format!("error taking a snapshot of {:?}", path))
+    }
+}
+
+// What to do about a file that changes while the backup is running.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChangePolicy {
+    // Read the file again and rebuild the redundancy of its group.
+    Retry,
+    // Give up on the backup.
+    Fail,
+    // Carry on, marking the file as inconsistent in the file table.
+    Mark,
+}
+
+impl ChangePolicy {
+    pub fn from_name(name: &str) -> Result<Self> {
+        match name {
+            "retry" => Ok(ChangePolicy::Retry),
+            "fail" => Ok(ChangePolicy::Fail),
+            "mark" => Ok(ChangePolicy::Mark),
+            _ => bail!("unknown policy for changed files: {}", name),
+        }
+    }
+}
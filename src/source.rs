@@ -0,0 +1,116 @@
+use errors::*;
+use path::{self, Path};
+use std::collections::HashSet;
+use std::path::Path as StdPath;
+use std::path::PathBuf;
+
+// A directory whose contents go into the backup.  When there is more
+// than one, each gets a namespace named after its directory, so their
+// logical paths stay apart in the index tables and restore into
+// separate trees.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Source {
+    pub path: PathBuf,
+    pub namespace: PathBuf,
+}
+
+impl Source {
+    pub fn from_roots<P: AsRef<StdPath>>(roots: &[P]) -> Result<Vec<Self>> {
+        let mut canonical_roots: Vec<PathBuf> = vec![];
+        for root in roots {
+            let root = root.as_ref();
+            let canonical = root.canonicalize()
+                .chain_err(|| format!("error finding source directory {:?}", root))?;
+            if let Some(other) = canonical_roots
+                .iter()
+                .find(|other| canonical.starts_with(other) || other.starts_with(&canonical))
+            {
+                bail!("source directories {:?} and {:?} overlap", other, canonical);
+            }
+            canonical_roots.push(canonical);
+        }
+
+        if roots.len() == 1 {
+            return Ok(vec![Source {
+                path: roots[0].as_ref().into(),
+                namespace: PathBuf::new(),
+            }]);
+        }
+
+        let mut used = HashSet::new();
+        Ok(roots
+            .iter()
+            .zip(canonical_roots)
+            .map(|(root, canonical)| {
+                let name = canonical
+                    .file_name()
+                    .map(|name| name.to_string_lossy().into_owned())
+                    .unwrap_or_else(|| "root".into());
+                let mut namespace = name.clone();
+                let mut count = 1;
+                while !used.insert(namespace.clone()) {
+                    count += 1;
+                    namespace = format!("{}-{}", name, count);
+                }
+                Source {
+                    path: root.as_ref().into(),
+                    namespace: namespace.into(),
+                }
+            })
+            .collect())
+    }
+
+    pub fn template(&self) -> Path {
+        Path::with_namespace(&self.path, &self.namespace)
+    }
+
+    pub fn root(&self) -> Path {
+        self.template().path(&self.path)
+    }
+
+    // Turns a logical path back into a path under the source it came
+    // from.
+    pub fn resolve<P: AsRef<StdPath>>(sources: &[Source], logical: P) -> Result<Path> {
+        let logical = logical.as_ref();
+        for source in sources {
+            if let Ok(relative) = logical.strip_prefix(&source.namespace) {
+                if !path::stays_below(relative) {
+                    bail!("{:?} leads out of the source directories", logical);
+                }
+                return Ok(source.template().path(source.path.join(relative)));
+            }
+        }
+        bail!("{:?} belongs to none of the source directories", logical)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use source::Source;
+    use std::fs;
+    use tempdir::TempDir;
+
+    #[test]
+    fn test_namespaces() {
+        let temp_dir = TempDir::new("test_source").expect("TempDir::new");
+        let photos = temp_dir.path().join("photos");
+        let other = temp_dir.path().join("other").join("photos");
+        fs::create_dir_all(&photos).expect("create_dir_all");
+        fs::create_dir_all(&other).expect("create_dir_all");
+
+        let single = Source::from_roots(&[&photos]).expect("from_roots");
+        assert_eq!(single[0].namespace.to_str(), Some(""));
+
+        let sources = Source::from_roots(&[&photos, &other]).expect("from_roots");
+        assert_eq!(sources[0].namespace.to_str(), Some("photos"));
+        assert_eq!(sources[1].namespace.to_str(), Some("photos-2"));
+
+        let file = sources[1].template().path(other.join("a.jpg"));
+        let logical = file.logical().expect("logical");
+        assert_eq!(logical.to_str(), Some("photos-2/a.jpg"));
+        let resolved = Source::resolve(&sources, &logical).expect("resolve");
+        assert_eq!(&*resolved, &*other.join("a.jpg"));
+
+        assert!(Source::from_roots(&[temp_dir.path(), &photos]).is_err());
+    }
+}
@@ -1,23 +1,76 @@
-use consts::*;
+use compress::Compression;
 use errors::*;
+use filter::Scope;
 use path::Path;
 use slog::Logger;
+use snapshot::Snapshot;
 use std::fmt::{self, Debug, Formatter};
 use std::result::Result as StdResult;
 
-#[derive(Debug, Default)]
+#[derive(Clone, Debug, Default)]
 pub struct File {
     pub path: Path,
     pub len: u64,
+    pub snapshot: Option<Snapshot>,
+    // other files with the same contents, stored only once
+    pub duplicates: Vec<Duplicate>,
+    pub compression: Option<Compression>,
 }
 
 impl File {
     pub fn new(path: Path, len: u64) -> Self {
-        File { path, len }
+        File {
+            path,
+            len,
+            snapshot: None,
+            duplicates: vec![],
+            compression: None,
+        }
+    }
+
+    pub fn with_snapshot(path: Path, snapshot: Snapshot) -> Self {
+        File {
+            path,
+            len: snapshot.len,
+            snapshot: Some(snapshot),
+            duplicates: vec![],
+            compression: None,
+        }
+    }
+
+    // Takes a new snapshot of a file that has changed.  A file stored
+    // compressed has to be compressed again.
+    pub fn refresh(&mut self) -> Result<()> {
+        let snapshot = Snapshot::take(&self.path)?;
+        self.len = snapshot.len;
+        self.snapshot = Some(snapshot);
+        if let Some(ref mut compression) = self.compression {
+            compression.original_len = snapshot.len;
+            compression.stored = None;
+        }
+        Ok(())
+    }
+}
+
+// A file with the same contents as another, which is stored in its
+// place.  Its contents are never read, so the snapshot is all there is
+// to tell whether it changed.
+#[derive(Clone, Debug)]
+pub struct Duplicate {
+    pub path: Path,
+    pub snapshot: Option<Snapshot>,
+}
+
+impl Duplicate {
+    pub fn is_unchanged(&self) -> Result<bool> {
+        match self.snapshot {
+            Some(snapshot) => Ok(Snapshot::take(&self.path)? == snapshot),
+            None => Ok(true),
+        }
     }
 }
 
-#[derive(Default)]
+#[derive(Clone, Default)]
 pub struct Files(pub Vec<File>);
 
 impl Debug for Files {
@@ -29,6 +82,7 @@ impl Debug for Files {
     }
 }
 
+#[derive(Clone)]
 pub struct Unit {
     pub parent: usize,
     pub len: u64,
@@ -37,20 +91,32 @@ pub struct Unit {
 }
 
 impl Unit {
-    pub fn root(root: Path, log: &Logger) -> Result<Self> {
-        Self::new(root, 0, log)
+    pub fn root(root: Path, scope: &Scope, log: &Logger) -> Result<Self> {
+        Self::new(root, 0, scope, log)
     }
 
-    pub fn new(path: Path, parent: usize, log: &Logger) -> Result<Self> {
+    pub fn new(path: Path, parent: usize, scope: &Scope, log: &Logger) -> Result<Self> {
         let mut files = vec![];
         let mut len = 0;
 
         for file in path.read_dir()
             .chain_err(|| format!("error reading directory {:?}", path))?
         {
-            let file = file.chain_err(|| format!("error reading directory {:?}", path))?;
-            let file_type = file.file_type()
-                .chain_err(|| format!("error getting file type of {:?}", file.path()))?;
+            let file = match scope.tolerate(
+                &path,
+                file.chain_err(|| format!("error reading directory {:?}", path)),
+            )? {
+                Some(file) => file,
+                None => continue,
+            };
+            let file_type = match scope.tolerate(
+                &file.path(),
+                file.file_type()
+                    .chain_err(|| format!("error getting file type of {:?}", file.path())),
+            )? {
+                Some(file_type) => file_type,
+                None => continue,
+            };
             let entry_path = Path::with_template(&path).path(file.path());
 
             if file_type.is_dir() {
@@ -58,11 +124,29 @@ impl Unit {
             } else if file_type.is_file()
                 || (file_type.is_symlink() && entry_path.exists() && entry_path.is_file())
             {
-                let file_len = file.metadata()
-                    .chain_err(|| format!("error getting metadata of {:?}", entry_path))?
-                    .len();
-                len += file_len;
-                files.push(File::new(entry_path, file_len));
+                let metadata = match scope.tolerate(
+                    &entry_path,
+                    entry_path
+                        .metadata()
+                        .chain_err(|| format!("error getting metadata of {:?}", entry_path)),
+                )? {
+                    Some(metadata) => metadata,
+                    None => continue,
+                };
+                if scope.excludes_file(&entry_path, &metadata)? {
+                    slog_debug!(log, "exclude"; "path" => format!("{:?}", entry_path));
+                    continue;
+                }
+                let snapshot = match scope.tolerate(
+                    &entry_path,
+                    Snapshot::of(&metadata)
+                        .chain_err(|| format!("error taking a snapshot of {:?}", entry_path)),
+                )? {
+                    Some(snapshot) => snapshot,
+                    None => continue,
+                };
+                len += snapshot.len;
+                files.push(File::with_snapshot(entry_path, snapshot));
             } else if file_type.is_symlink() && !entry_path.exists() {
                 // warn about broken symlinks that are skipped
                 slog_warn!(log, "skip"; "path" => format!("{:?}", entry_path));
@@ -77,7 +161,18 @@ impl Unit {
         })
     }
 
-    pub fn is_small(&self) -> bool {
+    pub fn with_files(path: Path, parent: usize, files: Vec<File>) -> Self {
+        Unit {
+            parent,
+            len: files.iter().map(|file| file.len).sum(),
+            path,
+            files: Files(files),
+        }
+    }
+
+    // Tells whether none of the files of the unit is larger than the
+    // threshold.
+    pub fn is_small(&self, threshold: u64) -> bool {
         for &File { len, .. } in self.files.0.iter().filter(|file| {
             // don't count the hidden files
             !file.path
@@ -86,7 +181,7 @@ impl Unit {
                 .to_string_lossy()
                 .starts_with('.')
         }) {
-            if len > SMALL_FILE_UPPER_BOUND {
+            if len > threshold {
                 return false;
             }
         }
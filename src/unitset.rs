@@ -1,29 +1,42 @@
 use errors::*;
+use filter::{Filter, Scope};
 use path::Path;
+use progress::Progress;
+use report::{self, Skipped};
 use slog::Logger;
+use snapshot::Snapshot;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt::{self, Debug, Display, Formatter};
 use std::fs::{DirEntry, ReadDir};
 use std::io;
 use std::iter::Enumerate;
+#[cfg(target_family = "unix")]
+use std::os::unix::fs::MetadataExt;
+use std::path::Path as StdPath;
 use std::path::PathBuf;
 use std::result::Result as StdResult;
 use std::slice::Iter;
 use unit::{File, Unit};
 
 #[derive(Debug)]
-struct StackUnitItem {
+struct StackUnitItem<'a> {
     index: usize,
     cursor: ReadDir,
     path: PathBuf,
+    scope: Scope<'a>,
+    device: u64,
 }
 
-impl StackUnitItem {
-    fn new(path: &Path, index: usize) -> Result<Self> {
+impl<'a> StackUnitItem<'a> {
+    fn new(path: &Path, index: usize, scope: Scope<'a>) -> Result<Self> {
+        let device = device(path)?;
         match path.read_dir() {
             Ok(cursor) => Ok(StackUnitItem {
                 index,
                 cursor,
                 path: path.as_ref().into(),
+                scope,
+                device,
             }),
             Err(err) => Err(Error::with_chain(
                 err,
@@ -33,7 +46,7 @@ impl StackUnitItem {
     }
 }
 
-impl Iterator for StackUnitItem {
+impl<'a> Iterator for StackUnitItem<'a> {
     type Item = StdResult<DirEntry, io::Error>;
 
     fn next(&mut self) -> Option<Self::Item> {
@@ -41,46 +54,86 @@ impl Iterator for StackUnitItem {
     }
 }
 
-#[derive(Default)]
-pub struct UnitSet(pub Vec<Unit>, u64);
+// Units with their total length, and the mount points left out of
+// the scan.
+#[derive(Clone, Default)]
+pub struct UnitSet(pub Vec<Unit>, u64, Vec<PathBuf>);
 
 impl UnitSet {
-    pub fn from_path(root: Path, log: &Logger) -> Result<Self> {
+    // Scans the directory tree under root, leaving out whatever the
+    // filter excludes.
+    pub fn from_path(root: Path, filter: &Filter, log: &Logger) -> Result<Self> {
         let mut set = vec![];
         let mut stack = vec![];
+        let mut mount_points = vec![];
         let mut len;
+        let progress = Progress::new("scan");
 
-        stack.push(StackUnitItem::new(&root, 0)?);
-        let root = Unit::root(root, log)?;
+        let scope = filter.scope().enter(&root)?;
+        let root = Unit::root(root, &scope, log)?;
+        stack.push(StackUnitItem::new(&root.path, 0, scope)?);
         len = root.len;
+        progress.advance(root.len, root.files.0.len() as u64);
         set.push(root);
 
         while !stack.is_empty() {
             if let Some(dir_entry) = stack.last_mut().unwrap().next() {
-                let dir_entry = dir_entry.chain_err(|| {
-                    format!("error reading directory {:?}", stack.last().unwrap().path)
-                })?;
+                let dir_entry = {
+                    let item = stack.last().unwrap();
+                    let dir_entry = dir_entry
+                        .chain_err(|| format!("error reading directory {:?}", item.path));
+                    match item.scope.tolerate(&item.path, dir_entry)? {
+                        Some(dir_entry) => dir_entry,
+                        None => continue,
+                    }
+                };
                 let path = Path::with_template(&set.first().unwrap().path).path(dir_entry.path());
                 let file_type = dir_entry.file_type().chain_err(|| {
                     format!("error getting file type of {:?}", dir_entry.path())
-                })?;
+                });
+                let file_type = match stack.last().unwrap().scope.tolerate(&path, file_type)? {
+                    Some(file_type) => file_type,
+                    None => continue,
+                };
                 let log = log.new(o!("path" => format!("{:?}", path)));
 
-                if file_type.is_dir()
-                    || (file_type.is_symlink() && path.exists()
-                        && path.read_link()
-                            .chain_err(|| format!("error reading symlink {:?}", path))?
-                            .is_dir())
-                {
+                let is_dir = file_type.is_dir() || (file_type.is_symlink() && path.exists() && {
+                    let target = path.read_link()
+                        .chain_err(|| format!("error reading symlink {:?}", path));
+                    match stack.last().unwrap().scope.tolerate(&path, target)? {
+                        Some(target) => target.is_dir(),
+                        None => continue,
+                    }
+                });
+                if is_dir {
                     let parent = stack.last().unwrap().index;
 
                     if file_type.is_symlink() && detect_cycle(&path, parent, &set)? {
                         slog_warn!(log, "detected a cycle");
+                    } else if stack.last().unwrap().scope.excludes_dir(&path)? {
+                        slog_debug!(log, "exclude directory");
+                    } else if filter.is_one_file_system()
+                        && device(&path)? != stack.last().unwrap().device
+                    {
+                        slog_warn!(log, "skip mount point");
+                        mount_points.push(path.to_path_buf());
                     } else {
-                        stack.push(StackUnitItem::new(&path, set.len())?);
-                        let unit = Unit::new(path, parent, &log)?;
-                        len += unit.len;
-                        set.push(unit);
+                        let entered = stack.last().unwrap().scope.enter(&path).and_then(|scope| {
+                            let unit = Unit::new(path.clone(), parent, &scope, &log)?;
+                            let item = StackUnitItem::new(&unit.path, set.len(), scope)?;
+                            Ok((unit, item))
+                        });
+                        if let Some((unit, item)) =
+                            stack.last().unwrap().scope.tolerate(&path, entered)?
+                        {
+                            if item.device != stack.last().unwrap().device {
+                                slog_info!(log, "cross mount point");
+                            }
+                            stack.push(item);
+                            len += unit.len;
+                            progress.advance(unit.len, unit.files.0.len() as u64);
+                            set.push(unit);
+                        }
                     }
                 } else {
                     // Pass because we are only interested in
@@ -91,20 +144,72 @@ impl UnitSet {
             }
         }
 
-        Ok(UnitSet(set, len))
+        progress.finish();
+        Ok(UnitSet(set, len, mount_points))
+    }
+
+    // Makes units out of a list of files under root instead of scanning
+    // it: one unit for each directory holding any of the files.
+    pub fn from_files(
+        root: Path,
+        files: &[PathBuf],
+        skipped: Option<&Skipped>,
+        log: &Logger,
+    ) -> Result<Self> {
+        let mut dirs = BTreeMap::new();
+        let _ = dirs.insert(root.to_path_buf(), vec![]);
+
+        for path in files {
+            let metadata = path.metadata()
+                .chain_err(|| format!("error getting metadata of {:?}", path));
+            let metadata = match report::tolerate(skipped, path, metadata)? {
+                Some(metadata) => metadata,
+                None => continue,
+            };
+            if !metadata.is_file() {
+                slog_warn!(log, "skip"; "path" => format!("{:?}", path));
+                continue;
+            }
+            let snapshot = Snapshot::of(&metadata)
+                .chain_err(|| format!("error taking a snapshot of {:?}", path));
+            let snapshot = match report::tolerate(skipped, path, snapshot)? {
+                Some(snapshot) => snapshot,
+                None => continue,
+            };
+            let dir = path.parent().expect("listed file has no parent");
+            dirs.entry(dir.to_path_buf())
+                .or_insert_with(Vec::new)
+                .push(File::with_snapshot(Path::with_template(&root).path(path), snapshot));
+        }
+
+        // Directories come in order, each after its ancestors.
+        let mut set: Vec<Unit> = vec![];
+        for (dir, files) in dirs {
+            let parent = set.iter()
+                .rposition(|unit| dir.starts_with(&unit.path))
+                .unwrap_or(0);
+            set.push(Unit::with_files(
+                Path::with_template(&root).path(dir),
+                parent,
+                files,
+            ));
+        }
+
+        let len = set.iter().map(|unit| unit.len).sum();
+        Ok(UnitSet(set, len, vec![]))
     }
 
     pub fn len(&self) -> u64 {
         self.1
     }
 
-    pub fn plan_merges(&self) -> Vec<(usize, usize)> {
+    pub fn plan_merges(&self, threshold: u64) -> Vec<(usize, usize)> {
         let mut plan = vec![];
 
-        for (index, small) in self.small_units() {
+        for (index, small) in self.small_units(threshold) {
             let mut ancestor = small.parent;
             while ancestor != 0 {
-                if !self.0[ancestor].is_small() {
+                if !self.0[ancestor].is_small(threshold) {
                     break;
                 } else {
                     ancestor = self.0[ancestor].parent;
@@ -134,12 +239,67 @@ impl UnitSet {
             }
         }
 
+        // Units keep the index of their parent, which shifts as merged
+        // units are removed.  Children of a merged unit are reparented
+        // to the unit it was merged into.
+        let merged: HashMap<usize, usize> = plan.iter().cloned().collect();
+        let mut new_indices = Vec::with_capacity(self.0.len());
+        let mut next = 0;
+        for index in 0..self.0.len() {
+            new_indices.push(next);
+            if !merged.contains_key(&index) {
+                next += 1;
+            }
+        }
+        for unit in &mut self.0 {
+            let mut parent = unit.parent;
+            while let Some(&into) = merged.get(&parent) {
+                parent = into;
+            }
+            unit.parent = new_indices[parent];
+        }
+
         for &(merge, _) in plan.iter().rev() {
             assert_eq!(self.0[merge].files.0.len(), 0);
             let _ = self.0.remove(merge);
         }
     }
 
+    // Adds the units of another scan, such as that of another source
+    // directory, keeping their parents.  The root of the other scan,
+    // its own parent, becomes a child of the first root, so that every
+    // chain of parents still ends at 0.
+    pub fn append(&mut self, other: UnitSet) {
+        let offset = self.0.len();
+        self.1 += other.1;
+        self.2.extend(other.2);
+        self.0
+            .extend(other.0.into_iter().enumerate().map(|(index, mut unit)| {
+                if index != 0 {
+                    unit.parent += offset;
+                }
+                unit
+            }));
+    }
+
+    // Brings the lengths up to date after files have been taken out of
+    // the units.
+    pub fn recount(&mut self) {
+        for unit in &mut self.0 {
+            unit.len = unit.files.0.iter().map(|file| file.len).sum();
+        }
+        self.1 = self.0.iter().map(|unit| unit.len).sum();
+    }
+
+    pub fn mount_points(&self) -> &[PathBuf] {
+        &self.2
+    }
+
+    pub fn push(&mut self, unit: Unit) {
+        self.1 += unit.len;
+        self.0.push(unit);
+    }
+
     pub fn shift_from(&mut self, unit_set: &mut UnitSet) {
         assert!(!unit_set.0.is_empty());
         let first = unit_set.0.remove(0);
@@ -163,19 +323,19 @@ impl UnitSet {
         Ok(())
     }
 
-    pub fn small_units(&self) -> SmallUnits {
-        SmallUnits(self.0.iter().enumerate())
+    pub fn small_units(&self, threshold: u64) -> SmallUnits {
+        SmallUnits(self.0.iter().enumerate(), threshold)
     }
 }
 
-pub struct SmallUnits<'a>(Enumerate<Iter<'a, Unit>>);
+pub struct SmallUnits<'a>(Enumerate<Iter<'a, Unit>>, u64);
 
 impl<'a> Iterator for SmallUnits<'a> {
     type Item = (usize, &'a Unit);
 
     fn next(&mut self) -> Option<Self::Item> {
         if let Some((i, unit)) = self.0.next() {
-            if unit.is_small() {
+            if unit.is_small(self.1) {
                 Some((i, unit))
             } else {
                 self.next()
@@ -213,6 +373,19 @@ impl Display for UnitSet {
     }
 }
 
+// Returns the device of the file system a path is on.
+fn device(path: &StdPath) -> Result<u64> {
+    let metadata = path.metadata()
+        .chain_err(|| format!("error getting metadata of {:?}", path))?;
+    if cfg!(target_family = "unix") {
+        Ok(metadata.dev())
+    } else {
+        // Not implemented on other platforms yet, just assume
+        // everything is on the same file system.
+        Ok(0)
+    }
+}
+
 fn detect_cycle(path: &Path, parent: usize, set: &[Unit]) -> Result<bool> {
     let mut finger = &set[parent];
 